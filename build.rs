@@ -0,0 +1,32 @@
+//! Build metadata for `cfs version`: the git commit, when building from a
+//! checkout, and the target triple.
+
+use std::env;
+use std::fs;
+use std::process::Command;
+
+fn main() {
+    println!(
+        "cargo:rustc-env=CFS_TARGET={}",
+        env::var("TARGET").unwrap_or_default()
+    );
+
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=CFS_GIT_COMMIT={}", commit.trim());
+    }
+
+    // a new commit moves the branch HEAD points at, not HEAD itself
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    if let Some(branch) = fs::read_to_string(".git/HEAD")
+        .ok()
+        .and_then(|head| head.strip_prefix("ref: ").map(|r| r.trim().to_string()))
+    {
+        println!("cargo:rerun-if-changed=.git/{}", branch);
+    }
+}
@@ -0,0 +1,542 @@
+//! The subcommands, and `main` picking one.
+
+use std::io::{BufWriter, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{env, fs};
+
+use log::debug;
+
+use cfs::error::{CfsError, Context, Result};
+use cfs::format::{self, Format, Summary};
+use cfs::rootfs::{self, Distro};
+use cfs::state::{self, ListFilter, State};
+use cfs::{Container, units};
+
+use crate::cli::{self, Parser};
+use crate::completions;
+use crate::config::{self, Config};
+use crate::logger;
+
+/// How far apart `stats` takes its readings.
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Seconds `stop` gives a container between SIGTERM and SIGKILL.
+const DEFAULT_STOP_TIMEOUT: u64 = 10;
+
+pub fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args = global_options(&args);
+    let Some(name) = args.first() else {
+        print!("{}", cli::help());
+        return;
+    };
+    let result = match name.as_str() {
+        "-h" | "--help" | "help" => help(&args[1..]),
+        "--version" => {
+            println!("{}", version_line());
+            Ok(())
+        }
+        name => match cli::find(name) {
+            Some(command) => {
+                let mut parser = Parser::new(command, &args[1..]);
+                match command.name {
+                    "run" => run(&mut parser),
+                    "list" => list(&mut parser),
+                    "inspect" => inspect(&mut parser),
+                    "stats" => stats(&mut parser),
+                    "stop" => stop(&mut parser),
+                    "logs" => logs(&mut parser),
+                    "cp" => cp(&mut parser),
+                    "export" => export(&mut parser),
+                    "import" => import(&mut parser),
+                    "commit" => commit(&mut parser),
+                    "build" => build(&mut parser),
+                    "pause" => freeze(&mut parser, true),
+                    "resume" => freeze(&mut parser, false),
+                    "version" => version(&mut parser),
+                    "completions" => completions(&mut parser),
+                    _ => exec(&mut parser),
+                }
+            }
+            None => Err(CfsError::Usage(format!(
+                "Unknown command {}, see cfs --help",
+                name
+            ))),
+        },
+    };
+    if let Err(e) = result {
+        eprintln!("cfs: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Sets up logging from the options before the subcommand, `-v`, `-vv`,
+/// `--verbose` and `-q`/`--quiet`, and returns what follows them.
+fn global_options(args: &[String]) -> &[String] {
+    let mut verbosity = 0;
+    let mut quiet = false;
+    let mut rest = args;
+    while let [arg, tail @ ..] = rest {
+        match arg.strip_prefix('-') {
+            Some("q" | "-quiet") => quiet = true,
+            Some("-verbose") => verbosity += 1,
+            Some(vs) if !vs.is_empty() && vs.bytes().all(|b| b == b'v') => verbosity += vs.len(),
+            _ => break,
+        }
+        rest = tail;
+    }
+    logger::init(verbosity, quiet);
+    rest
+}
+
+/// `cfs help [<command>]`
+fn help(args: &[String]) -> Result<()> {
+    match args.first() {
+        None => print!("{}", cli::help()),
+        Some(name) => match cli::find(name) {
+            Some(command) => print!("{}", command.help()),
+            None => return Err(CfsError::Usage(format!("Unknown command {}", name))),
+        },
+    }
+    Ok(())
+}
+
+/// The file's settings, then the flags over them. `--config` may come
+/// anywhere among the flags and still loses to all of them.
+fn parse_run_args(parser: &mut Parser) -> Result<Container> {
+    let mut path = None;
+    let mut given = Vec::new();
+    while let Some(flag) = parser.next()? {
+        match flag.name() {
+            "config" => path = Some(expand_home(flag.value())?),
+            _ => given.push(flag),
+        }
+    }
+
+    let mut config = match path {
+        Some(path) => Config::load(&path)?,
+        None if Path::new(config::DEFAULT_FILE).exists() => {
+            debug!("using ./{}", config::DEFAULT_FILE);
+            Config::load(Path::new(config::DEFAULT_FILE))?
+        }
+        None => Config::default(),
+    };
+    for flag in &given {
+        config.set(flag.name(), flag.value());
+    }
+    config.command(parser.rest());
+    config.container()
+}
+
+// relative paths are resolved by the library, `~` is for us to expand
+pub fn expand_home(path: &str) -> Result<PathBuf> {
+    match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            let home = env::var("HOME")
+                .map_err(|_| CfsError::Usage(format!("Cannot expand {}: HOME is not set", path)))?;
+            Ok(PathBuf::from(home).join(rest.trim_start_matches('/')))
+        }
+        _ => Ok(PathBuf::from(path)),
+    }
+}
+
+/// Exits with the container's code, so scripts can tell how it went.
+fn run(parser: &mut Parser) -> Result<()> {
+    let status = parse_run_args(parser)?.run()?;
+    std::process::exit(cfs::exit_code(status));
+}
+
+/// Prints the running containers. Entries left behind by a cfs that died
+/// without cleaning up are pruned on the way.
+fn list(parser: &mut Parser) -> Result<()> {
+    let mut output = Format::Text;
+    let mut filters = Vec::new();
+    while let Some(flag) = parser.next()? {
+        match flag.name() {
+            "format" => output = Format::parse(flag.value())?,
+            _ => filters.push(ListFilter::parse(flag.value())?),
+        }
+    }
+    if !parser.rest().is_empty() {
+        return Err(CfsError::Usage("list takes no arguments".into()));
+    }
+    let mut running = Vec::new();
+    for state in State::all()? {
+        if !state.is_running() {
+            state.remove()?;
+        } else if filters.iter().all(|filter| filter.matches(&state)) {
+            running.push(state);
+        }
+    }
+    if output == Format::Json {
+        let summaries: Vec<Summary> = running.iter().map(Summary::from).collect();
+        format::print_json(&summaries);
+        return Ok(());
+    }
+
+    let now = state::now();
+    println!(
+        "{:<16} {:>8} {:<24} {:>8}  {:<9}  {:<24} ROOTFS",
+        "NAME", "PID", "COMMAND", "UPTIME", "HEALTH", "LABELS"
+    );
+    let truncate = |s: String| {
+        if s.chars().count() > 24 {
+            s.chars().take(21).collect::<String>() + "..."
+        } else {
+            s
+        }
+    };
+    for state in running {
+        let mut labels: Vec<String> = state
+            .labels
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        labels.sort();
+        let labels = if labels.is_empty() {
+            "-".into()
+        } else {
+            labels.join(",")
+        };
+        println!(
+            "{:<16} {:>8} {:<24} {:>8}  {:<9}  {:<24} {}",
+            state.name,
+            state.pid,
+            truncate(state.command.join(" ")),
+            units::format_duration(now.saturating_sub(state.started)),
+            state.health.map_or("-", |health| health.as_str()),
+            truncate(labels),
+            state.rootfs.display()
+        );
+    }
+    Ok(())
+}
+
+/// CPU is a share of one CPU over the last second, so it takes two
+/// readings a second apart.
+fn stats(parser: &mut Parser) -> Result<()> {
+    // --stream is the only flag
+    let mut stream = false;
+    while parser.next()?.is_some() {
+        stream = true;
+    }
+    let names: Vec<String> = match parser.rest() {
+        [] => State::all()?
+            .into_iter()
+            .filter(|state| state.is_running() && !state.cgroups.is_empty())
+            .map(|state| state.name)
+            .collect(),
+        names => names.to_vec(),
+    };
+
+    let read = || -> Result<Vec<_>> { names.iter().map(|name| cfs::stats(name)).collect() };
+    let mut before = (read()?, Instant::now());
+    loop {
+        std::thread::sleep(STATS_INTERVAL);
+        let now = (read()?, Instant::now());
+        let elapsed = now.1.duration_since(before.1).as_micros() as f64;
+        println!(
+            "{:<16} {:>7}  {:<21} {:>11}",
+            "NAME", "CPU %", "MEM USAGE / LIMIT", "PIDS"
+        );
+        for ((name, old), new) in names.iter().zip(&before.0).zip(&now.0) {
+            let cpu = match (old.cpu_usec, new.cpu_usec) {
+                (Some(old), Some(new)) => {
+                    format!("{:.1}%", new.saturating_sub(old) as f64 * 100.0 / elapsed)
+                }
+                _ => "-".into(),
+            };
+            let memory = format!(
+                "{} / {}",
+                new.memory.map_or("-".into(), units::format_size),
+                new.memory_max.map_or("-".into(), units::format_size)
+            );
+            let pids = format!(
+                "{} / {}",
+                new.pids.map_or("-".into(), |n| n.to_string()),
+                new.pids_max.map_or("-".into(), |n| n.to_string())
+            );
+            println!("{:<16} {:>7}  {:<21} {:>11}", name, cpu, memory, pids);
+        }
+        if !stream {
+            return Ok(());
+        }
+        println!();
+        before = now;
+    }
+}
+
+fn inspect(parser: &mut Parser) -> Result<()> {
+    parser.next()?;
+    let [name] = parser.rest() else {
+        return Err(CfsError::Usage("inspect needs a container name".into()));
+    };
+
+    let inspection = cfs::inspect(name)?;
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&inspection).expect("reports serialize")
+    );
+    Ok(())
+}
+
+fn stop(parser: &mut Parser) -> Result<()> {
+    let mut timeout = DEFAULT_STOP_TIMEOUT;
+    // --time is the only flag
+    while let Some(flag) = parser.next()? {
+        let value = flag.value();
+        timeout = value.parse().map_err(|_| {
+            CfsError::Usage(format!("Invalid grace period {}, expected seconds", value))
+        })?;
+    }
+    let [name] = parser.rest() else {
+        return Err(CfsError::Usage("stop needs a container name".into()));
+    };
+
+    cfs::stop(name, Duration::from_secs(timeout))?;
+    println!("{}", name);
+    Ok(())
+}
+
+/// Prints the log of a detached container. With `--follow` it keeps
+/// printing what gets appended for as long as the container runs.
+fn logs(parser: &mut Parser) -> Result<()> {
+    // --follow is the only flag
+    let mut follow = false;
+    while parser.next()?.is_some() {
+        follow = true;
+    }
+    let [name] = parser.rest() else {
+        return Err(CfsError::Usage("logs needs a container name".into()));
+    };
+
+    let path = State::log_path(name);
+    let mut log = fs::File::open(&path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => CfsError::Usage(format!(
+            "No logs for {}, only detached containers have them",
+            name
+        )),
+        _ => CfsError::Io {
+            context: format!("Failed to open {}", path.display()),
+            source: e,
+        },
+    })?;
+    let mut stdout = std::io::stdout();
+    std::io::copy(&mut log, &mut stdout).context("Failed to print the log")?;
+    if !follow {
+        return Ok(());
+    }
+
+    // read() at the end of a growing file just returns 0, so poll
+    loop {
+        let running = State::load(name).is_ok_and(|state| state.is_running());
+        let copied = std::io::copy(&mut log, &mut stdout).context("Failed to print the log")?;
+        if copied == 0 {
+            if !running {
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(200));
+        }
+    }
+}
+
+/// Exactly one side of the copy is in a container, as `<name>:<path>`.
+fn cp(parser: &mut Parser) -> Result<()> {
+    parser.next()?;
+    let [src, dest] = parser.rest() else {
+        return Err(CfsError::Usage(
+            "cp needs a source and a destination".into(),
+        ));
+    };
+
+    match (in_container(src)?, in_container(dest)?) {
+        (None, Some((name, path))) => cfs::copy_in(name, &expand_home(src)?, path),
+        (Some((name, path)), None) => cfs::copy_out(name, path, &expand_home(dest)?),
+        _ => Err(CfsError::Usage(
+            "cp copies between the host and a container, one side must be <name>:<path>".into(),
+        )),
+    }
+}
+
+// a colon after something that isn't a path, as in `web:/etc/hosts`
+fn in_container(arg: &str) -> Result<Option<(&str, &Path)>> {
+    match arg.split_once(':') {
+        Some((name, path)) if !name.is_empty() && !name.contains('/') => {
+            if !path.starts_with('/') {
+                return Err(CfsError::Usage(format!(
+                    "Container path {} must be absolute",
+                    path
+                )));
+            }
+            Ok(Some((name, Path::new(path))))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Writes to stdout without `--output`, unless that is a terminal.
+fn export(parser: &mut Parser) -> Result<()> {
+    let mut output = None;
+    // --output is the only flag
+    let names = parser.interspersed(|flag| {
+        output = Some(expand_home(flag.value())?);
+        Ok(())
+    })?;
+    let [name] = names[..] else {
+        return Err(CfsError::Usage("export needs a container name".into()));
+    };
+
+    let Some(path) = output else {
+        let stdout = std::io::stdout();
+        if stdout.is_terminal() {
+            return Err(CfsError::Usage(
+                "Not writing a tarball to a terminal, redirect it or use --output".into(),
+            ));
+        }
+        return cfs::export(name, stdout.lock());
+    };
+    let file = fs::File::create(&path).context(format!("Failed to create {}", path.display()))?;
+    cfs::export(name, BufWriter::new(file)).inspect_err(|_| {
+        let _ = fs::remove_file(&path);
+    })
+}
+
+/// Prints the directory unpacked into, for `cfs run --rootfs`.
+fn import(parser: &mut Parser) -> Result<()> {
+    let mut dest = None;
+    // --as is the only flag
+    let archives = parser.interspersed(|flag| {
+        dest = Some(expand_home(flag.value())?);
+        Ok(())
+    })?;
+    let [archive] = archives[..] else {
+        return Err(CfsError::Usage("import needs a tarball".into()));
+    };
+    let Some(dest) = dest else {
+        return Err(CfsError::Usage(
+            "import needs --as with the directory to unpack into".into(),
+        ));
+    };
+
+    cfs::archive::import(Path::new(archive), &dest)?;
+    println!("{}", dest.display());
+    Ok(())
+}
+
+fn commit(parser: &mut Parser) -> Result<()> {
+    parser.next()?;
+    let [name, rootfs] = parser.rest() else {
+        return Err(CfsError::Usage(
+            "commit needs a container name and a name for the rootfs".into(),
+        ));
+    };
+
+    let dest = cfs::commit(name, rootfs)?;
+    println!("{}", dest.display());
+    Ok(())
+}
+
+/// Prints where the new rootfs went, for `cfs run --rootfs`.
+fn build(parser: &mut Parser) -> Result<()> {
+    let mut from = None;
+    let mut steps = Vec::new();
+    let mut name = None;
+    let mut overlay = true;
+    while let Some(flag) = parser.next()? {
+        match flag.name() {
+            "from" => from = Some(flag.value().to_string()),
+            "run" => steps.push(flag.value().to_string()),
+            "as" => name = Some(flag.value().to_string()),
+            _ => overlay = false,
+        }
+    }
+    if !parser.rest().is_empty() {
+        return Err(CfsError::Usage(
+            "build takes no arguments, the commands go in --run".into(),
+        ));
+    }
+    let (Some(from), Some(name)) = (from, name) else {
+        return Err(CfsError::Usage(
+            "build needs --from with what to start from and --as with a name".into(),
+        ));
+    };
+
+    // a rootfs by that name first, as --rootfs has it
+    let rootfs = rootfs::resolve(&expand_home(&from)?);
+    let container = if rootfs.is_dir() {
+        Container::new().rootfs(rootfs)
+    } else {
+        let distro = Distro::parse(&from).map_err(|_| {
+            CfsError::Usage(format!(
+                "No rootfs {}, nor a distro such as ubuntu:jammy or alpine",
+                from
+            ))
+        })?;
+        Container::new()
+            .rootfs(rootfs::base(&distro))
+            .distro(distro)
+    };
+    let dest = cfs::build(container.overlay(overlay), &steps, &name)?;
+    println!("{}", dest.display());
+    Ok(())
+}
+
+fn version(parser: &mut Parser) -> Result<()> {
+    if parser.next()?.is_some() || !parser.rest().is_empty() {
+        return Err(CfsError::Usage("version takes no arguments".into()));
+    }
+    println!("{}", version_line());
+    Ok(())
+}
+
+fn completions(parser: &mut Parser) -> Result<()> {
+    parser.next()?;
+    let [shell] = parser.rest() else {
+        return Err(CfsError::Usage(
+            "completions needs a shell: bash, zsh or fish".into(),
+        ));
+    };
+    let script = completions::script(shell).ok_or_else(|| {
+        CfsError::Usage(format!(
+            "No completions for {}, expected bash, zsh or fish",
+            shell
+        ))
+    })?;
+    print!("{}", script);
+    Ok(())
+}
+
+/// `cfs 0.1.0 (1a2b3c4, x86_64-unknown-linux-gnu)`, for bug reports.
+fn version_line() -> String {
+    format!(
+        "cfs {} ({}, {})",
+        env!("CARGO_PKG_VERSION"),
+        option_env!("CFS_GIT_COMMIT").unwrap_or("unknown commit"),
+        env!("CFS_TARGET")
+    )
+}
+
+/// `pause` and `resume`.
+fn freeze(parser: &mut Parser, frozen: bool) -> Result<()> {
+    parser.next()?;
+    let [name] = parser.rest() else {
+        return Err(CfsError::Usage("Need a container name".into()));
+    };
+    if frozen {
+        cfs::pause(name)?;
+    } else {
+        cfs::resume(name)?;
+    }
+    println!("{}", name);
+    Ok(())
+}
+
+fn exec(parser: &mut Parser) -> Result<()> {
+    parser.next()?;
+    let [name, command @ ..] = parser.rest() else {
+        return Err(CfsError::Usage("exec needs a container name".into()));
+    };
+    let status = cfs::exec(name, command)?;
+    std::process::exit(cfs::exit_code(status));
+}
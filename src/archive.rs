@@ -0,0 +1,225 @@
+//! Rootfs tarballs: what `cfs export` writes and `cfs import` unpacks, and
+//! what `cfs commit` copies through.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+
+use flate2::read::GzDecoder;
+
+use crate::error::{CfsError, Context, Result, check};
+use crate::mount;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Writes the tree below `root` to `writer` as a tar archive, keeping
+/// ownership, permissions and symlinks. `mounts` are mount points below
+/// it, as absolute paths: directories among them go in empty, files are
+/// left out.
+pub fn pack(root: &Path, mounts: &HashSet<PathBuf>, writer: impl Write) -> Result<()> {
+    let mut builder = tar::Builder::new(writer);
+    builder.follow_symlinks(false);
+    append_children(&mut builder, root, Path::new(""), mounts)?;
+    builder
+        .into_inner()
+        .and_then(|mut writer| writer.flush())
+        .context("Failed to finish the archive")
+}
+
+fn append_children(
+    builder: &mut tar::Builder<impl Write>,
+    root: &Path,
+    dir: &Path,
+    mounts: &HashSet<PathBuf>,
+) -> Result<()> {
+    let full = root.join(dir);
+    let mut entries = fs::read_dir(&full)
+        .and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+        .context(format!("Failed to read /{}", dir.display()))?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = dir.join(entry.file_name());
+        let kind = entry
+            .file_type()
+            .context(format!("Failed to stat /{}", path.display()))?;
+        let mounted = mounts.contains(&Path::new("/").join(&path));
+        // a socket is only good to the process listening on it
+        if kind.is_socket() || (mounted && !kind.is_dir()) {
+            continue;
+        }
+        let appended = if kind.is_fifo() || kind.is_char_device() || kind.is_block_device() {
+            append_special(builder, &entry.path(), &path)
+        } else {
+            builder.append_path_with_name(entry.path(), &path)
+        };
+        appended.context(format!("Failed to archive /{}", path.display()))?;
+        if kind.is_dir() && !mounted {
+            append_children(builder, root, &path, mounts)?;
+        }
+    }
+    Ok(())
+}
+
+// tar's own append_path_with_name names these after their source path
+fn append_special(
+    builder: &mut tar::Builder<impl Write>,
+    source: &Path,
+    path: &Path,
+) -> io::Result<()> {
+    let stat = fs::symlink_metadata(source)?;
+    let kind = stat.file_type();
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata(&stat);
+    header.set_entry_type(if kind.is_fifo() {
+        tar::EntryType::Fifo
+    } else if kind.is_char_device() {
+        tar::EntryType::Char
+    } else {
+        tar::EntryType::Block
+    });
+    let rdev = stat.rdev();
+    header.set_device_major(libc::major(rdev))?;
+    header.set_device_minor(libc::minor(rdev))?;
+    header.set_size(0);
+    builder.append_data(&mut header, path, io::empty())
+}
+
+/// Copies the tree below `src` into `dest`, a new directory, with what
+/// [`pack`] keeps.
+pub fn copy(src: &Path, dest: &Path) -> Result<()> {
+    let (reader, writer) = io::pipe().context("pipe failed")?;
+    fs::create_dir_all(dest).context(format!("Failed to create {}", dest.display()))?;
+    thread::scope(|scope| {
+        let packer = scope.spawn(|| pack(src, &HashSet::new(), writer));
+        let unpacked = unpack(reader, dest);
+        let packed = packer.join().expect("packing doesn't panic");
+        // pack only sees a broken pipe when the unpacking failed first
+        match packed {
+            Err(CfsError::Io { source, .. }) if source.kind() == io::ErrorKind::BrokenPipe => {
+                unpacked
+            }
+            packed => packed.and(unpacked),
+        }
+    })
+}
+
+/// Extracts the tarball at `archive`, gzipped or not, into `dest`, which
+/// must be empty if it exists at all.
+pub fn import(archive: &Path, dest: &Path) -> Result<()> {
+    if fs::read_dir(dest).is_ok_and(|mut entries| entries.next().is_some()) {
+        return Err(CfsError::Usage(format!(
+            "{} is not empty, import into a new directory",
+            dest.display()
+        )));
+    }
+    let file = File::open(archive).context(format!("Failed to open {}", archive.display()))?;
+    let mut reader = BufReader::new(file);
+    let gzipped = reader
+        .fill_buf()
+        .context(format!("Failed to read {}", archive.display()))?
+        .starts_with(&GZIP_MAGIC);
+
+    fs::create_dir_all(dest).context(format!("Failed to create {}", dest.display()))?;
+    if gzipped {
+        unpack(GzDecoder::new(reader), dest)
+    } else {
+        unpack(reader, dest)
+    }
+}
+
+fn unpack(reader: impl Read, dest: &Path) -> Result<()> {
+    let root = unsafe { libc::geteuid() } == 0;
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    // chown takes root; without it everything is ours, which is what a
+    // user namespace maps to root anyway
+    archive.set_preserve_ownerships(root);
+    archive.set_overwrite(true);
+
+    let entries = archive.entries().context("Failed to read the tarball")?;
+    for entry in entries {
+        let mut entry = entry.context("Failed to read the tarball")?;
+        let path = entry
+            .path()
+            .context("Invalid path in the tarball")?
+            .into_owned();
+        let kind = entry.header().entry_type();
+        // tar writes these as plain files
+        if kind.is_fifo() || kind.is_character_special() || kind.is_block_special() {
+            make_node(&entry, &path, dest, root)?;
+        } else {
+            entry
+                .unpack_in(dest)
+                .context(format!("Failed to extract {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+fn make_node(entry: &tar::Entry<impl Read>, path: &Path, dest: &Path, root: bool) -> Result<()> {
+    let header = entry.header();
+    let relative: PathBuf = path
+        .components()
+        .filter(|c| !matches!(c, Component::CurDir))
+        .collect();
+    // the parent may be a symlink from an earlier entry, so check where
+    // it really is
+    let parent = relative
+        .parent()
+        .map_or(dest.to_path_buf(), |p| dest.join(p));
+    let escapes = relative
+        .components()
+        .any(|c| matches!(c, Component::ParentDir | Component::RootDir))
+        || !fs::canonicalize(&parent)
+            .is_ok_and(|parent| fs::canonicalize(dest).is_ok_and(|dest| parent.starts_with(dest)));
+    if escapes {
+        return Err(CfsError::Command(format!(
+            "Tarball entry {} escapes {}",
+            path.display(),
+            dest.display()
+        )));
+    }
+
+    let kind = header.entry_type();
+    let file_type = if kind.is_fifo() {
+        libc::S_IFIFO
+    } else if kind.is_character_special() {
+        libc::S_IFCHR
+    } else {
+        libc::S_IFBLK
+    };
+    let mode = header.mode().context("Invalid mode in the tarball")? & 0o7777;
+    let major = header.device_major().ok().flatten().unwrap_or(0);
+    let minor = header.device_minor().ok().flatten().unwrap_or(0);
+    let target = dest.join(&relative);
+    let _ = fs::remove_file(&target);
+    let c_target = mount::cstring(&target)?;
+    let made = unsafe {
+        libc::mknod(
+            c_target.as_ptr(),
+            file_type | mode,
+            libc::makedev(major, minor),
+        )
+    };
+    if made < 0 {
+        let e = io::Error::last_os_error();
+        // device nodes take root, and /dev is mounted over at run time anyway
+        if e.raw_os_error() == Some(libc::EPERM) && file_type != libc::S_IFIFO {
+            return Ok(());
+        }
+        return Err(e).context(format!("Failed to create {}", target.display()));
+    }
+    if root {
+        let uid = header.uid().context("Invalid owner in the tarball")?;
+        let gid = header.gid().context("Invalid group in the tarball")?;
+        check(
+            unsafe { libc::lchown(c_target.as_ptr(), uid as libc::uid_t, gid as libc::gid_t) },
+            &format!("Failed to chown {}", target.display()),
+        )?;
+    }
+    Ok(())
+}
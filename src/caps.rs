@@ -0,0 +1,212 @@
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{CfsError, Result};
+use crate::user::User;
+
+const CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// Indexed by capability number, see capabilities(7).
+const NAMES: &[&str] = &[
+    "CHOWN",
+    "DAC_OVERRIDE",
+    "DAC_READ_SEARCH",
+    "FOWNER",
+    "FSETID",
+    "KILL",
+    "SETGID",
+    "SETUID",
+    "SETPCAP",
+    "LINUX_IMMUTABLE",
+    "NET_BIND_SERVICE",
+    "NET_BROADCAST",
+    "NET_ADMIN",
+    "NET_RAW",
+    "IPC_LOCK",
+    "IPC_OWNER",
+    "SYS_MODULE",
+    "SYS_RAWIO",
+    "SYS_CHROOT",
+    "SYS_PTRACE",
+    "SYS_PACCT",
+    "SYS_ADMIN",
+    "SYS_BOOT",
+    "SYS_NICE",
+    "SYS_RESOURCE",
+    "SYS_TIME",
+    "SYS_TTY_CONFIG",
+    "MKNOD",
+    "LEASE",
+    "AUDIT_WRITE",
+    "AUDIT_CONTROL",
+    "SETFCAP",
+    "MAC_OVERRIDE",
+    "MAC_ADMIN",
+    "SYSLOG",
+    "WAKE_ALARM",
+    "BLOCK_SUSPEND",
+    "AUDIT_READ",
+    "PERFMON",
+    "BPF",
+    "CHECKPOINT_RESTORE",
+];
+
+/// What an unprivileged-looking root in a container usually still needs to
+/// install packages and run services; the same set Docker keeps.
+const DEFAULTS: &[&str] = &[
+    "CHOWN",
+    "DAC_OVERRIDE",
+    "FOWNER",
+    "FSETID",
+    "KILL",
+    "SETGID",
+    "SETUID",
+    "SETPCAP",
+    "NET_BIND_SERVICE",
+    "NET_RAW",
+    "SYS_CHROOT",
+    "MKNOD",
+    "AUDIT_WRITE",
+    "SETFCAP",
+];
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// The capabilities the container command keeps, as a bitmask.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Capabilities {
+    keep: u64,
+}
+
+impl Default for Capabilities {
+    fn default() -> Capabilities {
+        let keep = DEFAULTS
+            .iter()
+            .map(|name| 1 << number(name).unwrap())
+            .fold(0, |acc, bit| acc | bit);
+        Capabilities { keep }
+    }
+}
+
+impl Capabilities {
+    /// The names of those kept, in capability order.
+    pub fn names(&self) -> Vec<&'static str> {
+        NAMES
+            .iter()
+            .enumerate()
+            .filter(|&(cap, _)| self.keep & (1 << cap) != 0)
+            .map(|(_, &name)| name)
+            .collect()
+    }
+
+    /// `--cap-add NAME`, where NAME may be `ALL`.
+    pub fn add(&mut self, name: &str) -> Result<()> {
+        self.keep |= mask(name)?;
+        Ok(())
+    }
+
+    /// `--cap-drop NAME`, where NAME may be `ALL`.
+    pub fn drop(&mut self, name: &str) -> Result<()> {
+        self.keep &= !mask(name)?;
+        Ok(())
+    }
+
+    /// Clears everything not kept from the bounding set, then narrows the
+    /// permitted, effective and inheritable sets. Runs between fork and exec,
+    /// so it only makes syscalls and doesn't allocate. With a `user` to run
+    /// as, the switch comes in between: after the bounding set, which only
+    /// root may change, and before the rest, which it would clear.
+    pub fn apply(&self, user: Option<User>) -> io::Result<()> {
+        // the bounding set is what exec grants root, so it's what matters;
+        // dropping from it needs CAP_SETPCAP, which goes last with capset
+        let mut keep = self.keep;
+        for cap in 0..64 {
+            // past the last capability this kernel knows about; and what we
+            // don't have ourselves can't be kept either
+            match unsafe { libc::prctl(libc::PR_CAPBSET_READ, cap, 0, 0, 0) } {
+                0 => keep &= !(1 << cap),
+                1 => {}
+                _ => {
+                    keep &= (1 << cap) - 1;
+                    break;
+                }
+            }
+            if keep & (1 << cap) == 0
+                && unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) } != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        if let Some(user) = user {
+            // keeps the permitted set through setuid, the effective one
+            // goes anyway and comes back with capset
+            if unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            user.switch()?;
+        }
+
+        let mut header = CapHeader {
+            version: CAPABILITY_VERSION_3,
+            pid: 0,
+        };
+        let mut data = [CapData::default(); 2];
+        for (n, word) in data.iter_mut().enumerate() {
+            let bits = (keep >> (32 * n)) as u32;
+            *word = CapData {
+                effective: bits,
+                permitted: bits,
+                inheritable: bits,
+            };
+        }
+        if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Whether the calling process has `name` in its effective set.
+pub fn effective(name: &str) -> bool {
+    let Some(cap) = number(name) else {
+        return false;
+    };
+    let mut header = CapHeader {
+        version: CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } != 0 {
+        return false;
+    }
+    data[cap / 32].effective & (1 << (cap % 32)) != 0
+}
+
+fn number(name: &str) -> Option<usize> {
+    let name = name.to_ascii_uppercase();
+    let name = name.strip_prefix("CAP_").unwrap_or(&name);
+    NAMES.iter().position(|n| *n == name)
+}
+
+fn mask(name: &str) -> Result<u64> {
+    if name.eq_ignore_ascii_case("ALL") {
+        return Ok(u64::MAX);
+    }
+    number(name)
+        .map(|cap| 1 << cap)
+        .ok_or_else(|| CfsError::Usage(format!("Unknown capability {}", name)))
+}
@@ -0,0 +1,893 @@
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use log::{debug, warn};
+use serde::Serialize;
+
+use crate::error::{CfsError, Context, Result};
+use crate::plan::Plan;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+// where hybrid hosts mount the unified hierarchy, next to the v1 controllers
+const HYBRID_UNIFIED_ROOT: &str = "/sys/fs/cgroup/unified";
+const CGROUP_NAME: &str = "cfs";
+const CPU_PERIOD: u64 = 100_000; // microseconds, the kernel default
+const MIN_CPUS: f64 = 0.01; // the kernel rejects quotas below 1ms
+/// What v1 device control grants when everything else is denied: creating
+/// nodes, the devices `mount::setup_dev` provides, and PTYs.
+const DEFAULT_DEVICES: &[&str] = &[
+    "c *:* m",
+    "b *:* m",
+    "c 1:3 rwm",   // null
+    "c 1:5 rwm",   // zero
+    "c 1:7 rwm",   // full
+    "c 1:8 rwm",   // random
+    "c 1:9 rwm",   // urandom
+    "c 5:0 rwm",   // tty
+    "c 5:2 rwm",   // ptmx
+    "c 136:* rwm", // pts
+];
+/// Enough for a parallel build, still a stop to a fork bomb.
+pub const DEFAULT_PIDS_MAX: u64 = 1024;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+impl Version {
+    /// Parses `--cgroup-version`; `auto` is `None`, leaving it to [`detect`].
+    pub fn parse(s: &str) -> Result<Option<Version>> {
+        match s {
+            "auto" => Ok(None),
+            "v1" | "1" => Ok(Some(Version::V1)),
+            "v2" | "2" => Ok(Some(Version::V2)),
+            _ => Err(CfsError::Usage(format!(
+                "Invalid cgroup version {}, expected auto, v1 or v2",
+                s
+            ))),
+        }
+    }
+
+    fn root(self) -> PathBuf {
+        let hybrid = Path::new(HYBRID_UNIFIED_ROOT);
+        match self {
+            Version::V2
+                if !Path::new(CGROUP_ROOT).join("cgroup.controllers").exists()
+                    && hybrid.join("cgroup.controllers").exists() =>
+            {
+                hybrid.to_path_buf()
+            }
+            _ => PathBuf::from(CGROUP_ROOT),
+        }
+    }
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Version::V1 => write!(f, "v1"),
+            Version::V2 => write!(f, "v2"),
+        }
+    }
+}
+
+/// Resource limits applied to the container's cgroup. `None` leaves the
+/// controller alone.
+pub struct Limits {
+    pub memory: Option<u64>,
+    pub cpus: Option<f64>,
+    /// CPUs to pin to, in the kernel's list format.
+    pub cpuset: Option<String>,
+    /// Always applied, `None` is no limit.
+    pub pids: Option<u64>,
+    /// Device rules such as `c 10:229 rwm` allowed on top of
+    /// [`DEFAULT_DEVICES`]. Enforced on v1 only, v2 takes a BPF program.
+    pub devices: Vec<String>,
+    /// Has a container over its memory limit wait for memory rather than
+    /// lose a process to the OOM killer. v1 only, v2 has no such switch.
+    pub oom_kill_disable: bool,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            memory: None,
+            cpus: None,
+            cpuset: None,
+            pids: Some(DEFAULT_PIDS_MAX),
+            devices: Vec::new(),
+            oom_kill_disable: false,
+        }
+    }
+}
+
+/// Parses `--pids-max`, where `0` and `unlimited` lift the limit.
+pub fn parse_pids_max(s: &str) -> Result<Option<u64>> {
+    match s {
+        "unlimited" => Ok(None),
+        _ => match s.parse::<u64>() {
+            Ok(0) => Ok(None),
+            Ok(n) => Ok(Some(n)),
+            Err(_) => Err(CfsError::Usage(format!(
+                "Invalid --pids-max {}, expected a number or unlimited",
+                s
+            ))),
+        },
+    }
+}
+
+/// The value of `pids.max` for a limit.
+fn pids_max(limits: &Limits) -> String {
+    limits.pids.map_or("max".to_string(), |n| n.to_string())
+}
+
+/// Parses a fractional CPU count such as `1.5`, clamped between
+/// [`MIN_CPUS`] and the number of CPUs available on the host.
+pub fn parse_cpus(s: &str) -> Result<f64> {
+    let cpus: f64 = s
+        .parse()
+        .ok()
+        .filter(|c: &f64| c.is_finite() && *c > 0.0)
+        .ok_or_else(|| CfsError::Usage(format!("Invalid --cpus {}, expected e.g. 1.5", s)))?;
+    let available = std::thread::available_parallelism().map_or(1, |n| n.get()) as f64;
+    Ok(cpus.clamp(MIN_CPUS, available))
+}
+
+/// Checks a CPU list such as `0-3,5`, the format `cpuset.cpus` takes.
+/// Whether the CPUs exist is left to the kernel.
+pub fn parse_cpuset(s: &str) -> Result<String> {
+    let invalid = || CfsError::Usage(format!("Invalid --cpuset-cpus {}, expected e.g. 0-3,5", s));
+    for item in s.split(',') {
+        let (first, last) = item.split_once('-').unwrap_or((item, item));
+        let first: u32 = first.parse().map_err(|_| invalid())?;
+        let last: u32 = last.parse().map_err(|_| invalid())?;
+        if first > last {
+            return Err(invalid());
+        }
+    }
+    Ok(s.to_string())
+}
+
+fn cpu_quota(cpus: f64) -> u64 {
+    (cpus * CPU_PERIOD as f64).round() as u64
+}
+
+/// Parses `--cgroup-parent`, a path below the root of the hierarchy such
+/// as `system.slice/app.slice`; a leading `/` is allowed.
+pub fn parse_parent(s: &str) -> Result<PathBuf> {
+    let path = Path::new(s.trim_start_matches('/'));
+    if path.as_os_str().is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(CfsError::Usage(format!(
+            "Invalid --cgroup-parent {}, expected a path such as system.slice/app.slice",
+            s
+        )));
+    }
+    Ok(path.to_path_buf())
+}
+
+/// The unified hierarchy exposes `cgroup.controllers` at its root, the v1
+/// layout has one directory per controller instead. Hybrid hosts have both,
+/// with the controllers, and so the limits, on the v1 side.
+pub fn detect() -> Version {
+    if Path::new(CGROUP_ROOT).join("cgroup.controllers").exists() {
+        Version::V2
+    } else {
+        Version::V1
+    }
+}
+
+/// The cgroup of one container, `cfs/<id>` or `<parent>/<id>` below the
+/// root of every hierarchy it was added to.
+pub struct Cgroup {
+    plan: Plan,
+    version: Version,
+    root: PathBuf,
+    parent: PathBuf,
+    id: String,
+    paths: Vec<PathBuf>,
+}
+
+impl Cgroup {
+    /// Creates the container's cgroup, below `parent` rather than `cfs` if
+    /// given, applies the limits and moves `pid` into it. Whatever was
+    /// created is removed again if a step fails.
+    pub fn create(
+        plan: Plan,
+        version: Version,
+        parent: Option<&Path>,
+        id: &str,
+        pid: libc::pid_t,
+        limits: &Limits,
+    ) -> Result<Cgroup> {
+        Cgroup::create_in(plan, &version.root(), version, parent, id, pid, limits)
+    }
+
+    /// [`Cgroup::create`] below `root` rather than the host's hierarchy,
+    /// which then holds the unified tree or a directory per v1 controller.
+    pub fn create_in(
+        plan: Plan,
+        root: &Path,
+        version: Version,
+        parent: Option<&Path>,
+        id: &str,
+        pid: libc::pid_t,
+        limits: &Limits,
+    ) -> Result<Cgroup> {
+        let mut cgroup = Cgroup {
+            plan,
+            version,
+            root: root.to_path_buf(),
+            parent: parent.unwrap_or(Path::new(CGROUP_NAME)).to_path_buf(),
+            id: id.to_string(),
+            paths: Vec::new(),
+        };
+        // cfs makes its own level, someone else's has to be there already;
+        // on v1 the pids hierarchy, which every container joins, tells
+        if parent.is_some() {
+            cgroup.check_parent()?;
+        }
+        let result = match cgroup.version {
+            Version::V2 => cgroup.setup_v2(pid, limits),
+            Version::V1 => cgroup.setup_v1(pid, limits),
+        };
+        if let Err(e) = result {
+            let _ = cgroup.remove();
+            return Err(e);
+        }
+        Ok(cgroup)
+    }
+
+    fn path(&self, controller: &str) -> PathBuf {
+        self.parent_path(controller).join(&self.id)
+    }
+
+    fn parent_path(&self, controller: &str) -> PathBuf {
+        match self.version {
+            Version::V2 => self.root.join(&self.parent),
+            Version::V1 => self.root.join(controller).join(&self.parent),
+        }
+    }
+
+    /// The hierarchy root of `controller` and every directory from there
+    /// down to the parent, in that order.
+    fn levels(&self, controller: &str) -> Vec<PathBuf> {
+        let mut level = self.parent_path(controller);
+        let mut levels = vec![level.clone()];
+        for _ in self.parent.components() {
+            level.pop();
+            levels.push(level.clone());
+        }
+        levels.reverse();
+        levels
+    }
+
+    fn check_parent(&self) -> Result<()> {
+        // the library's callers may not have gone through parse_parent
+        parse_parent(&self.parent.to_string_lossy())?;
+        let parent = self.parent_path("pids");
+        if !parent.is_dir() {
+            return Err(CfsError::Usage(format!(
+                "The cgroup parent {} does not exist",
+                parent.display()
+            )));
+        }
+        let path = CString::new(parent.as_os_str().as_bytes())
+            .map_err(|_| CfsError::Usage(format!("Invalid path {}", parent.display())))?;
+        if unsafe { libc::access(path.as_ptr(), libc::W_OK) } != 0 {
+            return Err(CfsError::Usage(format!(
+                "The cgroup parent {} is not writable",
+                parent.display()
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Whether the kernel OOM killer has struck inside this cgroup.
+    pub fn oom_killed(&self) -> bool {
+        let events = match self.version {
+            Version::V2 => self.path("memory").join("memory.events"),
+            Version::V1 => self.path("memory").join("memory.oom_control"),
+        };
+        fs::read_to_string(events)
+            .map(|contents| {
+                contents.lines().any(|line| {
+                    line.strip_prefix("oom_kill ")
+                        .and_then(|n| n.trim().parse::<u64>().ok())
+                        .is_some_and(|n| n > 0)
+                })
+            })
+            .unwrap_or(false)
+    }
+
+    pub fn remove(&self) -> Result<()> {
+        remove_dirs(&self.paths)
+    }
+
+    fn mkdir(&mut self, path: PathBuf) -> Result<()> {
+        create_dir(self.plan, &path)?;
+        self.paths.push(path);
+        Ok(())
+    }
+
+    fn setup_v2(&mut self, pid: libc::pid_t, limits: &Limits) -> Result<()> {
+        // controllers are only available to a child once its parent delegates
+        // them, which has to happen at every level down to the container
+        let mut controllers = vec!["+pids"];
+        // memory.current is only there with the controller, which `stats`
+        // wants even without a limit
+        let available = read(self.plan, &self.root.join("cgroup.controllers")).unwrap_or_default();
+        if limits.memory.is_some() || available.split_whitespace().any(|c| c == "memory") {
+            controllers.push("+memory");
+        }
+        if limits.cpus.is_some() {
+            controllers.push("+cpu");
+        }
+        if limits.cpuset.is_some() {
+            controllers.push("+cpuset");
+        }
+        let controllers = controllers.join(" ");
+        let parent = self.parent_path("");
+        create_dir(self.plan, &parent)?;
+        for level in self.levels("") {
+            write(
+                self.plan,
+                &level.join("cgroup.subtree_control"),
+                &controllers,
+            )?;
+        }
+
+        let cgroup = self.path("");
+        self.mkdir(cgroup.clone())?;
+        write(self.plan, &cgroup.join("pids.max"), &pids_max(limits))?;
+        if let Some(memory) = limits.memory {
+            write(self.plan, &cgroup.join("memory.max"), &memory.to_string())?;
+        }
+        if limits.oom_kill_disable {
+            warn!("cgroup v2 has no way to turn the OOM killer off, ignoring --oom-kill-disable");
+        }
+        if let Some(cpus) = limits.cpus {
+            let max = format!("{} {}", cpu_quota(cpus), CPU_PERIOD);
+            write(self.plan, &cgroup.join("cpu.max"), &max)?;
+        }
+        if let Some(cpus) = &limits.cpuset {
+            write(self.plan, &cgroup.join("cpuset.cpus"), cpus)?;
+            // the memory nodes the CPUs are allowed to use, all of them
+            let mems = read(self.plan, &parent.join("cpuset.mems.effective"))?;
+            write(self.plan, &cgroup.join("cpuset.mems"), &mems)?;
+        }
+        write(self.plan, &cgroup.join("cgroup.procs"), &pid.to_string())
+    }
+
+    // v1 keeps a separate hierarchy per controller, so the process has to be
+    // added to the container's cgroup in each of them.
+    fn setup_v1(&mut self, pid: libc::pid_t, limits: &Limits) -> Result<()> {
+        self.join_v1(
+            "pids",
+            pid,
+            &[
+                ("pids.max", pids_max(limits)),
+                ("notify_on_release", "1".into()),
+            ],
+        )?;
+
+        let mut memory = Vec::new();
+        if let Some(limit) = limits.memory {
+            memory.push(("memory.limit_in_bytes", limit.to_string()));
+        }
+        if limits.oom_kill_disable {
+            memory.push(("memory.oom_control", "1".into()));
+        }
+        // without limits too when mounted, to count for `stats`
+        if !memory.is_empty() || self.root.join("memory").exists() {
+            self.join_v1("memory", pid, &memory)?;
+        }
+        if self.root.join("cpuacct").exists() {
+            self.join_v1("cpuacct", pid, &[])?;
+        }
+
+        if let Some(cpus) = limits.cpus {
+            self.join_v1(
+                "cpu",
+                pid,
+                &[
+                    ("cpu.cfs_period_us", CPU_PERIOD.to_string()),
+                    ("cpu.cfs_quota_us", cpu_quota(cpus).to_string()),
+                ],
+            )?;
+        }
+
+        // for pause and resume
+        if self.root.join("freezer").exists() {
+            self.join_v1("freezer", pid, &[])?;
+        }
+
+        // deny by default, then allow the basics and what was asked for
+        if self.root.join("devices").exists() {
+            let mut rules = vec![("devices.deny", "a".to_string())];
+            for rule in DEFAULT_DEVICES.iter().map(|r| r.to_string()) {
+                rules.push(("devices.allow", rule));
+            }
+            for rule in &limits.devices {
+                rules.push(("devices.allow", rule.clone()));
+            }
+            self.join_v1("devices", pid, &rules)?;
+        }
+
+        if let Some(cpus) = &limits.cpuset {
+            // a new cpuset starts out with neither CPUs nor memory nodes, and
+            // takes no processes or children until it has both, so every
+            // level down to the parent gets what the one above has
+            let parent = self.parent_path("cpuset");
+            create_dir(self.plan, &parent)?;
+            let levels = self.levels("cpuset");
+            for pair in levels.windows(2) {
+                for file in ["cpuset.cpus", "cpuset.mems"] {
+                    if read(self.plan, &pair[1].join(file))?.is_empty() {
+                        write(
+                            self.plan,
+                            &pair[1].join(file),
+                            &read(self.plan, &pair[0].join(file))?,
+                        )?;
+                    }
+                }
+            }
+            let mems = read(self.plan, &parent.join("cpuset.mems"))?;
+            self.join_v1(
+                "cpuset",
+                pid,
+                &[("cpuset.cpus", cpus.clone()), ("cpuset.mems", mems)],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Creates the container's cgroup in one v1 controller hierarchy, writes
+    /// `files` and moves `pid` into it.
+    fn join_v1(
+        &mut self,
+        controller: &str,
+        pid: libc::pid_t,
+        files: &[(&str, String)],
+    ) -> Result<()> {
+        let cgroup = self.path(controller);
+        self.mkdir(cgroup.clone())?;
+        for (file, value) in files {
+            write(self.plan, &cgroup.join(file), value)?;
+        }
+        write(self.plan, &cgroup.join("cgroup.procs"), &pid.to_string())
+    }
+}
+
+/// Freezes or thaws every process in the container's cgroups at `paths`,
+/// through whichever of the v1 freezer or the v2 `cgroup.freeze` is there.
+pub fn freeze(paths: &[PathBuf], frozen: bool) -> Result<()> {
+    for path in paths {
+        // it takes until every task has stopped: v1 says FREEZING meanwhile,
+        // v2 flips `frozen` in cgroup.events at the end
+        let (control, value, status, settled) = if path.join("cgroup.freeze").exists() {
+            let value = if frozen { "1" } else { "0" };
+            (
+                "cgroup.freeze",
+                value,
+                "cgroup.events",
+                format!("frozen {}", value),
+            )
+        } else if path.join("freezer.state").exists() {
+            let value = if frozen { "FROZEN" } else { "THAWED" };
+            ("freezer.state", value, "freezer.state", value.to_string())
+        } else {
+            continue;
+        };
+        write(Plan::RUN, &path.join(control), value)?;
+        for _ in 0..50 {
+            if read(Plan::RUN, &path.join(status))?
+                .lines()
+                .any(|line| line == settled)
+            {
+                return Ok(());
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        return Err(CfsError::Command(format!(
+            "{} did not get to {}",
+            path.join(status).display(),
+            settled
+        )));
+    }
+    Err(CfsError::Usage(
+        "The container has no freezer cgroup, rootless ones have none".into(),
+    ))
+}
+
+/// Removes cgroup directories, innermost last created first. The kernel
+/// answers EBUSY until the last process has been fully released, which can
+/// lag behind waitpid a little.
+pub fn remove_dirs(paths: &[PathBuf]) -> Result<()> {
+    for path in paths.iter().rev() {
+        let mut attempts = 0;
+        loop {
+            match fs::remove_dir(path) {
+                Ok(()) => break,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => break,
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) && attempts < 20 => {
+                    attempts += 1;
+                    std::thread::sleep(std::time::Duration::from_millis(50));
+                }
+                Err(e) => {
+                    return Err(e).context(format!("Failed to remove {}", path.display()));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What a container has used so far, as its cgroups count it. Whatever
+/// the cgroups don't tell, without the controller or without a limit, is
+/// `None`.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Usage {
+    /// CPU time, in microseconds.
+    pub cpu_usec: Option<u64>,
+    pub memory: Option<u64>,
+    pub memory_max: Option<u64>,
+    pub pids: Option<u64>,
+    pub pids_max: Option<u64>,
+}
+
+// v1 has no "max", a limit it can't even represent means none
+const V1_NO_LIMIT: u64 = 1 << 62;
+
+/// Reads the usage from `paths`, the v2 directory or the v1 controllers,
+/// each file from the first path that has it.
+pub fn usage(paths: &[PathBuf]) -> Usage {
+    let value = |file: &str| {
+        paths
+            .iter()
+            .find_map(|path| fs::read_to_string(path.join(file)).ok())
+            .map(|contents| contents.trim().to_string())
+    };
+    let number = |file: &str| value(file).and_then(|v| v.parse::<u64>().ok());
+    let limit = |file: &str| number(file).filter(|&max| max < V1_NO_LIMIT);
+
+    let cpu_usec = value("cpu.stat")
+        .and_then(|stat| {
+            stat.lines()
+                .find_map(|line| line.strip_prefix("usage_usec "))
+                .and_then(|usec| usec.trim().parse().ok())
+        })
+        .or_else(|| number("cpuacct.usage").map(|nsec| nsec / 1000));
+    Usage {
+        cpu_usec,
+        memory: number("memory.current").or_else(|| number("memory.usage_in_bytes")),
+        memory_max: limit("memory.max").or_else(|| limit("memory.limit_in_bytes")),
+        pids: number("pids.current"),
+        pids_max: limit("pids.max"),
+    }
+}
+
+fn create_dir(plan: Plan, path: &Path) -> Result<()> {
+    if plan.skip(format!("create cgroup {}", path.display())) {
+        return Ok(());
+    }
+    fs::create_dir_all(path).context("Failed to create cgroup dir")
+}
+
+fn read(plan: Plan, path: &Path) -> Result<String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents.trim().to_string()),
+        // in a dry run, of a cgroup that was never created
+        Err(_) if plan.dry_run() => Ok(format!("<{}>", path.display())),
+        Err(e) => Err(e).context(format!("Failed to read {}", path.display())),
+    }
+}
+
+fn write(plan: Plan, path: &Path, value: &str) -> Result<()> {
+    if plan.skip(format!("write {} to {}", value, path.display())) {
+        return Ok(());
+    }
+    debug!("writing {} to {}", value, path.display());
+    fs::write(path, value).context(format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory standing in for `/sys/fs/cgroup`, gone with the test.
+    struct Root(PathBuf);
+
+    impl Root {
+        fn new(test: &str) -> Root {
+            let path =
+                std::env::temp_dir().join(format!("cfs-cgroup-{}-{}", test, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Root(path)
+        }
+
+        /// Files the kernel would have there already.
+        fn with(self, files: &[(&str, &str)]) -> Root {
+            for (file, contents) in files {
+                let path = self.0.join(file);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, contents).unwrap();
+            }
+            self
+        }
+
+        fn read(&self, file: &str) -> String {
+            read(Plan::RUN, &self.0.join(file)).unwrap()
+        }
+    }
+
+    impl Drop for Root {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn v2_writes_limits_and_moves_the_process() {
+        let root = Root::new("v2-limits");
+        let limits = Limits {
+            memory: Some(256 << 20),
+            cpus: Some(1.5),
+            pids: Some(100),
+            ..Limits::default()
+        };
+        let cgroup =
+            Cgroup::create_in(Plan::RUN, &root.0, Version::V2, None, "abc", 42, &limits).unwrap();
+
+        assert_eq!(root.read("cgroup.subtree_control"), "+pids +memory +cpu");
+        assert_eq!(
+            root.read("cfs/cgroup.subtree_control"),
+            "+pids +memory +cpu"
+        );
+        assert_eq!(root.read("cfs/abc/pids.max"), "100");
+        assert_eq!(root.read("cfs/abc/memory.max"), "268435456");
+        assert_eq!(root.read("cfs/abc/cpu.max"), "150000 100000");
+        assert_eq!(root.read("cfs/abc/cgroup.procs"), "42");
+        assert_eq!(cgroup.paths(), [root.0.join("cfs/abc")]);
+    }
+
+    #[test]
+    fn v2_lifts_the_pids_limit_with_max() {
+        let root = Root::new("v2-pids");
+        let limits = Limits {
+            pids: None,
+            ..Limits::default()
+        };
+        Cgroup::create_in(Plan::RUN, &root.0, Version::V2, None, "abc", 42, &limits).unwrap();
+
+        assert_eq!(root.read("cgroup.subtree_control"), "+pids");
+        assert_eq!(root.read("cfs/abc/pids.max"), "max");
+        assert!(!root.0.join("cfs/abc/memory.max").exists());
+    }
+
+    #[test]
+    fn v2_cpuset_takes_the_parents_memory_nodes() {
+        let root = Root::new("v2-cpuset").with(&[("cfs/cpuset.mems.effective", "0-1\n")]);
+        let limits = Limits {
+            cpuset: Some("0,2".into()),
+            ..Limits::default()
+        };
+        Cgroup::create_in(Plan::RUN, &root.0, Version::V2, None, "abc", 42, &limits).unwrap();
+
+        assert_eq!(root.read("cfs/abc/cpuset.cpus"), "0,2");
+        assert_eq!(root.read("cfs/abc/cpuset.mems"), "0-1");
+    }
+
+    #[test]
+    fn v2_parent_gets_the_controllers_at_every_level() {
+        let root = Root::new("v2-parent").with(&[("system.slice/app.slice/cgroup.procs", "")]);
+        let parent = parse_parent("/system.slice/app.slice").unwrap();
+        let cgroup = Cgroup::create_in(
+            Plan::RUN,
+            &root.0,
+            Version::V2,
+            Some(&parent),
+            "abc",
+            42,
+            &Limits::default(),
+        )
+        .unwrap();
+
+        for level in ["", "system.slice/", "system.slice/app.slice/"] {
+            assert_eq!(
+                root.read(&format!("{}cgroup.subtree_control", level)),
+                "+pids"
+            );
+        }
+        assert_eq!(root.read("system.slice/app.slice/abc/cgroup.procs"), "42");
+        assert!(!root.0.join("cfs").exists());
+        assert_eq!(cgroup.paths(), [root.0.join("system.slice/app.slice/abc")]);
+    }
+
+    #[test]
+    fn parent_has_to_exist() {
+        let root = Root::new("no-parent");
+        let parent = parse_parent("missing.slice").unwrap();
+        let created = Cgroup::create_in(
+            Plan::RUN,
+            &root.0,
+            Version::V1,
+            Some(&parent),
+            "abc",
+            42,
+            &Limits::default(),
+        );
+        assert!(created.is_err());
+        assert!(!root.0.join("pids/missing.slice").exists());
+        assert!(parse_parent("../escape").is_err());
+        assert!(parse_parent("/").is_err());
+    }
+
+    #[test]
+    fn v1_turns_the_oom_killer_off() {
+        let root = Root::new("v1-oom");
+        let limits = Limits {
+            memory: Some(1 << 20),
+            oom_kill_disable: true,
+            ..Limits::default()
+        };
+        Cgroup::create_in(Plan::RUN, &root.0, Version::V1, None, "abc", 42, &limits).unwrap();
+
+        assert_eq!(root.read("memory/cfs/abc/memory.oom_control"), "1");
+        assert_eq!(root.read("memory/cfs/abc/memory.limit_in_bytes"), "1048576");
+    }
+
+    #[test]
+    fn v1_joins_every_controller_it_limits() {
+        let root = Root::new("v1-limits");
+        let limits = Limits {
+            memory: Some(1 << 20),
+            cpus: Some(0.5),
+            ..Limits::default()
+        };
+        let cgroup =
+            Cgroup::create_in(Plan::RUN, &root.0, Version::V1, None, "abc", 42, &limits).unwrap();
+
+        assert_eq!(root.read("pids/cfs/abc/pids.max"), "1024");
+        assert_eq!(root.read("pids/cfs/abc/notify_on_release"), "1");
+        assert_eq!(root.read("memory/cfs/abc/memory.limit_in_bytes"), "1048576");
+        assert_eq!(root.read("cpu/cfs/abc/cpu.cfs_period_us"), "100000");
+        assert_eq!(root.read("cpu/cfs/abc/cpu.cfs_quota_us"), "50000");
+        for controller in ["pids", "memory", "cpu"] {
+            assert_eq!(
+                root.read(&format!("{}/cfs/abc/cgroup.procs", controller)),
+                "42"
+            );
+        }
+        let paths: Vec<_> = ["pids", "memory", "cpu"]
+            .iter()
+            .map(|c| root.0.join(c).join("cfs/abc"))
+            .collect();
+        assert_eq!(cgroup.paths(), paths);
+    }
+
+    #[test]
+    fn v1_freezer_and_devices_only_where_mounted() {
+        let root = Root::new("v1-optional");
+        let cgroup = Cgroup::create_in(
+            Plan::RUN,
+            &root.0,
+            Version::V1,
+            None,
+            "abc",
+            42,
+            &Limits::default(),
+        )
+        .unwrap();
+        assert_eq!(cgroup.paths(), [root.0.join("pids/cfs/abc")]);
+
+        let root = Root::new("v1-devices");
+        fs::create_dir_all(root.0.join("freezer")).unwrap();
+        fs::create_dir_all(root.0.join("devices")).unwrap();
+        let limits = Limits {
+            devices: vec!["c 10:229 rwm".into()],
+            ..Limits::default()
+        };
+        let cgroup =
+            Cgroup::create_in(Plan::RUN, &root.0, Version::V1, None, "abc", 42, &limits).unwrap();
+        assert_eq!(root.read("freezer/cfs/abc/cgroup.procs"), "42");
+        assert_eq!(root.read("devices/cfs/abc/devices.deny"), "a");
+        // one rule per write, so a plain file keeps the last
+        assert_eq!(root.read("devices/cfs/abc/devices.allow"), "c 10:229 rwm");
+        assert_eq!(cgroup.paths().len(), 3);
+    }
+
+    #[test]
+    fn v1_cpuset_fills_in_the_cfs_level_first() {
+        let root = Root::new("v1-cpuset").with(&[
+            ("cpuset/cpuset.cpus", "0-3\n"),
+            ("cpuset/cpuset.mems", "0\n"),
+            ("cpuset/cfs/cpuset.cpus", ""),
+            ("cpuset/cfs/cpuset.mems", ""),
+        ]);
+        let limits = Limits {
+            cpuset: Some("1".into()),
+            ..Limits::default()
+        };
+        Cgroup::create_in(Plan::RUN, &root.0, Version::V1, None, "abc", 42, &limits).unwrap();
+
+        assert_eq!(root.read("cpuset/cfs/cpuset.cpus"), "0-3");
+        assert_eq!(root.read("cpuset/cfs/cpuset.mems"), "0");
+        assert_eq!(root.read("cpuset/cfs/abc/cpuset.cpus"), "1");
+        assert_eq!(root.read("cpuset/cfs/abc/cpuset.mems"), "0");
+        assert_eq!(root.read("cpuset/cfs/abc/cgroup.procs"), "42");
+    }
+
+    #[test]
+    fn usage_reads_the_v2_files() {
+        let root = Root::new("usage-v2").with(&[
+            (
+                "cpu.stat",
+                "usage_usec 1500\nuser_usec 1000\nsystem_usec 500\n",
+            ),
+            ("memory.current", "4096\n"),
+            ("memory.max", "max\n"),
+            ("pids.current", "3\n"),
+            ("pids.max", "1024\n"),
+        ]);
+        assert_eq!(
+            usage(std::slice::from_ref(&root.0)),
+            Usage {
+                cpu_usec: Some(1500),
+                memory: Some(4096),
+                memory_max: None,
+                pids: Some(3),
+                pids_max: Some(1024),
+            }
+        );
+    }
+
+    #[test]
+    fn usage_finds_the_v1_files_across_controllers() {
+        let root = Root::new("usage-v1").with(&[
+            ("cpuacct/cpuacct.usage", "2000000\n"),
+            ("memory/memory.usage_in_bytes", "8192\n"),
+            ("memory/memory.limit_in_bytes", "9223372036854771712\n"),
+            ("pids/pids.current", "1\n"),
+            ("pids/pids.max", "max\n"),
+        ]);
+        let paths: Vec<_> = ["pids", "memory", "cpuacct"]
+            .iter()
+            .map(|c| root.0.join(c))
+            .collect();
+        assert_eq!(
+            usage(&paths),
+            Usage {
+                cpu_usec: Some(2000),
+                memory: Some(8192),
+                memory_max: None,
+                pids: Some(1),
+                pids_max: None,
+            }
+        );
+    }
+
+    #[test]
+    fn freeze_writes_the_v1_freezer_state() {
+        let root = Root::new("freeze").with(&[("freezer.state", "THAWED")]);
+        freeze(std::slice::from_ref(&root.0), true).unwrap();
+        assert_eq!(root.read("freezer.state"), "FROZEN");
+        freeze(std::slice::from_ref(&root.0), false).unwrap();
+        assert_eq!(root.read("freezer.state"), "THAWED");
+    }
+
+    #[test]
+    fn freeze_needs_a_freezer() {
+        let root = Root::new("no-freezer");
+        assert!(freeze(std::slice::from_ref(&root.0), true).is_err());
+    }
+}
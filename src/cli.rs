@@ -0,0 +1,857 @@
+use cfs::{CfsError, Result};
+
+/// A flag a subcommand accepts. Flags with a `value` placeholder take an
+/// argument, as `--flag value`, `--flag=value`, `-f value` or `-fvalue`.
+pub struct Flag {
+    pub short: Option<char>,
+    pub long: &'static str,
+    pub value: Option<&'static str>,
+    pub help: &'static str,
+}
+
+pub struct Subcommand {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    /// What follows the flags in the usage line.
+    pub operands: &'static str,
+    pub about: &'static str,
+    pub flags: &'static [Flag],
+}
+
+const fn flag(short: Option<char>, long: &'static str, help: &'static str) -> Flag {
+    Flag {
+        short,
+        long,
+        value: None,
+        help,
+    }
+}
+
+const fn option(
+    short: Option<char>,
+    long: &'static str,
+    value: &'static str,
+    help: &'static str,
+) -> Flag {
+    Flag {
+        short,
+        long,
+        value: Some(value),
+        help,
+    }
+}
+
+pub const RUN: Subcommand = Subcommand {
+    name: "run",
+    aliases: &[],
+    operands: "<command> [args...]",
+    about: "Runs a command in a new container.",
+    flags: &[
+        option(
+            None,
+            "name",
+            "name",
+            "Register the container under this name, else a random one",
+        ),
+        option(
+            None,
+            "hostname",
+            "name",
+            "Hostname inside the container, the name by default",
+        ),
+        option(
+            None,
+            "domainname",
+            "name",
+            "NIS domain name inside the container",
+        ),
+        option(
+            None,
+            "config",
+            "file",
+            "Read options from a TOML file, ./cfs.toml by default",
+        ),
+        option(None, "rootfs", "path", "Root filesystem to run in"),
+        option(
+            None,
+            "distro",
+            "ubuntu[:release]|alpine[:version]",
+            "What to bootstrap a missing rootfs with",
+        ),
+        flag(
+            None,
+            "auto-install-deps",
+            "Install debootstrap with apt-get if it is missing",
+        ),
+        option(
+            None,
+            "cache-dir",
+            "dir",
+            "Keep debootstrap's downloads here for the next bootstrap",
+        ),
+        option(
+            None,
+            "proxy",
+            "url",
+            "HTTP proxy to bootstrap through, $http_proxy by default",
+        ),
+        flag(
+            None,
+            "insecure",
+            "Don't check downloads against their checksums",
+        ),
+        option(None, "image", "ref", "Pull the rootfs from a registry"),
+        flag(
+            None,
+            "no-pivot",
+            "Switch roots with chroot rather than pivot_root",
+        ),
+        flag(
+            None,
+            "no-overlay",
+            "Write to the rootfs itself rather than an overlay",
+        ),
+        flag(
+            None,
+            "readonly",
+            "Mount the root read-only, with a tmpfs on /tmp",
+        ),
+        flag(
+            None,
+            "rm",
+            "Delete the overlay, or a cfs managed rootfs, on exit",
+        ),
+        flag(Some('d'), "detach", "Run in the background, print the name"),
+        flag(
+            None,
+            "dry-run",
+            "Print what would be done, clone flags, cgroup writes, mounts and exec, and stop",
+        ),
+        option(
+            None,
+            "format",
+            "text|json",
+            "json reports the name, id, PID and exit code as JSON",
+        ),
+        option(
+            None,
+            "restart",
+            "no|always|on-failure[:max]",
+            "Start a detached container again when it exits",
+        ),
+        option(
+            None,
+            "health-cmd",
+            "command",
+            "Shell command that checks the container works",
+        ),
+        option(
+            None,
+            "health-interval",
+            "duration",
+            "Time between health checks, 30s by default",
+        ),
+        option(
+            None,
+            "health-retries",
+            "n",
+            "Failed checks in a row that make it unhealthy, 3 by default",
+        ),
+        flag(Some('i'), "interactive", "Pass stdin on to the command"),
+        flag(Some('t'), "tty", "Give the command a terminal"),
+        flag(None, "init", "Reap orphaned processes in the container"),
+        option(
+            None,
+            "timeout",
+            "duration",
+            "Kill the container after e.g. 30s, 5m or 1h",
+        ),
+        option(
+            None,
+            "stack-size",
+            "size",
+            "Stack size of the container's PID 1, 1M by default, up to ulimit -s",
+        ),
+        flag(
+            None,
+            "userns",
+            "Run rootless, in a user namespace, without cgroup limits",
+        ),
+        option(
+            None,
+            "pid",
+            "private|host",
+            "Own PID namespace or the host's, private by default",
+        ),
+        option(
+            None,
+            "ipc",
+            "private|host",
+            "Own System V IPC or the host's, private by default",
+        ),
+        option(
+            None,
+            "cgroupns",
+            "private|host",
+            "Own cgroup namespace or the host's, private where supported",
+        ),
+        option(
+            None,
+            "time-offset",
+            "monotonic|boottime=secs",
+            "Shift a clock in the container, repeatable",
+        ),
+        option(
+            None,
+            "net",
+            "mode",
+            "Network mode: none, bridge, host or container:<name>",
+        ),
+        option(None, "subnet", "cidr", "Subnet of the bridge network"),
+        option(None, "ip", "address", "Fixed address on the bridge"),
+        option(None, "mac", "address", "Fixed MAC address of eth0"),
+        option(
+            None,
+            "network-mtu",
+            "bytes",
+            "MTU of eth0 on the bridge, 1500 by default",
+        ),
+        option(
+            Some('p'),
+            "publish",
+            "host:container[/udp]",
+            "Forward a host port to the container, repeatable",
+        ),
+        option(
+            None,
+            "dns",
+            "ip",
+            "Name server for the container, repeatable",
+        ),
+        flag(
+            None,
+            "mount-sysfs",
+            "Mount /sys read-only, the default unless on the host network",
+        ),
+        flag(None, "no-sysfs", "Leave /sys as the rootfs has it"),
+        option(
+            None,
+            "proc",
+            "path|none",
+            "Where to mount proc, /proc by default, none for nowhere",
+        ),
+        option(None, "memory", "size", "Memory limit, e.g. 512M"),
+        option(
+            None,
+            "oom-score-adj",
+            "n",
+            "Make the OOM killer pick it sooner or later, -1000 to 1000",
+        ),
+        flag(
+            None,
+            "oom-kill-disable",
+            "Wait for memory at the limit rather than OOM kill, cgroup v1 only",
+        ),
+        option(None, "cpus", "n", "CPU quota, e.g. 1.5"),
+        option(None, "cpuset-cpus", "list", "Pin to these CPUs, e.g. 0-3,5"),
+        option(
+            None,
+            "pids-max",
+            "n|unlimited",
+            "Process limit, 1024 by default, 0 for none",
+        ),
+        option(
+            None,
+            "cgroup-version",
+            "auto|v1|v2",
+            "cgroup hierarchy to use",
+        ),
+        option(
+            None,
+            "cgroup-parent",
+            "path",
+            "Existing cgroup to create the container's below, e.g. system.slice",
+        ),
+        option(
+            Some('v'),
+            "volume",
+            "host:container[:ro]",
+            "Bind mount a host path, repeatable",
+        ),
+        option(None, "tmpfs", "path[:size]", "Mount a tmpfs, repeatable"),
+        option(None, "shm-size", "size", "Size of /dev/shm, 64M by default"),
+        option(
+            None,
+            "mount",
+            "type=bind|tmpfs,src=..,dst=..",
+            "Mount with ro, propagation or size options, repeatable",
+        ),
+        option(
+            None,
+            "device",
+            "host[:container][:rwm]",
+            "Expose a host device node, repeatable",
+        ),
+        option(
+            None,
+            "read-only-path",
+            "path",
+            "Make a path read-only, repeatable",
+        ),
+        option(
+            None,
+            "masked-path",
+            "path",
+            "Hide a file or directory, repeatable",
+        ),
+        option(
+            Some('e'),
+            "env",
+            "KEY=VALUE",
+            "Set a variable for the command, repeatable",
+        ),
+        option(
+            None,
+            "keep-env",
+            "KEY",
+            "Pass a host variable through, repeatable",
+        ),
+        option(
+            None,
+            "env-file",
+            "path",
+            "Read variables from a file, repeatable",
+        ),
+        option(
+            None,
+            "entrypoint",
+            "program",
+            "Run this with the command as arguments, over the image's",
+        ),
+        option(
+            Some('w'),
+            "workdir",
+            "dir",
+            "Directory the command starts in",
+        ),
+        option(
+            Some('u'),
+            "user",
+            "uid[:gid]|name[:group]",
+            "Who the command runs as inside",
+        ),
+        option(None, "cap-add", "cap", "Grant a capability, repeatable"),
+        option(None, "cap-drop", "cap", "Drop a capability, repeatable"),
+        option(
+            None,
+            "ulimit",
+            "name=soft[:hard]",
+            "Resource limit, e.g. nofile=1024:2048, repeatable",
+        ),
+        option(
+            None,
+            "sysctl",
+            "key=value",
+            "Namespaced kernel parameter, e.g. net.core.somaxconn=1024, repeatable",
+        ),
+        option(
+            None,
+            "label",
+            "key=value",
+            "Attach a label to the container, repeatable",
+        ),
+        option(
+            None,
+            "seccomp",
+            "profile.json|unconfined",
+            "Seccomp profile for the command",
+        ),
+    ],
+};
+
+pub const LIST: Subcommand = Subcommand {
+    name: "list",
+    aliases: &["ls"],
+    operands: "",
+    about: "Lists the running containers.",
+    flags: &[
+        option(
+            None,
+            "format",
+            "table|json",
+            "Output format, a table by default",
+        ),
+        option(
+            None,
+            "filter",
+            "label=key[=value]",
+            "Only those with the label, repeatable",
+        ),
+    ],
+};
+
+pub const INSPECT: Subcommand = Subcommand {
+    name: "inspect",
+    aliases: &[],
+    operands: "<name>",
+    about: "Prints everything known about a running container, as JSON.",
+    flags: &[],
+};
+
+pub const STOP: Subcommand = Subcommand {
+    name: "stop",
+    aliases: &[],
+    operands: "<name>",
+    about: "Stops a container, with SIGKILL once the grace period is over.",
+    flags: &[option(
+        Some('t'),
+        "time",
+        "seconds",
+        "Grace period after SIGTERM, 10 by default",
+    )],
+};
+
+pub const STATS: Subcommand = Subcommand {
+    name: "stats",
+    aliases: &[],
+    operands: "[<name>...]",
+    about: "Prints the CPU, memory and PIDs containers use, all running ones by default.",
+    flags: &[flag(
+        Some('s'),
+        "stream",
+        "Keep refreshing until interrupted",
+    )],
+};
+
+pub const LOGS: Subcommand = Subcommand {
+    name: "logs",
+    aliases: &[],
+    operands: "<name>",
+    about: "Prints the output of a detached container.",
+    flags: &[flag(
+        Some('f'),
+        "follow",
+        "Keep printing while the container runs",
+    )],
+};
+
+pub const EXEC: Subcommand = Subcommand {
+    name: "exec",
+    aliases: &[],
+    operands: "<name> <command> [args...]",
+    about: "Runs a command in a running container.",
+    flags: &[],
+};
+
+pub const EXPORT: Subcommand = Subcommand {
+    name: "export",
+    aliases: &[],
+    operands: "<name>",
+    about: "Writes the rootfs of a running container as a tarball.",
+    flags: &[option(
+        Some('o'),
+        "output",
+        "file",
+        "Write it here rather than to stdout",
+    )],
+};
+
+pub const IMPORT: Subcommand = Subcommand {
+    name: "import",
+    aliases: &[],
+    operands: "<tarball>",
+    about: "Unpacks a tarball into a new rootfs directory.",
+    flags: &[option(None, "as", "path", "Directory to unpack into")],
+};
+
+pub const CP: Subcommand = Subcommand {
+    name: "cp",
+    aliases: &[],
+    operands: "<src> <dest>",
+    about: "Copies files between the host and a container, as <name>:<path>.",
+    flags: &[],
+};
+
+pub const COMMIT: Subcommand = Subcommand {
+    name: "commit",
+    aliases: &[],
+    operands: "<name> <rootfs>",
+    about: "Saves a stopped container's filesystem as a new rootfs.",
+    flags: &[],
+};
+
+pub const BUILD: Subcommand = Subcommand {
+    name: "build",
+    aliases: &[],
+    operands: "",
+    about: "Builds a new rootfs by running commands on another in turn.",
+    flags: &[
+        option(
+            None,
+            "from",
+            "rootfs|distro[:version]",
+            "What to start from, a rootfs or a distro to bootstrap, e.g. ubuntu:jammy",
+        ),
+        option(None, "run", "command", "Shell command to run, repeatable"),
+        option(None, "as", "name", "Name of the new rootfs"),
+        flag(
+            None,
+            "no-overlay",
+            "Run the steps on a copy rather than commit each one",
+        ),
+    ],
+};
+
+pub const PAUSE: Subcommand = Subcommand {
+    name: "pause",
+    aliases: &[],
+    operands: "<name>",
+    about: "Suspends every process of a container.",
+    flags: &[],
+};
+
+pub const RESUME: Subcommand = Subcommand {
+    name: "resume",
+    aliases: &["unpause"],
+    operands: "<name>",
+    about: "Resumes a paused container.",
+    flags: &[],
+};
+
+pub const VERSION: Subcommand = Subcommand {
+    name: "version",
+    aliases: &[],
+    operands: "",
+    about: "Prints the version of cfs and what it was built from.",
+    flags: &[],
+};
+
+pub const COMPLETIONS: Subcommand = Subcommand {
+    name: "completions",
+    aliases: &[],
+    operands: "<bash|zsh|fish>",
+    about: "Prints a completion script for a shell.",
+    flags: &[],
+};
+
+pub const SUBCOMMANDS: &[&Subcommand] = &[
+    &RUN,
+    &LIST,
+    &INSPECT,
+    &STATS,
+    &STOP,
+    &LOGS,
+    &EXEC,
+    &CP,
+    &EXPORT,
+    &IMPORT,
+    &COMMIT,
+    &BUILD,
+    &PAUSE,
+    &RESUME,
+    &VERSION,
+    &COMPLETIONS,
+];
+
+/// What goes before the command, handled by `main` rather than a parser.
+pub const GLOBAL_FLAGS: &[Flag] = &[
+    flag(
+        Some('v'),
+        "verbose",
+        "Log every step, -vv in even more detail",
+    ),
+    flag(Some('q'), "quiet", "Only print warnings and errors"),
+    flag(None, "version", "Same as cfs version"),
+];
+
+pub fn find(name: &str) -> Option<&'static Subcommand> {
+    SUBCOMMANDS
+        .iter()
+        .copied()
+        .find(|command| command.name == name || command.aliases.contains(&name))
+}
+
+/// The overview `cfs --help` prints.
+pub fn help() -> String {
+    let mut text = String::from("Usage: cfs [-v|-q] <command> [options]\n\nCommands:\n");
+    for command in SUBCOMMANDS {
+        text += &format!("  {:<13}{}\n", command.name, command.about);
+    }
+    text += "\nOptions, before the command:\n";
+    for flag in GLOBAL_FLAGS {
+        let spec = match flag.short {
+            Some(short) => format!("-{}, --{}", short, flag.long),
+            None => format!("    --{}", flag.long),
+        };
+        text += &format!("  {:<13}  {}\n", spec, flag.help);
+    }
+    text + "\nSee cfs <command> --help for the options of each.\n"
+}
+
+impl Subcommand {
+    pub fn help(&self) -> String {
+        let mut text = format!("Usage: cfs {}", self.name);
+        if !self.flags.is_empty() {
+            text += " [options]";
+        }
+        if !self.operands.is_empty() {
+            text += &format!(" {}", self.operands);
+        }
+        text += &format!("\n\n{}\n\nOptions:\n", self.about);
+
+        let mut rows = Vec::new();
+        for flag in self.flags {
+            let mut spec = match flag.short {
+                Some(short) => format!("-{}, --{}", short, flag.long),
+                None => format!("    --{}", flag.long),
+            };
+            if let Some(value) = flag.value {
+                spec += &format!(" <{}>", value);
+            }
+            rows.push((spec, flag.help));
+        }
+        rows.push(("-h, --help".into(), "Print this help"));
+        let width = rows.iter().map(|(spec, _)| spec.len()).max().unwrap_or(0);
+        for (spec, help) in rows {
+            text += &format!("  {:<width$}  {}\n", spec, help);
+        }
+        text
+    }
+}
+
+/// A flag as it was given, with its value if it takes one.
+pub struct Given {
+    pub flag: &'static Flag,
+    value: Option<String>,
+}
+
+impl Given {
+    pub fn name(&self) -> &'static str {
+        self.flag.long
+    }
+
+    /// The value of a flag declared with one.
+    pub fn value(&self) -> &str {
+        self.value.as_deref().unwrap_or_default()
+    }
+}
+
+/// Walks the arguments of a subcommand, flag by flag. Flags end at `--` or
+/// the first operand, so the container command keeps its own.
+pub struct Parser<'a> {
+    command: &'static Subcommand,
+    args: &'a [String],
+    next: usize,
+    /// The rest of a `-abc` cluster still to hand out.
+    shorts: String,
+    /// Past a `--`, where nothing is a flag.
+    done: bool,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(command: &'static Subcommand, args: &'a [String]) -> Parser<'a> {
+        Parser {
+            command,
+            args,
+            next: 0,
+            shorts: String::new(),
+            done: false,
+        }
+    }
+
+    /// The next flag, `None` once the flags are over. `--help` prints the
+    /// help of the subcommand and exits.
+    pub fn next(&mut self) -> Result<Option<Given>> {
+        if !self.shorts.is_empty() {
+            let short = self.shorts.remove(0);
+            return self.short(short).map(Some);
+        }
+        let Some(arg) = self.args.get(self.next).filter(|_| !self.done) else {
+            return Ok(None);
+        };
+        if arg == "--" {
+            self.next += 1;
+            self.done = true;
+            return Ok(None);
+        }
+        if arg == "-h" || arg == "--help" {
+            print!("{}", self.command.help());
+            std::process::exit(0);
+        }
+
+        if let Some(long) = arg.strip_prefix("--") {
+            self.next += 1;
+            let (name, inline) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (long, None),
+            };
+            let flag = self
+                .command
+                .flags
+                .iter()
+                .find(|flag| flag.long == name)
+                .ok_or_else(|| self.unknown(arg))?;
+            let value = match (flag.value, inline) {
+                (Some(_), Some(value)) => Some(value),
+                (Some(_), None) => Some(self.take_value(arg)?),
+                (None, Some(_)) => {
+                    return Err(CfsError::Usage(format!("--{} takes no value", name)));
+                }
+                (None, None) => None,
+            };
+            return Ok(Some(Given { flag, value }));
+        }
+
+        match arg.strip_prefix('-') {
+            Some(cluster) if !cluster.is_empty() => {
+                self.next += 1;
+                self.shorts = cluster.to_string();
+                let short = self.shorts.remove(0);
+                self.short(short).map(Some)
+            }
+            // an operand, or a lone `-`
+            _ => Ok(None),
+        }
+    }
+
+    /// The operands after the flags.
+    pub fn rest(&self) -> &'a [String] {
+        &self.args[self.next..]
+    }
+
+    /// For subcommands with no command line to pass on, whose flags may
+    /// come after the operands as well: hands each flag to `handle` and
+    /// returns the operands.
+    pub fn interspersed(
+        &mut self,
+        mut handle: impl FnMut(Given) -> Result<()>,
+    ) -> Result<Vec<&'a str>> {
+        let mut operands = Vec::new();
+        loop {
+            while let Some(flag) = self.next()? {
+                handle(flag)?;
+            }
+            match self.args.get(self.next) {
+                Some(operand) => {
+                    operands.push(operand.as_str());
+                    self.next += 1;
+                }
+                None => return Ok(operands),
+            }
+        }
+    }
+
+    fn short(&mut self, short: char) -> Result<Given> {
+        let flag = self
+            .command
+            .flags
+            .iter()
+            .find(|flag| flag.short == Some(short))
+            .ok_or_else(|| self.unknown(&format!("-{}", short)))?;
+        let value = match flag.value {
+            // the rest of the cluster is the value, or else the next argument
+            Some(_) if !self.shorts.is_empty() => Some(std::mem::take(&mut self.shorts)),
+            Some(_) => Some(self.take_value(&format!("-{}", short))?),
+            None => None,
+        };
+        Ok(Given { flag, value })
+    }
+
+    fn take_value(&mut self, flag: &str) -> Result<String> {
+        let value = self
+            .args
+            .get(self.next)
+            .cloned()
+            .ok_or_else(|| CfsError::Usage(format!("{} needs a value", flag)))?;
+        self.next += 1;
+        Ok(value)
+    }
+
+    fn unknown(&self, flag: &str) -> CfsError {
+        CfsError::Usage(format!(
+            "Unknown flag {}, see cfs {} --help",
+            flag, self.command.name
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST: Subcommand = Subcommand {
+        name: "test",
+        aliases: &[],
+        operands: "<command> [args...]",
+        about: "A subcommand to parse with.",
+        flags: &[
+            flag(Some('d'), "detach", "A flag"),
+            flag(Some('i'), "interactive", "Another"),
+            option(Some('u'), "user", "uid", "An option"),
+            option(None, "name", "name", "A long one"),
+        ],
+    };
+
+    /// The flags, as `name` or `name=value`, and the operands after them.
+    fn parse(args: &[&str]) -> Result<(Vec<String>, Vec<String>)> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let mut parser = Parser::new(&TEST, &args);
+        let mut flags = Vec::new();
+        while let Some(given) = parser.next()? {
+            flags.push(match given.flag.value {
+                Some(_) => format!("{}={}", given.name(), given.value()),
+                None => given.name().to_string(),
+            });
+        }
+        Ok((flags, parser.rest().to_vec()))
+    }
+
+    #[test]
+    fn flags_end_at_the_first_operand_or_a_double_dash() {
+        for (args, flags, rest) in [
+            (&["-d", "sh"][..], &["detach"][..], &["sh"][..]),
+            (&["-di", "sh"], &["detach", "interactive"], &["sh"]),
+            (
+                &["-diu", "0", "sh"],
+                &["detach", "interactive", "user=0"],
+                &["sh"],
+            ),
+            (&["-du0", "sh"], &["detach", "user=0"], &["sh"]),
+            (&["-ud", "sh"], &["user=d"], &["sh"]),
+            (&["--name=web", "sh"], &["name=web"], &["sh"]),
+            (&["--name", "web", "sh"], &["name=web"], &["sh"]),
+            (&["--name=", "sh"], &["name="], &["sh"]),
+            (&["--name", "--detach"], &["name=--detach"], &[]),
+            (&["--user=a=b"], &["user=a=b"], &[]),
+            (&["-d", "--", "-i", "sh"], &["detach"], &["-i", "sh"]),
+            (&["--", "--", "sh"], &[], &["--", "sh"]),
+            (&["sh", "-d"], &[], &["sh", "-d"]),
+            (&["-", "-d"], &[], &["-", "-d"]),
+            (&[], &[], &[]),
+        ] {
+            let (given, operands) = parse(args).unwrap();
+            assert_eq!(given, flags, "{:?}", args);
+            assert_eq!(operands, rest, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn bad_flags_are_usage_errors() {
+        for (args, error) in [
+            (&["--name"][..], "--name needs a value"),
+            (&["-u"], "-u needs a value"),
+            (&["-du"], "-u needs a value"),
+            (&["--detach=yes"], "--detach takes no value"),
+            (
+                &["--bogus", "sh"],
+                "Unknown flag --bogus, see cfs test --help",
+            ),
+            (&["-dx"], "Unknown flag -x, see cfs test --help"),
+        ] {
+            match parse(args) {
+                Err(CfsError::Usage(message)) => assert_eq!(message, error, "{:?}", args),
+                _ => panic!("{:?} should not parse", args),
+            }
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Completion scripts, generated from the same tables as `--help` so they
+//! keep up with the flags.
+
+use crate::cli::{Flag, GLOBAL_FLAGS, SUBCOMMANDS, Subcommand};
+
+pub fn script(shell: &str) -> Option<String> {
+    match shell {
+        "bash" => Some(bash()),
+        "zsh" => Some(zsh()),
+        "fish" => Some(fish()),
+        _ => None,
+    }
+}
+
+// every spelling of a flag, `-v --volume`
+fn spellings(flag: &Flag) -> Vec<String> {
+    let mut words: Vec<String> = flag.short.iter().map(|s| format!("-{}", s)).collect();
+    words.push(format!("--{}", flag.long));
+    words
+}
+
+fn words(flags: &[Flag]) -> String {
+    flags
+        .iter()
+        .flat_map(spellings)
+        .chain(["-h".into(), "--help".into()])
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn names(command: &Subcommand) -> Vec<&'static str> {
+    std::iter::once(command.name)
+        .chain(command.aliases.iter().copied())
+        .collect()
+}
+
+/// Completes the command, then its flags; anything else, flag values and
+/// the container command alike, falls back to file names.
+fn bash() -> String {
+    let commands: Vec<&str> = SUBCOMMANDS.iter().flat_map(|c| names(c)).collect();
+    let mut cases = String::new();
+    for command in SUBCOMMANDS {
+        cases += &format!(
+            "        {}) flags=\"{}\" ;;\n",
+            names(command).join("|"),
+            words(command.flags)
+        );
+    }
+    format!(
+        r#"# bash completion for cfs: source <(cfs completions bash)
+_cfs() {{
+    local cur=${{COMP_WORDS[COMP_CWORD]}} command= flags i
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        if [[ ${{COMP_WORDS[i]}} != -* ]]; then
+            command=${{COMP_WORDS[i]}}
+            break
+        fi
+    done
+    if [[ -z $command ]]; then
+        COMPREPLY=($(compgen -W "{commands} {globals}" -- "$cur"))
+        return
+    fi
+    [[ $cur == -* ]] || return
+    case $command in
+{cases}    esac
+    COMPREPLY=($(compgen -W "$flags" -- "$cur"))
+}}
+complete -o default -F _cfs cfs
+"#,
+        commands = commands.join(" "),
+        globals = words(GLOBAL_FLAGS),
+        cases = cases,
+    )
+}
+
+// brackets and colons mean something in an _arguments spec
+fn zsh_escape(text: &str) -> String {
+    text.replace('\'', "'\\''")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+        .replace(':', "\\:")
+}
+
+fn zsh_specs(flags: &[Flag]) -> String {
+    let mut specs = String::new();
+    for flag in flags {
+        for spelling in spellings(flag) {
+            let value = flag.value.map_or(String::new(), |value| {
+                format!(":{}:_files", zsh_escape(value))
+            });
+            specs += &format!(
+                " \\\n        '*{}[{}]{}'",
+                spelling,
+                zsh_escape(flag.help),
+                value
+            );
+        }
+    }
+    specs
+}
+
+fn zsh() -> String {
+    let mut commands = String::new();
+    let mut cases = String::new();
+    for command in SUBCOMMANDS {
+        commands += &format!("\n        '{}:{}'", command.name, zsh_escape(command.about));
+        cases += &format!(
+            "        {})\n            _arguments{} \\\n        '*:: :_files' ;;\n",
+            names(command).join("|"),
+            zsh_specs(command.flags)
+        );
+    }
+    format!(
+        r#"#compdef cfs
+# zsh completion for cfs: cfs completions zsh > "${{fpath[1]}}/_cfs"
+_cfs() {{
+    local curcontext=$curcontext state line
+    local -a commands=({commands}
+    )
+    _arguments -C{globals} \
+        '1: :->command' \
+        '*:: :->args'
+    case $state in
+    command) _describe -t commands command commands ;;
+    args)
+        case $words[1] in
+{cases}        esac ;;
+    esac
+}}
+_cfs "$@"
+"#,
+        commands = commands,
+        globals = zsh_specs(GLOBAL_FLAGS),
+        cases = cases,
+    )
+}
+
+fn fish_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+fn fish_flag(condition: &str, flag: &Flag) -> String {
+    let short = flag.short.map_or(String::new(), |s| format!(" -s {}", s));
+    let value = if flag.value.is_some() { " -r" } else { "" };
+    format!(
+        "complete -c cfs -n '{}'{} -l {}{} -d '{}'\n",
+        condition,
+        short,
+        flag.long,
+        value,
+        fish_escape(flag.help)
+    )
+}
+
+fn fish() -> String {
+    let mut script = String::from(
+        "# fish completion for cfs: cfs completions fish > ~/.config/fish/completions/cfs.fish\n",
+    );
+    for flag in GLOBAL_FLAGS {
+        script += &fish_flag("__fish_use_subcommand", flag);
+    }
+    for command in SUBCOMMANDS {
+        script += &format!(
+            "complete -c cfs -f -n __fish_use_subcommand -a {} -d '{}'\n",
+            command.name,
+            fish_escape(command.about)
+        );
+    }
+    for command in SUBCOMMANDS {
+        let condition = format!("__fish_seen_subcommand_from {}", names(command).join(" "));
+        for flag in command.flags {
+            script += &fish_flag(&condition, flag);
+        }
+    }
+    script
+}
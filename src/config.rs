@@ -0,0 +1,494 @@
+//! What `cfs run` is asked for, from a TOML file and then the command line.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Deserializer};
+
+use cfs::caps::Capabilities;
+use cfs::error::{CfsError, Context, Result};
+use cfs::format::Format;
+use cfs::health::HealthCheck;
+use cfs::image::Reference;
+use cfs::mount::{Device, MountSpec, Tmpfs, Volume};
+use cfs::net::{MacAddr, NetMode, PortMapping, StaticIp, Subnet};
+use cfs::ns::TimeOffset;
+use cfs::rlimit::Ulimit;
+use cfs::rootfs::Distro;
+use cfs::seccomp::Filter;
+use cfs::sysctl::Sysctl;
+use cfs::{Container, RestartPolicy, cgroup, environ, state, units};
+
+use crate::app::expand_home;
+
+/// Read without `--config` when it is in the current directory.
+pub const DEFAULT_FILE: &str = "cfs.toml";
+
+/// The options of `cfs run`. In a file every long option goes by its own
+/// name, repeatable ones as arrays, flags as booleans, and `command` lists
+/// the command to run:
+///
+/// ```toml
+/// rootfs = "~/rootfs/web"
+/// hostname = "web01"
+/// memory = "256M"
+/// pids-max = 100
+/// env = ["PORT=8080"]
+/// volume = ["./src:/app:ro"]
+/// command = ["/app/serve"]
+/// ```
+///
+/// Relative paths are taken from the current directory, as on the command
+/// line, whose options win over the file's and add to its lists.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    name: Option<String>,
+    hostname: Option<String>,
+    domainname: Option<String>,
+    rootfs: Option<String>,
+    image: Option<String>,
+    distro: Option<String>,
+    auto_install_deps: bool,
+    cache_dir: Option<String>,
+    proxy: Option<String>,
+    insecure: bool,
+    no_pivot: bool,
+    no_overlay: bool,
+    readonly: bool,
+    rm: bool,
+    dry_run: bool,
+    detach: bool,
+    format: Option<String>,
+    restart: Option<String>,
+    health_cmd: Option<String>,
+    health_interval: Option<String>,
+    #[serde(deserialize_with = "scalar")]
+    health_retries: Option<String>,
+    interactive: bool,
+    tty: bool,
+    init: bool,
+    timeout: Option<String>,
+    #[serde(deserialize_with = "scalar")]
+    stack_size: Option<String>,
+    userns: bool,
+    pid: Option<String>,
+    ipc: Option<String>,
+    cgroupns: Option<String>,
+    time_offset: Vec<String>,
+    net: Option<String>,
+    subnet: Option<String>,
+    ip: Option<String>,
+    mac: Option<String>,
+    #[serde(deserialize_with = "scalar")]
+    network_mtu: Option<String>,
+    publish: Vec<String>,
+    dns: Vec<String>,
+    proc: Option<String>,
+    mount_sysfs: bool,
+    no_sysfs: bool,
+    #[serde(deserialize_with = "scalar")]
+    memory: Option<String>,
+    #[serde(deserialize_with = "scalar")]
+    oom_score_adj: Option<String>,
+    oom_kill_disable: bool,
+    #[serde(deserialize_with = "scalar")]
+    cpus: Option<String>,
+    cpuset_cpus: Option<String>,
+    #[serde(deserialize_with = "scalar")]
+    pids_max: Option<String>,
+    cgroup_version: Option<String>,
+    cgroup_parent: Option<String>,
+    volume: Vec<String>,
+    tmpfs: Vec<String>,
+    #[serde(deserialize_with = "scalar")]
+    shm_size: Option<String>,
+    mount: Vec<String>,
+    device: Vec<String>,
+    read_only_path: Vec<String>,
+    masked_path: Vec<String>,
+    env: Vec<String>,
+    keep_env: Vec<String>,
+    env_file: Vec<String>,
+    workdir: Option<String>,
+    user: Option<String>,
+    cap_add: Vec<String>,
+    cap_drop: Vec<String>,
+    ulimit: Vec<String>,
+    sysctl: Vec<String>,
+    label: Vec<String>,
+    seccomp: Option<String>,
+    entrypoint: Option<String>,
+    command: Vec<String>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config> {
+        let text =
+            fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?;
+        toml::from_str(&text).map_err(|e| {
+            let line = e
+                .span()
+                .map_or(1, |span| text[..span.start].matches('\n').count() + 1);
+            CfsError::Usage(format!(
+                "Invalid {} at line {}: {}",
+                path.display(),
+                line,
+                e.message()
+            ))
+        })
+    }
+
+    /// Takes `--<name> <value>` from the command line over the file.
+    pub fn set(&mut self, name: &str, value: &str) {
+        let value = value.to_string();
+        match name {
+            "name" => self.name = Some(value),
+            "hostname" => self.hostname = Some(value),
+            "domainname" => self.domainname = Some(value),
+            "rootfs" => self.rootfs = Some(value),
+            "image" => self.image = Some(value),
+            "distro" => self.distro = Some(value),
+            "auto-install-deps" => self.auto_install_deps = true,
+            "cache-dir" => self.cache_dir = Some(value),
+            "proxy" => self.proxy = Some(value),
+            "insecure" => self.insecure = true,
+            "no-pivot" => self.no_pivot = true,
+            "no-overlay" => self.no_overlay = true,
+            "readonly" => self.readonly = true,
+            "rm" => self.rm = true,
+            "dry-run" => self.dry_run = true,
+            "detach" => self.detach = true,
+            "format" => self.format = Some(value),
+            "restart" => self.restart = Some(value),
+            "health-cmd" => self.health_cmd = Some(value),
+            "health-interval" => self.health_interval = Some(value),
+            "health-retries" => self.health_retries = Some(value),
+            "interactive" => self.interactive = true,
+            "tty" => self.tty = true,
+            "init" => self.init = true,
+            "timeout" => self.timeout = Some(value),
+            "stack-size" => self.stack_size = Some(value),
+            "userns" => self.userns = true,
+            "pid" => self.pid = Some(value),
+            "ipc" => self.ipc = Some(value),
+            "cgroupns" => self.cgroupns = Some(value),
+            "time-offset" => self.time_offset.push(value),
+            "net" => self.net = Some(value),
+            "subnet" => self.subnet = Some(value),
+            "ip" => self.ip = Some(value),
+            "mac" => self.mac = Some(value),
+            "network-mtu" => self.network_mtu = Some(value),
+            "publish" => self.publish.push(value),
+            "dns" => self.dns.push(value),
+            "proc" => self.proc = Some(value),
+            // the later of the two wins
+            "mount-sysfs" => (self.mount_sysfs, self.no_sysfs) = (true, false),
+            "no-sysfs" => (self.mount_sysfs, self.no_sysfs) = (false, true),
+            "memory" => self.memory = Some(value),
+            "oom-score-adj" => self.oom_score_adj = Some(value),
+            "oom-kill-disable" => self.oom_kill_disable = true,
+            "cpus" => self.cpus = Some(value),
+            "cpuset-cpus" => self.cpuset_cpus = Some(value),
+            "pids-max" => self.pids_max = Some(value),
+            "cgroup-version" => self.cgroup_version = Some(value),
+            "cgroup-parent" => self.cgroup_parent = Some(value),
+            "volume" => self.volume.push(value),
+            "tmpfs" => self.tmpfs.push(value),
+            "shm-size" => self.shm_size = Some(value),
+            "mount" => self.mount.push(value),
+            "device" => self.device.push(value),
+            "read-only-path" => self.read_only_path.push(value),
+            "masked-path" => self.masked_path.push(value),
+            "env" => self.env.push(value),
+            "keep-env" => self.keep_env.push(value),
+            "env-file" => self.env_file.push(value),
+            "workdir" => self.workdir = Some(value),
+            "user" => self.user = Some(value),
+            "cap-add" => self.cap_add.push(value),
+            "cap-drop" => self.cap_drop.push(value),
+            "ulimit" => self.ulimit.push(value),
+            "sysctl" => self.sysctl.push(value),
+            "label" => self.label.push(value),
+            "entrypoint" => self.entrypoint = Some(value),
+            "seccomp" => self.seccomp = Some(value),
+            _ => unreachable!("--{} is declared but not handled", name),
+        }
+    }
+
+    /// Replaces the file's command, if any.
+    pub fn command(&mut self, args: &[String]) {
+        if !args.is_empty() {
+            self.command = args.to_vec();
+        }
+    }
+
+    pub fn container(self) -> Result<Container> {
+        let mut container = Container::new()
+            .pivot_root(!self.no_pivot)
+            .overlay(!self.no_overlay)
+            .readonly(self.readonly)
+            .remove_on_exit(self.rm)
+            .dry_run(self.dry_run)
+            .detach(self.detach)
+            .interactive(self.interactive)
+            .tty(self.tty)
+            .init(self.init)
+            .auto_install_deps(self.auto_install_deps)
+            .insecure(self.insecure)
+            .userns(self.userns)
+            .mount_sysfs(match (self.mount_sysfs, self.no_sysfs) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            });
+        if let Some(format) = &self.format {
+            container = container.format(Format::parse(format)?);
+        }
+        if let Some(name) = &self.name {
+            container = container.name(name);
+        }
+        if let Some(hostname) = &self.hostname {
+            container = container.hostname(hostname);
+        }
+        if let Some(domainname) = &self.domainname {
+            container = container.domainname(domainname);
+        }
+        if let Some(rootfs) = &self.rootfs {
+            container = container.rootfs(expand_home(rootfs)?);
+        }
+        if let Some(dir) = &self.cache_dir {
+            container = container.cache_dir(expand_home(dir)?);
+        }
+        if let Some(proxy) = &self.proxy {
+            container = container.proxy(proxy);
+        }
+        if let Some(image) = &self.image {
+            container = container.image(Reference::parse(image)?);
+        }
+        if let Some(distro) = &self.distro {
+            container = container.distro(Distro::parse(distro)?);
+        }
+        if let Some(policy) = &self.restart {
+            container = container.restart(RestartPolicy::parse(policy)?);
+        }
+        match &self.health_cmd {
+            Some(command) => {
+                let mut check = HealthCheck::new(command);
+                if let Some(interval) = &self.health_interval {
+                    check.interval = units::parse_duration(interval)?;
+                }
+                if let Some(retries) = &self.health_retries {
+                    check.retries = retries.parse().map_err(|_| {
+                        CfsError::Usage(format!(
+                            "Invalid health check retries {}, expected a number",
+                            retries
+                        ))
+                    })?;
+                }
+                container = container.health_check(check);
+            }
+            None if self.health_interval.is_some() || self.health_retries.is_some() => {
+                return Err(CfsError::Usage(
+                    "--health-interval and --health-retries take --health-cmd".into(),
+                ));
+            }
+            None => {}
+        }
+        if let Some(timeout) = &self.timeout {
+            container = container.timeout(units::parse_duration(timeout)?);
+        }
+        if let Some(size) = &self.stack_size {
+            container = container.stack_size(units::parse_size(size)? as usize);
+        }
+        if let Some(mode) = &self.pid {
+            container = container.private_pid(match mode.as_str() {
+                "private" => true,
+                "host" => false,
+                _ => {
+                    return Err(CfsError::Usage(format!(
+                        "Unknown PID mode {}, expected private or host",
+                        mode
+                    )));
+                }
+            });
+        }
+        if let Some(proc) = &self.proc {
+            container = container.proc(match proc.as_str() {
+                "none" => None,
+                path => Some(PathBuf::from(path)),
+            });
+        }
+        if let Some(mode) = &self.ipc {
+            container = container.private_ipc(match mode.as_str() {
+                "private" => true,
+                "host" => false,
+                _ => {
+                    return Err(CfsError::Usage(format!(
+                        "Unknown IPC mode {}, expected private or host",
+                        mode
+                    )));
+                }
+            });
+        }
+        if let Some(mode) = &self.cgroupns {
+            container = container.private_cgroupns(match mode.as_str() {
+                "private" => Some(true),
+                "host" => Some(false),
+                _ => {
+                    return Err(CfsError::Usage(format!(
+                        "Unknown cgroup namespace mode {}, expected private or host",
+                        mode
+                    )));
+                }
+            });
+        }
+        for offset in &self.time_offset {
+            container = container.time_offset(TimeOffset::parse(offset)?);
+        }
+        if let Some(net) = &self.net {
+            container = container.net(NetMode::parse(net)?);
+        }
+        if let Some(subnet) = &self.subnet {
+            container = container.subnet(Subnet::parse(subnet)?);
+        }
+        if let Some(ip) = &self.ip {
+            container = container.ip(StaticIp::parse(ip)?);
+        }
+        if let Some(mac) = &self.mac {
+            container = container.mac(MacAddr::parse(mac)?);
+        }
+        if let Some(mtu) = &self.network_mtu {
+            container = container.network_mtu(mtu.parse().map_err(|_| {
+                CfsError::Usage(format!("Invalid --network-mtu {}, expected bytes", mtu))
+            })?);
+        }
+        for port in &self.publish {
+            container = container.publish(PortMapping::parse(port)?);
+        }
+        for dns in &self.dns {
+            container = container.dns(dns.parse().map_err(|_| {
+                CfsError::Usage(format!(
+                    "Invalid DNS server {}, expected an IPv4 address",
+                    dns
+                ))
+            })?);
+        }
+        if let Some(memory) = &self.memory {
+            container = container.memory_limit(units::parse_size(memory)?);
+        }
+        if let Some(score) = &self.oom_score_adj {
+            container = container.oom_score_adj(score.parse().map_err(|_| {
+                CfsError::Usage(format!(
+                    "Invalid --oom-score-adj {}, expected -1000 to 1000",
+                    score
+                ))
+            })?);
+        }
+        if self.oom_kill_disable {
+            container = container.oom_kill_disable(true);
+        }
+        if let Some(cpus) = &self.cpus {
+            container = container.cpus(cgroup::parse_cpus(cpus)?);
+        }
+        if let Some(cpuset) = &self.cpuset_cpus {
+            container = container.cpuset_cpus(cgroup::parse_cpuset(cpuset)?);
+        }
+        if let Some(max) = &self.pids_max {
+            container = container.pids_max(cgroup::parse_pids_max(max)?);
+        }
+        if let Some(version) = &self.cgroup_version {
+            container = container.cgroup_version(cgroup::Version::parse(version)?);
+        }
+        if let Some(parent) = &self.cgroup_parent {
+            container = container.cgroup_parent(cgroup::parse_parent(parent)?);
+        }
+        for volume in &self.volume {
+            container = container.volume(Volume::parse(volume)?);
+        }
+        for tmpfs in &self.tmpfs {
+            container = container.tmpfs(Tmpfs::parse(tmpfs)?);
+        }
+        if let Some(size) = &self.shm_size {
+            container = container.shm_size(units::parse_size(size)?);
+        }
+        for spec in &self.mount {
+            container = container.mount(MountSpec::parse(spec)?);
+        }
+        for device in &self.device {
+            container = container.device(Device::parse(device)?);
+        }
+        for key in &self.keep_env {
+            container = container.keep_env(key);
+        }
+        for path in &self.read_only_path {
+            container = container.readonly_path(path);
+        }
+        for path in &self.masked_path {
+            container = container.masked_path(path);
+        }
+        // explicit --env wins over the files
+        for path in &self.env_file {
+            for (key, value) in environ::read_env_file(Path::new(path))? {
+                container = container.env(key, value);
+            }
+        }
+        for var in &self.env {
+            let (key, value) = environ::parse_var(var)?;
+            container = container.env(key, value);
+        }
+        if let Some(dir) = &self.workdir {
+            container = container.workdir(dir);
+        }
+        if let Some(user) = &self.user {
+            container = container.user(user);
+        }
+        // drops first, so --cap-drop ALL with --cap-add keeps the additions
+        let mut caps = Capabilities::default();
+        for cap in &self.cap_drop {
+            caps.drop(cap)?;
+        }
+        for cap in &self.cap_add {
+            caps.add(cap)?;
+        }
+        for ulimit in &self.ulimit {
+            container = container.ulimit(Ulimit::parse(ulimit)?);
+        }
+        for sysctl in &self.sysctl {
+            container = container.sysctl(Sysctl::parse(sysctl)?);
+        }
+        for label in &self.label {
+            let (key, value) = state::parse_label(label)?;
+            container = container.label(key, value);
+        }
+        if let Some(profile) = &self.seccomp {
+            container = container.seccomp(match profile.as_str() {
+                "unconfined" => None,
+                path => Some(Filter::load(Path::new(path))?),
+            });
+        }
+        // Docker's --entrypoint "" only drops the image's
+        if let Some(entrypoint) = &self.entrypoint {
+            container = container.entrypoint(Some(entrypoint).filter(|e| !e.is_empty()));
+        }
+        Ok(container.capabilities(caps).command(self.command))
+    }
+}
+
+/// A value TOML may as well write as a number, `pids-max = 100`.
+fn scalar<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Scalar {
+        Text(String),
+        Integer(i64),
+        Float(f64),
+    }
+    Ok(Some(match Scalar::deserialize(deserializer)? {
+        Scalar::Text(text) => text,
+        Scalar::Integer(n) => n.to_string(),
+        Scalar::Float(x) => x.to_string(),
+    }))
+}
@@ -0,0 +1,2586 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::Duration;
+use std::{env, fs, thread};
+
+use log::{debug, info, trace, warn};
+
+use crate::archive;
+use crate::caps::{self, Capabilities};
+use crate::cgroup::{self, Cgroup, Limits};
+use crate::copy;
+use crate::environ;
+use crate::error::{CfsError, Context, Result, check};
+use crate::etc::{EtcFiles, ResolvConf};
+use crate::format::{self, Format, Inspection, RunReport};
+use crate::health::{Health, HealthCheck, Monitor};
+use crate::image::{self, Reference};
+use crate::mount::{self, Device, MountGuard, MountSpec, Overlay, Propagation, Tmpfs, Volume};
+use crate::names;
+use crate::net::{self, Forwarding, MacAddr, NetMode, PortMapping, StaticIp, Subnet, Veth};
+use crate::ns::{self, TimeOffset};
+use crate::plan::Plan;
+use crate::program::Program;
+use crate::rlimit::Ulimit;
+use crate::rootfs::{self, Distro};
+use crate::seccomp::Filter;
+use crate::signals;
+use crate::stack::{self, Stack};
+use crate::state::{self, State};
+use crate::sysctl::{self, Sysctl};
+use crate::tty;
+use crate::units;
+use crate::user::User;
+
+const HOST_NAME_MAX: usize = 64;
+// RFC 1123 takes that from DNS
+const LABEL_MAX: usize = 63;
+
+const DEFAULT_ROOTFS: &str = "/home/ubuntu-fs";
+
+// what a container that ran out of --timeout exits with, as with timeout(1)
+const TIMEOUT_EXIT_CODE: i32 = 124;
+// how long it gets to shut down after SIGTERM before SIGKILL
+const TIMEOUT_GRACE: Duration = Duration::from_secs(5);
+
+// the first restart waits this long, each one after twice as long
+const RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// When a detached container is started again after its command exits.
+/// Never after `cfs stop`.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum RestartPolicy {
+    #[default]
+    No,
+    Always,
+    /// On a nonzero exit, at most that many times if given.
+    OnFailure(Option<u32>),
+}
+
+impl RestartPolicy {
+    pub fn parse(s: &str) -> Result<RestartPolicy> {
+        let invalid = || {
+            CfsError::Usage(format!(
+                "Unknown restart policy {}, expected no, always or on-failure[:max]",
+                s
+            ))
+        };
+        match s.split_once(':') {
+            None if s == "no" => Ok(RestartPolicy::No),
+            None if s == "always" => Ok(RestartPolicy::Always),
+            None if s == "on-failure" => Ok(RestartPolicy::OnFailure(None)),
+            Some(("on-failure", max)) => Ok(RestartPolicy::OnFailure(Some(
+                max.parse().map_err(|_| invalid())?,
+            ))),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// How long to wait before restarting after the `restarts`'th run ended
+    /// with `code`, if at all.
+    fn delay(&self, code: i32, restarts: u32) -> Option<Duration> {
+        let restart = match *self {
+            RestartPolicy::No => false,
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure(max) => code != 0 && max.is_none_or(|max| restarts < max),
+        };
+        restart.then(|| {
+            RESTART_BACKOFF
+                .saturating_mul(1 << restarts.min(16))
+                .min(RESTART_BACKOFF_MAX)
+        })
+    }
+}
+
+/// A container to run, configured builder style:
+///
+/// ```no_run
+/// let status = cfs::Container::new()
+///     .rootfs("/home/ubuntu-fs")
+///     .hostname("web01")
+///     .memory_limit(256 * 1024 * 1024)
+///     .command(["/bin/sh", "-c", "echo hello"])
+///     .run()?;
+/// # Ok::<(), cfs::CfsError>(())
+/// ```
+///
+/// Without a rootfs it runs in `/home/ubuntu-fs`, debootstrapped when
+/// missing, or in the image's own directory when there is one.
+pub struct Container {
+    name: Option<String>,
+    hostname: Option<String>,
+    domainname: Option<String>,
+    rootfs: Option<PathBuf>,
+    distro: Distro,
+    auto_install_deps: bool,
+    cache_dir: Option<PathBuf>,
+    proxy: Option<String>,
+    insecure: bool,
+    image: Option<Reference>,
+    pivot: bool,
+    readonly: bool,
+    overlay: bool,
+    rm: bool,
+    dry_run: bool,
+    format: Format,
+    detach: bool,
+    restart: RestartPolicy,
+    health: Option<HealthCheck>,
+    tty: bool,
+    interactive: bool,
+    init: bool,
+    stack_size: usize,
+    userns: bool,
+    private_pid: bool,
+    private_ipc: bool,
+    cgroupns: Option<bool>,
+    time_offsets: Vec<TimeOffset>,
+    net: NetMode,
+    subnet: Subnet,
+    ports: Vec<PortMapping>,
+    ip: Option<StaticIp>,
+    mac: Option<MacAddr>,
+    mtu: Option<u32>,
+    dns: Vec<Ipv4Addr>,
+    proc: Option<PathBuf>,
+    sysfs: Option<bool>,
+    limits: Limits,
+    oom_score_adj: Option<i32>,
+    cgroup_version: Option<cgroup::Version>,
+    cgroup_parent: Option<PathBuf>,
+    volumes: Vec<Volume>,
+    tmpfs: Vec<Tmpfs>,
+    shm_size: u64,
+    devices: Vec<Device>,
+    readonly_paths: Vec<PathBuf>,
+    masked_paths: Vec<PathBuf>,
+    env: Vec<(String, String)>,
+    keep_env: Vec<String>,
+    workdir: Option<PathBuf>,
+    caps: Capabilities,
+    seccomp: Option<Filter>,
+    ulimits: Vec<Ulimit>,
+    sysctls: Vec<Sysctl>,
+    labels: HashMap<String, String>,
+    user: Option<String>,
+    timeout: Option<Duration>,
+    entrypoint: Option<Vec<String>>,
+    command: Vec<String>,
+}
+
+impl Default for Container {
+    fn default() -> Container {
+        Container {
+            name: None,
+            hostname: None,
+            domainname: None,
+            rootfs: None,
+            distro: Distro::default(),
+            auto_install_deps: false,
+            cache_dir: None,
+            proxy: None,
+            insecure: false,
+            image: None,
+            pivot: true,
+            readonly: false,
+            overlay: true,
+            rm: false,
+            dry_run: false,
+            format: Format::Text,
+            detach: false,
+            restart: RestartPolicy::No,
+            health: None,
+            tty: false,
+            interactive: false,
+            init: false,
+            stack_size: stack::DEFAULT_STACK_SIZE,
+            userns: false,
+            private_pid: true,
+            private_ipc: true,
+            cgroupns: None,
+            time_offsets: Vec::new(),
+            net: NetMode::None,
+            subnet: Subnet::parse(net::DEFAULT_SUBNET).expect("the default subnet parses"),
+            ports: Vec::new(),
+            ip: None,
+            mac: None,
+            mtu: None,
+            dns: Vec::new(),
+            proc: Some(PathBuf::from("/proc")),
+            sysfs: None,
+            limits: Limits::default(),
+            oom_score_adj: None,
+            cgroup_version: None,
+            cgroup_parent: None,
+            volumes: Vec::new(),
+            tmpfs: Vec::new(),
+            shm_size: mount::DEFAULT_SHM_SIZE,
+            devices: Vec::new(),
+            readonly_paths: Vec::new(),
+            masked_paths: Vec::new(),
+            env: Vec::new(),
+            keep_env: Vec::new(),
+            workdir: None,
+            caps: Capabilities::default(),
+            seccomp: Some(Filter::default_profile()),
+            ulimits: Vec::new(),
+            sysctls: Vec::new(),
+            labels: HashMap::new(),
+            user: None,
+            timeout: None,
+            entrypoint: None,
+            command: Vec::new(),
+        }
+    }
+}
+
+// What `run` works out before the container first starts, for every start.
+struct Resolved {
+    name: String,
+    id: String,
+    hostname: String,
+    rootfs: PathBuf,
+    command: Vec<String>,
+    env: Vec<(String, String)>,
+    workdir: Option<PathBuf>,
+    user: Option<User>,
+    cgroupns: bool,
+    netns: Option<fs::File>,
+    resolv: ResolvConf,
+    address: Option<Ipv4Addr>,
+}
+
+// What the cloned child needs: the container, what `run` resolved for it,
+// the pipe it blocks on until the parent has finished its part of the
+// setup, its veth and overlay.
+struct ChildArgs<'a> {
+    container: &'a Container,
+    hostname: String,
+    rootfs: PathBuf,
+    command: Vec<String>,
+    env: Vec<(String, String)>,
+    workdir: Option<PathBuf>,
+    user: Option<User>,
+    cgroupns: bool,
+    sync_pipe: [libc::c_int; 2],
+    veth: Option<Veth>,
+    /// The network namespace of another container, to join.
+    netns: Option<&'a fs::File>,
+    overlay: Option<Overlay>,
+    etc: EtcFiles,
+    /// The child's end of the socket the PTY master goes back over.
+    tty_socket: Option<libc::c_int>,
+}
+
+impl Container {
+    pub fn new() -> Container {
+        Container::default()
+    }
+
+    /// Registers the container under `name` rather than its id.
+    pub fn name(mut self, name: impl Into<String>) -> Container {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Defaults to the container's name, with `-` for `_`, or else its id
+    /// if that doesn't make a valid host name.
+    pub fn hostname(mut self, hostname: impl Into<String>) -> Container {
+        self.hostname = Some(hostname.into());
+        self
+    }
+
+    /// The NIS domain name, left unset by default.
+    pub fn domainname(mut self, domainname: impl Into<String>) -> Container {
+        self.domainname = Some(domainname.into());
+        self
+    }
+
+    pub fn rootfs(mut self, path: impl Into<PathBuf>) -> Container {
+        self.rootfs = Some(path.into());
+        self
+    }
+
+    /// What to debootstrap a missing rootfs with.
+    pub fn distro(mut self, distro: Distro) -> Container {
+        self.distro = distro;
+        self
+    }
+
+    /// Lets a missing debootstrap be installed with apt-get.
+    pub fn auto_install_deps(mut self, enable: bool) -> Container {
+        self.auto_install_deps = enable;
+        self
+    }
+
+    /// Where debootstrap keeps the packages it downloads, so bootstrapping
+    /// again, or resuming after a failure, doesn't fetch them twice.
+    pub fn cache_dir(mut self, dir: impl Into<PathBuf>) -> Container {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    /// An HTTP proxy for bootstrapping, `http://host:port`, rather than the
+    /// one `http_proxy` names if any.
+    pub fn proxy(mut self, url: impl Into<String>) -> Container {
+        self.proxy = Some(url.into());
+        self
+    }
+
+    /// Takes image layers and rootfs tarballs as they come, without checking
+    /// them against their digests and checksums.
+    pub fn insecure(mut self, enable: bool) -> Container {
+        self.insecure = enable;
+        self
+    }
+
+    /// Pulls the rootfs from a registry, which also brings a default
+    /// command, environment and workdir.
+    pub fn image(mut self, reference: Reference) -> Container {
+        self.image = Some(reference);
+        self
+    }
+
+    /// Whether to switch roots with pivot_root, rather than chroot.
+    pub fn pivot_root(mut self, enable: bool) -> Container {
+        self.pivot = enable;
+        self
+    }
+
+    /// Mounts the root read-only, with a tmpfs on /tmp.
+    pub fn readonly(mut self, enable: bool) -> Container {
+        self.readonly = enable;
+        self
+    }
+
+    /// Whether writes go to an overlay rather than the rootfs itself.
+    pub fn overlay(mut self, enable: bool) -> Container {
+        self.overlay = enable;
+        self
+    }
+
+    /// Deletes what the container wrote once it exits: the overlay, or
+    /// without one the rootfs itself, if it is one cfs manages.
+    pub fn remove_on_exit(mut self, enable: bool) -> Container {
+        self.rm = enable;
+        self
+    }
+
+    /// Starts a detached container again when its command exits, as the
+    /// policy says.
+    pub fn restart(mut self, policy: RestartPolicy) -> Container {
+        self.restart = policy;
+        self
+    }
+
+    /// Checks every so often that the container still works, and with a
+    /// restart policy restarts it when it doesn't.
+    pub fn health_check(mut self, check: HealthCheck) -> Container {
+        self.health = Some(check);
+        self
+    }
+
+    /// Runs the container in the background with its output going to its
+    /// log. `run` prints the name and returns once it is registered.
+    pub fn detach(mut self, enable: bool) -> Container {
+        self.detach = enable;
+        self
+    }
+
+    /// Has `run` print every step that would change the system, from the
+    /// clone flags and the cgroup writes to the mounts and the exec, rather
+    /// than take it. Nothing is started, not even with `--detach`; what the
+    /// command would see isn't there, so paths only the mounted /proc has
+    /// don't show.
+    pub fn dry_run(mut self, enable: bool) -> Container {
+        self.dry_run = enable;
+        self
+    }
+
+    /// How `run` reports, by default only a detached container's name. As
+    /// JSON it prints a [`RunReport`], once detached or, after the
+    /// command's own output, once the container has exited.
+    pub fn format(mut self, format: Format) -> Container {
+        self.format = format;
+        self
+    }
+
+    /// Gives the command a PTY.
+    pub fn tty(mut self, enable: bool) -> Container {
+        self.tty = enable;
+        self
+    }
+
+    /// Connects our stdin to the command; without it, it reads /dev/null.
+    pub fn interactive(mut self, enable: bool) -> Container {
+        self.interactive = enable;
+        self
+    }
+
+    /// Has PID 1 reap orphans rather than only wait for the command.
+    pub fn init(mut self, enable: bool) -> Container {
+        self.init = enable;
+        self
+    }
+
+    /// The stack cfs's own code in the container runs on until the exec,
+    /// [`stack::DEFAULT_STACK_SIZE`] by default. Rounded up to whole pages,
+    /// and no more than RLIMIT_STACK allows.
+    pub fn stack_size(mut self, bytes: usize) -> Container {
+        self.stack_size = bytes;
+        self
+    }
+
+    /// Runs in a user namespace with our user as root, which needs no
+    /// privileges but does without cgroups.
+    pub fn userns(mut self, enable: bool) -> Container {
+        self.userns = enable;
+        self
+    }
+
+    /// Whether the container gets its own PID namespace, the default. On the
+    /// host's it sees and can signal every process of the host, and the
+    /// command isn't PID 1: nothing takes what it leaves running down with
+    /// the container, though it is killed when cfs's own process in the
+    /// container dies, and `--init` still reaps its orphans.
+    pub fn private_pid(mut self, enable: bool) -> Container {
+        self.private_pid = enable;
+        self
+    }
+
+    /// Whether the container gets its own System V IPC objects and POSIX
+    /// message queues, rather than the host's.
+    pub fn private_ipc(mut self, enable: bool) -> Container {
+        self.private_ipc = enable;
+        self
+    }
+
+    /// Whether the container gets a cgroup namespace rooted at its own
+    /// cgroup, mounted on /sys/fs/cgroup. `None`, the default, means
+    /// wherever the kernel supports it.
+    pub fn private_cgroupns(mut self, enable: Option<bool>) -> Container {
+        self.cgroupns = enable;
+        self
+    }
+
+    /// Shifts a clock for the command, which then runs in a time namespace
+    /// of its own.
+    pub fn time_offset(mut self, offset: TimeOffset) -> Container {
+        self.time_offsets.push(offset);
+        self
+    }
+
+    pub fn net(mut self, mode: NetMode) -> Container {
+        self.net = mode;
+        self
+    }
+
+    /// Where bridged containers get their addresses from.
+    pub fn subnet(mut self, subnet: Subnet) -> Container {
+        self.subnet = subnet;
+        self
+    }
+
+    /// A fixed address on the bridge rather than the first one free.
+    pub fn ip(mut self, ip: StaticIp) -> Container {
+        self.ip = Some(ip);
+        self
+    }
+
+    /// A fixed hardware address for the container's eth0.
+    pub fn mac(mut self, mac: MacAddr) -> Container {
+        self.mac = Some(mac);
+        self
+    }
+
+    /// The MTU of the container's eth0 and its end on the bridge, for a
+    /// network whose path can't take 1500 byte packets, a VPN say.
+    pub fn network_mtu(mut self, mtu: u32) -> Container {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// Forwards a host port to the container, which takes bridge networking.
+    pub fn publish(mut self, port: PortMapping) -> Container {
+        self.ports.push(port);
+        self
+    }
+
+    /// Adds a name server, which replaces the host's.
+    pub fn dns(mut self, server: Ipv4Addr) -> Container {
+        self.dns.push(server);
+        self
+    }
+
+    /// Where the container's proc goes, `/proc` by default, `None` for
+    /// nowhere. The parts of /proc that are masked or read-only by default
+    /// follow it.
+    pub fn proc(mut self, target: Option<PathBuf>) -> Container {
+        self.proc = target;
+        self
+    }
+
+    /// Whether the container gets sysfs, read-only, on /sys. `None`, the
+    /// default, means unless it is on the host's network, so /sys/class/net
+    /// lists its interfaces rather than the host's.
+    pub fn mount_sysfs(mut self, enable: Option<bool>) -> Container {
+        self.sysfs = enable;
+        self
+    }
+
+    pub fn memory_limit(mut self, bytes: u64) -> Container {
+        self.limits.memory = Some(bytes);
+        self
+    }
+
+    pub fn cpus(mut self, cpus: f64) -> Container {
+        self.limits.cpus = Some(cpus);
+        self
+    }
+
+    /// Pins the container to the CPUs in a list such as `0-3,5`, see
+    /// [`cgroup::parse_cpuset`].
+    pub fn cpuset_cpus(mut self, cpus: impl Into<String>) -> Container {
+        self.limits.cpuset = Some(cpus.into());
+        self
+    }
+
+    /// Adjusts how readily the kernel's OOM killer picks the container
+    /// under memory pressure, from -1000 for never to 1000 for first.
+    pub fn oom_score_adj(mut self, score: i32) -> Container {
+        self.oom_score_adj = Some(score);
+        self
+    }
+
+    /// Takes the container's processes out of the OOM killer's reach when
+    /// over the memory limit, see [`Limits::oom_kill_disable`].
+    pub fn oom_kill_disable(mut self, enable: bool) -> Container {
+        self.limits.oom_kill_disable = enable;
+        self
+    }
+
+    /// The most processes the container may have, `None` for no limit.
+    /// Defaults to [`cgroup::DEFAULT_PIDS_MAX`].
+    pub fn pids_max(mut self, max: Option<u64>) -> Container {
+        self.limits.pids = max;
+        self
+    }
+
+    /// The cgroup hierarchy to use, `None` to detect it.
+    pub fn cgroup_version(mut self, version: Option<cgroup::Version>) -> Container {
+        self.cgroup_version = version;
+        self
+    }
+
+    /// Creates the container's cgroup below an existing one, such as a
+    /// systemd slice, rather than below `cfs`. See [`cgroup::parse_parent`].
+    pub fn cgroup_parent(mut self, parent: impl Into<PathBuf>) -> Container {
+        self.cgroup_parent = Some(parent.into());
+        self
+    }
+
+    pub fn volume(mut self, volume: Volume) -> Container {
+        self.volumes.push(volume);
+        self
+    }
+
+    pub fn tmpfs(mut self, tmpfs: Tmpfs) -> Container {
+        self.tmpfs.push(tmpfs);
+        self
+    }
+
+    /// The size of the tmpfs on /dev/shm, [`mount::DEFAULT_SHM_SIZE`] by
+    /// default. Shared memory counts against it, so a program short of it
+    /// fails with "No space left on device".
+    pub fn shm_size(mut self, bytes: u64) -> Container {
+        self.shm_size = bytes;
+        self
+    }
+
+    /// A `--mount` spec, as a volume or a tmpfs.
+    pub fn mount(self, spec: MountSpec) -> Container {
+        match spec {
+            MountSpec::Bind(volume) => self.volume(volume),
+            MountSpec::Tmpfs(tmpfs) => self.tmpfs(tmpfs),
+        }
+    }
+
+    /// Makes a host device node available, and on cgroup v1 usable, in the
+    /// container, whose devices are otherwise limited to the basics.
+    pub fn device(mut self, device: Device) -> Container {
+        self.limits.devices.push(device.rule());
+        self.devices.push(device);
+        self
+    }
+
+    /// Makes a path in the container read-only, on top of the parts of
+    /// /proc that are by default.
+    pub fn readonly_path(mut self, path: impl Into<PathBuf>) -> Container {
+        self.readonly_paths.push(path.into());
+        self
+    }
+
+    /// Hides a file or directory in the container, on top of the parts of
+    /// /proc and /sys that are by default.
+    pub fn masked_path(mut self, path: impl Into<PathBuf>) -> Container {
+        self.masked_paths.push(path.into());
+        self
+    }
+
+    /// Sets a variable for the command, over the defaults and the image's.
+    /// Later settings of the same key win.
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Container {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    /// Passes a variable through from the host environment, when it is set
+    /// there. `env` settings of the same key still win.
+    pub fn keep_env(mut self, key: impl Into<String>) -> Container {
+        self.keep_env.push(key.into());
+        self
+    }
+
+    /// The absolute directory the command starts in.
+    pub fn workdir(mut self, dir: impl Into<PathBuf>) -> Container {
+        self.workdir = Some(dir.into());
+        self
+    }
+
+    pub fn capabilities(mut self, caps: Capabilities) -> Container {
+        self.caps = caps;
+        self
+    }
+
+    /// The seccomp filter for the command, `None` for none at all.
+    pub fn seccomp(mut self, filter: Option<Filter>) -> Container {
+        self.seccomp = filter;
+        self
+    }
+
+    /// Replaces a resource limit inherited from the host, for PID 1 and the
+    /// command alike.
+    pub fn ulimit(mut self, ulimit: Ulimit) -> Container {
+        self.ulimits.push(ulimit);
+        self
+    }
+
+    /// Sets a namespaced kernel parameter in the container.
+    pub fn sysctl(mut self, sysctl: Sysctl) -> Container {
+        self.sysctls.push(sysctl);
+        self
+    }
+
+    /// Attaches a label to the container, for `list --filter` to pick it
+    /// by. Later settings of the same key win.
+    pub fn label(mut self, key: impl Into<String>, value: impl Into<String>) -> Container {
+        self.labels.insert(key.into(), value.into());
+        self
+    }
+
+    /// Runs the command as `uid[:gid]` or `name[:group]` rather than root,
+    /// names as the rootfs's `/etc/passwd` and `/etc/group` have them.
+    pub fn user(mut self, spec: impl Into<String>) -> Container {
+        self.user = Some(spec.into());
+        self
+    }
+
+    /// Stops the container once it has run for `limit`: SIGTERM, then
+    /// SIGKILL a few seconds later. [`Container::run`] then returns exit
+    /// code 124, as `timeout(1)` does.
+    pub fn timeout(mut self, limit: Duration) -> Container {
+        self.timeout = Some(limit);
+        self
+    }
+
+    /// What runs with the command as its arguments, in place of the image's
+    /// entrypoint and default command. Empty, it only drops the image's.
+    pub fn entrypoint<I, S>(mut self, args: I) -> Container
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.entrypoint = Some(args.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// The command and its arguments. Can be left out with an image.
+    pub fn command<I, S>(mut self, args: I) -> Container
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.command = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Whether this container's steps are taken, or only shown.
+    fn plan(&self) -> Plan {
+        Plan::new(self.dry_run)
+    }
+
+    /// Runs the container and waits for it. The status is that of its PID 1,
+    /// which exits with the command's code, 128 + the signal number if the
+    /// command was killed by one; see [`exit_code`].
+    pub fn run(&self) -> Result<ExitStatus> {
+        self.validate()?;
+        // rather than half way through, with the first mount
+        if !self.userns && !self.dry_run && !caps::effective("SYS_ADMIN") {
+            return Err(CfsError::Usage(
+                "Running a container requires root or CAP_SYS_ADMIN; re-run with sudo or pass --userns".into(),
+            ));
+        }
+        let id = generate_id()?;
+        let name = match &self.name {
+            Some(name) => name.clone(),
+            None => names::generate()?,
+        };
+        State::ensure_available(&name)?;
+        let hostname = match &self.hostname {
+            Some(hostname) => hostname.clone(),
+            None => default_hostname(&name, &id),
+        };
+
+        let rootfs = match (&self.rootfs, &self.image) {
+            (Some(rootfs), _) => rootfs::resolve(rootfs),
+            (None, Some(image)) => image::default_rootfs(image),
+            (None, None) => PathBuf::from(DEFAULT_ROOTFS),
+        };
+        // chroot needs an absolute path
+        let rootfs = std::path::absolute(&rootfs)
+            .context(format!("Cannot resolve rootfs path {}", rootfs.display()))?;
+
+        // the command gets a clean environment: a few defaults, then what the
+        // image sets, then what is kept from the host, then what the user
+        // asked for, later entries winning
+        let mut env: Vec<(String, String)> = environ::DEFAULTS
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let mut command = self.command.clone();
+        let mut workdir = self.workdir.clone();
+
+        // bootstrapping needs the host network, which the child no longer has
+        if let Some(reference) = &self.image {
+            let image_config = image::pull(self.plan(), reference, &rootfs, !self.insecure)?;
+            command = image_config.command(self.entrypoint.as_deref(), &command);
+            env.extend(image_config.env());
+            if workdir.is_none() {
+                workdir = image_config
+                    .working_dir
+                    .filter(|dir| !dir.is_empty())
+                    .map(PathBuf::from);
+            }
+            // a dry run doesn't pull, so doesn't know it
+            if command.is_empty() && self.dry_run {
+                command = vec!["<the image's command>".into()];
+            }
+            if command.is_empty() {
+                return Err(CfsError::Usage(
+                    "The image has no default command, need a command to run".into(),
+                ));
+            }
+        } else {
+            if let Some(entrypoint) = &self.entrypoint {
+                command.splice(0..0, entrypoint.iter().cloned());
+            }
+            rootfs::bootstrap(
+                self.plan(),
+                &rootfs,
+                &self.distro,
+                self.auto_install_deps,
+                self.cache_dir.as_deref(),
+                self.proxy.as_deref(),
+                !self.insecure,
+            )?;
+        }
+        env.extend(environ::from_host(&self.keep_env));
+        env.extend(self.env.iter().cloned());
+        // from the rootfs itself, the overlay isn't mounted yet
+        let user = self
+            .user
+            .as_ref()
+            .map(|spec| User::resolve(spec, &rootfs))
+            .transpose()?;
+        if self.userns && user.is_some_and(|user| user.uid != 0 || user.gid != 0) {
+            return Err(CfsError::Usage(
+                "A rootless container only has root mapped, --user must be 0:0".into(),
+            ));
+        }
+
+        let cgroupns = match (self.cgroupns, ns::supported("cgroup")) {
+            (Some(true), false) => {
+                return Err(CfsError::Usage(
+                    "This kernel has no cgroup namespaces".into(),
+                ));
+            }
+            (Some(enable), _) => enable,
+            (None, supported) => supported,
+        };
+
+        if !self.time_offsets.is_empty() && !ns::supported("time") {
+            return Err(CfsError::Usage("This kernel has no time namespaces".into()));
+        }
+
+        // the other container's network comes with its name servers
+        let (netns, resolv) = match &self.net {
+            NetMode::Container(other) => {
+                let state = State::load(other)?;
+                if !state.is_running() {
+                    return Err(CfsError::Usage(format!("{} is not running", other)));
+                }
+                (
+                    Some(net::namespace_of(state.pid)?),
+                    ResolvConf::Shared(EtcFiles::new(&state.id)),
+                )
+            }
+            NetMode::Host => (None, ResolvConf::HostNetwork),
+            NetMode::None | NetMode::Bridge => (None, ResolvConf::Host),
+        };
+        let address = match self.net {
+            NetMode::Bridge => Some(self.bridge_address()?),
+            _ => None,
+        };
+
+        let detached = self.detach
+            && !self.plan().skip(format!(
+                "detach, with the output going to {}",
+                State::log_path(&name).display()
+            ));
+        let mut ready = if detached {
+            match detach(&name, self.format)? {
+                Some(ready) => Some(ready),
+                None => return Ok(ExitStatus::from_raw(0)),
+            }
+        } else {
+            None
+        };
+        let resolved = Resolved {
+            name,
+            id,
+            hostname,
+            rootfs,
+            command,
+            env,
+            workdir,
+            user,
+            cgroupns,
+            netns,
+            resolv,
+            address,
+        };
+
+        let mut restarts = 0;
+        let (pid, status) = loop {
+            let (pid, status, stopped) = self.start(&resolved, restarts, ready.take())?;
+            // a signal to us is one to stop as well
+            let code = exit_code(ExitStatus::from_raw(status));
+            let delay = if stopped || signals::forwarded() {
+                None
+            } else {
+                self.restart.delay(code, restarts)
+            };
+            let Some(delay) = delay else {
+                break (pid, status);
+            };
+            restarts += 1;
+            warn!(
+                "the container exited with {}, restarting it in {}s ({} so far)",
+                code,
+                delay.as_secs(),
+                restarts
+            );
+            thread::sleep(delay);
+        };
+        if self.rm {
+            if self.overlay {
+                Overlay::new(&resolved.id).remove(self.plan())?;
+            } else {
+                remove_rootfs(self.plan(), &resolved.rootfs)?;
+            }
+        }
+        // a detached one reported from the foreground already
+        if self.format == Format::Json && !self.detach {
+            format::print_json(&RunReport {
+                name: resolved.name.clone(),
+                id: resolved.id.clone(),
+                pid,
+                exit_code: Some(exit_code(ExitStatus::from_raw(status))),
+                restarts,
+            });
+        }
+        Ok(ExitStatus::from_raw(status))
+    }
+
+    /// Starts the container once and waits for it, cleaning up after it but
+    /// for its rootfs. Returns its PID and wait status, and whether `stop`
+    /// is what ended it, which isn't a reason to restart.
+    fn start(
+        &self,
+        resolved: &Resolved,
+        restarts: u32,
+        ready: Option<libc::c_int>,
+    ) -> Result<(libc::pid_t, i32, bool)> {
+        let id = &resolved.id;
+        info!(
+            "Running {:?} as PID {}",
+            resolved.command,
+            std::process::id()
+        );
+
+        // lives until after waitpid, the child runs on it
+        let stack = Stack::new(self.stack_size)?;
+
+        let tty_sockets = if self.tty && !self.dry_run {
+            Some(tty::socketpair()?)
+        } else {
+            None
+        };
+        let mut sync_pipe = [0; 2];
+        check(
+            unsafe { libc::pipe2(sync_pipe.as_mut_ptr(), libc::O_CLOEXEC) },
+            "pipe failed",
+        )?;
+        let child_args = ChildArgs {
+            container: self,
+            hostname: resolved.hostname.clone(),
+            rootfs: resolved.rootfs.clone(),
+            command: resolved.command.clone(),
+            env: resolved.env.clone(),
+            workdir: resolved.workdir.clone(),
+            user: resolved.user,
+            cgroupns: resolved.cgroupns,
+            sync_pipe,
+            veth: resolved.address.map(|address| {
+                Veth::new(
+                    &self.subnet,
+                    std::process::id(),
+                    address,
+                    self.mac,
+                    self.mtu,
+                )
+            }),
+            netns: resolved.netns.as_ref(),
+            overlay: self.overlay.then(|| Overlay::new(id)),
+            etc: EtcFiles::new(id).with_resolv_conf(&resolved.resolv, &self.dns),
+            tty_socket: tty_sockets.as_ref().map(|(_, child)| child.as_raw_fd()),
+        };
+        if let Some(overlay) = &child_args.overlay {
+            overlay.create(self.plan())?;
+        }
+        // a restart keeps the overlay, with what the container changed
+        let abort = |pid, cgroup| abort_start(&child_args, pid, cgroup, restarts == 0);
+        let written = child_args.etc.write(
+            self.plan(),
+            &child_args.hostname,
+            child_args.veth.as_ref().map(|veth| veth.address),
+            &self.dns,
+            &resolved.resolv,
+        );
+        if let Err(e) = written {
+            abort(None, None);
+            return Err(e);
+        }
+
+        if self.dry_run {
+            return self.start_dry(&child_args, id, &resolved.name);
+        }
+        let pid = match self.clone_child(&child_args, &stack) {
+            Ok(pid) => pid,
+            Err(e) => {
+                abort(None, None);
+                return Err(e);
+            }
+        };
+        if let Err(e) = signals::forward_to(pid) {
+            abort(Some(pid), None);
+            return Err(e);
+        }
+        unsafe { libc::close(sync_pipe[0]) };
+        let tty_socket = tty_sockets.map(|(parent, _)| parent);
+
+        if self.userns {
+            let uid = unsafe { libc::getuid() };
+            let gid = unsafe { libc::getgid() };
+            if let Err(e) = write_id_maps(pid, uid, gid) {
+                abort(Some(pid), None);
+                return Err(e);
+            }
+        }
+
+        if let Some(score) = self.oom_score_adj
+            && let Err(e) = set_oom_score_adj(self.plan(), pid, score)
+        {
+            abort(Some(pid), None);
+            return Err(e);
+        }
+
+        let cgroup = match self.create_cgroup(id, pid) {
+            Ok(cgroup) => cgroup,
+            Err(e) => {
+                abort(Some(pid), None);
+                return Err(e);
+            }
+        };
+
+        if let Some(veth) = &child_args.veth
+            && let Err(e) = net::create_veth(self.plan(), veth, pid)
+        {
+            abort(Some(pid), cgroup.as_ref());
+            return Err(e);
+        }
+        // validate() made sure published ports come with a veth
+        let forwarding = match &child_args.veth {
+            Some(veth) => Forwarding::add(self.plan(), &self.ports, veth.address),
+            None => Ok(Forwarding::default()),
+        };
+        let mut forwarding = match forwarding {
+            Ok(forwarding) => forwarding,
+            Err(e) => {
+                abort(Some(pid), cgroup.as_ref());
+                return Err(e);
+            }
+        };
+
+        let state = State {
+            name: resolved.name.clone(),
+            id: id.clone(),
+            pid,
+            command: child_args.command.clone(),
+            env: child_args.env.clone(),
+            caps: self.caps,
+            seccomp: self.seccomp.clone(),
+            ulimits: self.ulimits.clone(),
+            user: child_args.user,
+            rootfs: child_args.rootfs.clone(),
+            cgroups: cgroup.as_ref().map_or(Vec::new(), |c| c.paths().to_vec()),
+            address: child_args.veth.as_ref().map(|veth| veth.address),
+            restarts,
+            health: self.health.as_ref().map(|_| Health::Starting),
+            stopping: false,
+            labels: self.labels.clone(),
+            started: state::now(),
+        };
+        // for `commit`, after the state file is gone
+        let kept = match &child_args.overlay {
+            Some(overlay) if !self.rm => state.keep(overlay.dir()),
+            _ => Ok(()),
+        };
+        if let Err(e) = kept.and_then(|_| state.save()) {
+            forwarding.remove();
+            abort(Some(pid), cgroup.as_ref());
+            return Err(e);
+        }
+        if let Some(ready) = ready {
+            unsafe {
+                libc::write(ready, [0u8].as_ptr() as *const libc::c_void, 1);
+                libc::close(ready);
+            }
+        }
+
+        // setup done, let the child go on
+        unsafe {
+            libc::write(sync_pipe[1], [0u8].as_ptr() as *const libc::c_void, 1);
+            libc::close(sync_pipe[1]);
+        }
+
+        // the container's PID 1 opens the PTY on its own devpts and sends us
+        // the master. As with Docker, -t alone only gives the command a
+        // terminal to write to; typing into it takes -i, and our terminal
+        // goes raw for it.
+        let attached = tty_socket
+            .as_ref()
+            .map_or(Ok(None), |socket| self.attach(socket));
+        let pty = match attached {
+            Ok(pty) => pty,
+            // past the release, so the state file is there to go too
+            Err(e) => {
+                forwarding.remove();
+                abort(Some(pid), cgroup.as_ref());
+                if let Err(e) = state.remove() {
+                    warn!("{}", e);
+                }
+                return Err(e);
+            }
+        };
+
+        let monitor = self.health.clone().map(|check| {
+            Monitor::start(
+                check,
+                state.name.clone(),
+                pid,
+                self.restart != RestartPolicy::No,
+            )
+        });
+        let deadline = self.timeout.map(|limit| {
+            let cgroups = cgroup.as_ref().map_or(Vec::new(), |c| c.paths().to_vec());
+            Deadline::arm(pid, limit, cgroups)
+        });
+        // wait without reaping: the watcher and the health checks, which
+        // may kill the container, must be done with the PID before waitpid
+        // frees it for reuse
+        if deadline.is_some() || monitor.is_some() {
+            signals::wait_for_exit(pid);
+        }
+        let timed_out = deadline.is_some_and(Deadline::disarm);
+        if let Some(monitor) = monitor {
+            monitor.stop();
+        }
+        let mut status = signals::wait_for(pid).unwrap_or_else(|| {
+            warn!("lost track of the container's exit status");
+            1 << 8
+        });
+        if timed_out {
+            warn!(
+                "the container timed out after {}",
+                units::format_duration(self.timeout.unwrap_or_default().as_secs())
+            );
+            status = TIMEOUT_EXIT_CODE << 8;
+        }
+        if let Some((output, raw)) = pty {
+            let _ = output.join();
+            drop(raw);
+        }
+        forwarding.remove();
+        // gone already if `stop` got to it first
+        let stopped = State::load(&state.name).map_or(true, |now| now.id != *id || now.stopping);
+        state.remove()?;
+        child_args.etc.remove()?;
+
+        if let Some(cgroup) = cgroup {
+            if status != 0 && self.limits.memory.is_some() && cgroup.oom_killed() {
+                warn!("the container ran out of memory and was killed by the OOM killer");
+            }
+            cgroup.remove()?;
+        }
+        Ok((pid, status, stopped))
+    }
+
+    /// Proxies our terminal to the PTY whose master PID 1 sends over
+    /// `socket`, unless it went away without one.
+    fn attach(
+        &self,
+        socket: &OwnedFd,
+    ) -> Result<Option<(std::thread::JoinHandle<()>, Option<tty::RawMode>)>> {
+        let Some(master) = tty::recv_fd(socket)? else {
+            return Ok(None);
+        };
+        tty::copy_window_size(master.as_raw_fd());
+        signals::resize_on_winch(master.as_raw_fd())?;
+        let raw = self.interactive.then(tty::RawMode::enable);
+        Ok(Some((tty::proxy(master, self.interactive)?, raw)))
+    }
+
+    fn validate(&self) -> Result<()> {
+        if let Some(hostname) = &self.hostname {
+            validate_hostname(hostname)?;
+        }
+        if let Some(domainname) = &self.domainname
+            && (domainname.is_empty() || domainname.len() > HOST_NAME_MAX)
+        {
+            return Err(CfsError::Usage(format!(
+                "Invalid domain name {:?}: must be between 1 and {} bytes",
+                domainname, HOST_NAME_MAX
+            )));
+        }
+        if let Some(name) = &self.name {
+            state::validate_name(name)?;
+        }
+        stack::validate(self.stack_size)?;
+        if let Some(score) = self.oom_score_adj
+            && !(-1000..=1000).contains(&score)
+        {
+            return Err(CfsError::Usage(format!(
+                "Invalid --oom-score-adj {}, expected -1000 to 1000",
+                score
+            )));
+        }
+        // IPv4 takes 68 at the least, the kernel's ceiling for a veth
+        if let Some(mtu) = self.mtu
+            && !(68..=65535).contains(&mtu)
+        {
+            return Err(CfsError::Usage(format!(
+                "Invalid --network-mtu {}, expected 68 to 65535",
+                mtu
+            )));
+        }
+        // a tmpfs of size 0 has no limit at all
+        if self.shm_size == 0 {
+            return Err(CfsError::Usage("--shm-size must be more than 0".into()));
+        }
+        for sysctl in &self.sysctls {
+            let (private, namespace) = match sysctl.namespace() {
+                sysctl::Namespace::Ipc => (self.private_ipc, "an IPC"),
+                sysctl::Namespace::Net => (self.net.is_private(), "a network"),
+            };
+            if !private {
+                return Err(CfsError::Usage(format!(
+                    "--sysctl {} takes {} namespace of the container's own",
+                    sysctl, namespace
+                )));
+            }
+        }
+        if let Some(key) = self
+            .keep_env
+            .iter()
+            .find(|key| key.is_empty() || key.contains('='))
+        {
+            return Err(CfsError::Usage(format!(
+                "Invalid variable name {:?} to keep",
+                key
+            )));
+        }
+        if self.net != NetMode::Bridge {
+            for (given, what) in [
+                (!self.ports.is_empty(), "Publishing ports"),
+                (self.ip.is_some(), "--ip"),
+                (self.mac.is_some(), "--mac"),
+                (self.mtu.is_some(), "--network-mtu"),
+            ] {
+                if given {
+                    return Err(CfsError::Usage(format!("{} takes --net bridge", what)));
+                }
+            }
+        }
+        if let Some(ip) = self.ip
+            && (!self.subnet.is_host(ip.address)
+                || ip
+                    .prefix
+                    .is_some_and(|prefix| prefix != self.subnet.prefix()))
+        {
+            return Err(CfsError::Usage(format!(
+                "{} is not a container address in the bridge subnet {}",
+                ip.address, self.subnet
+            )));
+        }
+        // proc only mounts in a user namespace that owns its PID namespace
+        if self.userns && !self.private_pid {
+            return Err(CfsError::Usage(
+                "--pid host can't be combined with --userns".into(),
+            ));
+        }
+        // a new user namespace owns nothing of another container's network
+        if self.userns && matches!(self.net, NetMode::Container(_)) {
+            return Err(CfsError::Usage(
+                "--net container:<name> can't be combined with --userns".into(),
+            ));
+        }
+        // a rootless container has no cgroup, see create_cgroup
+        if self.userns {
+            let limits = &self.limits;
+            let cgroup_flags = [
+                ("--memory", limits.memory.is_some()),
+                ("--oom-kill-disable", limits.oom_kill_disable),
+                ("--cpus", limits.cpus.is_some()),
+                ("--cpuset-cpus", limits.cpuset.is_some()),
+                ("--pids-max", limits.pids != Limits::default().pids),
+                ("--cgroup-parent", self.cgroup_parent.is_some()),
+                ("--device", !self.devices.is_empty()),
+            ];
+            if let Some((flag, _)) = cgroup_flags.iter().find(|(_, given)| *given) {
+                return Err(CfsError::Usage(format!(
+                    "{} can't be combined with --userns, a rootless container has no cgroup",
+                    flag
+                )));
+            }
+        }
+        if self.proc.is_none() && !self.sysctls.is_empty() {
+            return Err(CfsError::Usage(
+                "--sysctl goes through /proc, which --proc none leaves out".into(),
+            ));
+        }
+        if let Some(path) = self
+            .readonly_paths
+            .iter()
+            .chain(&self.masked_paths)
+            .chain(&self.proc)
+            .find(|path| !path.is_absolute())
+        {
+            return Err(CfsError::Usage(format!(
+                "{} must be an absolute path in the container",
+                path.display()
+            )));
+        }
+        if let Some(check) = &self.health {
+            check.validate()?;
+        }
+        if self.restart != RestartPolicy::No {
+            if !self.detach {
+                return Err(CfsError::Usage("--restart takes --detach".into()));
+            }
+            if self.rm {
+                return Err(CfsError::Usage(
+                    "--restart and --rm don't go together".into(),
+                ));
+            }
+        }
+        if let Some(proxy) = &self.proxy
+            && !proxy.starts_with("http://")
+            && !proxy.starts_with("https://")
+        {
+            return Err(CfsError::Usage(format!(
+                "Invalid proxy {}, expected http://host:port",
+                proxy
+            )));
+        }
+        // images bring their own default command
+        let entrypoint = self.entrypoint.as_ref().is_some_and(|e| !e.is_empty());
+        if self.command.is_empty() && !entrypoint && self.image.is_none() {
+            return Err(CfsError::Usage("Need a command to run".into()));
+        }
+        if let Some(dir) = &self.workdir
+            && !dir.is_absolute()
+        {
+            return Err(CfsError::Usage(format!(
+                "Workdir {} must be an absolute path",
+                dir.display()
+            )));
+        }
+        Ok(())
+    }
+
+    /// The container's address on the bridge: the one asked for, which must
+    /// be free, or the first free one.
+    fn bridge_address(&self) -> Result<Ipv4Addr> {
+        let taken: Vec<(String, Ipv4Addr)> = State::all()?
+            .into_iter()
+            .filter(|state| state.is_running())
+            .filter_map(|state| Some((state.name, state.address?)))
+            .collect();
+        match self.ip {
+            Some(ip) => {
+                if let Some((name, _)) = taken.iter().find(|(_, taken)| *taken == ip.address) {
+                    return Err(CfsError::Usage(format!(
+                        "{} is already the address of {}",
+                        ip.address, name
+                    )));
+                }
+                Ok(ip.address)
+            }
+            None => {
+                let taken: Vec<Ipv4Addr> = taken.iter().map(|(_, address)| *address).collect();
+                self.subnet.host(std::process::id(), &taken)
+            }
+        }
+    }
+
+    /// Starts `child_func` in a new process running on `stack`, in fresh
+    /// namespaces.
+    ///
+    /// `args` crosses over as a raw pointer into our own memory. That is
+    /// sound only because, without CLONE_VM, the child gets a copy-on-write
+    /// snapshot of the whole address space taken at clone time: the pointer
+    /// stays valid in the child for as long as it runs, no matter what the
+    /// parent does with the original afterwards, and nothing the child does
+    /// is visible to the parent. The stack is the one thing the parent has
+    /// to keep alive until waitpid.
+    fn clone_child(&self, args: &ChildArgs, stack: &Stack) -> Result<libc::pid_t> {
+        let flags = self.clone_flags();
+        debug!(
+            "cloning into new {} namespaces",
+            ns::names(flags).join(", ")
+        );
+        let pid = unsafe {
+            libc::clone(
+                child_func,
+                stack.top(),
+                flags,
+                args as *const ChildArgs as *mut libc::c_void,
+            )
+        };
+        check(pid, "clone failed")?;
+        Ok(pid)
+    }
+
+    fn clone_flags(&self) -> libc::c_int {
+        let mut flags = libc::CLONE_NEWUTS | libc::SIGCHLD | libc::CLONE_NEWNS;
+        if self.private_pid {
+            flags |= libc::CLONE_NEWPID;
+        }
+        if self.net.is_private() {
+            flags |= libc::CLONE_NEWNET;
+        }
+        if self.userns {
+            flags |= libc::CLONE_NEWUSER;
+        }
+        if self.private_ipc {
+            flags |= libc::CLONE_NEWIPC;
+        }
+        flags
+    }
+
+    /// The rest of [`Container::start`] in a dry run, which has every step
+    /// print itself: the parent's part, then the child's, in this process.
+    /// The container's PID shows as 0.
+    fn start_dry(
+        &self,
+        child_args: &ChildArgs,
+        id: &str,
+        name: &str,
+    ) -> Result<(libc::pid_t, i32, bool)> {
+        let pid = 0;
+        self.plan().skip(format!(
+            "clone into new {} namespaces",
+            ns::names(self.clone_flags()).join(", ")
+        ));
+        if self.userns {
+            self.plan()
+                .skip("map root in the container to our user and group");
+        }
+        if let Some(score) = self.oom_score_adj {
+            set_oom_score_adj(self.plan(), pid, score)?;
+        }
+        self.create_cgroup(id, pid)?;
+        if let Some(veth) = &child_args.veth {
+            net::create_veth(self.plan(), veth, pid)?;
+            Forwarding::add(self.plan(), &self.ports, veth.address)?;
+        }
+        self.plan()
+            .skip(format!("register the container as {}", name));
+        child(child_args)?;
+        // as if stopped, so a restart policy doesn't go round
+        Ok((pid, 0, true))
+    }
+
+    /// Puts `pid` in a new cgroup with the limits. Rootless containers get
+    /// none, cgroups need delegation from the host to be writable without
+    /// root.
+    fn create_cgroup(&self, id: &str, pid: libc::pid_t) -> Result<Option<Cgroup>> {
+        if self.userns {
+            return Ok(None);
+        }
+        let version = self.cgroup_version.unwrap_or_else(cgroup::detect);
+        let how = if self.cgroup_version.is_some() {
+            "as asked"
+        } else {
+            "detected"
+        };
+        debug!("using cgroup {} ({})", version, how);
+        Cgroup::create(
+            self.plan(),
+            version,
+            self.cgroup_parent.as_deref(),
+            id,
+            pid,
+            &self.limits,
+        )
+        .map(Some)
+    }
+
+    /// Everything mounted below `root` before the switch: /dev and the
+    /// devices, the cgroup tree with a cgroup namespace, the /etc files,
+    /// volumes and tmpfs.
+    fn mount_filesystems(&self, root: &Path, etc: &EtcFiles, cgroupns: bool) -> Result<()> {
+        let plan = self.plan();
+        mount::setup_dev(plan, root, self.shm_size)?;
+        for device in &self.devices {
+            device.mount(plan, root)?;
+        }
+        // on the host's network, sysfs would only show the host's interfaces
+        if self.sysfs.unwrap_or(self.net != NetMode::Host) {
+            mount::mount_sysfs(plan, &root.join("sys"))?;
+        }
+        if cgroupns {
+            mount::mount_cgroup2(plan, &root.join("sys/fs/cgroup"))?;
+        }
+        etc.mount(plan, root)?;
+        for volume in &self.volumes {
+            volume.mount(plan, root)?;
+        }
+        for tmpfs in &self.tmpfs {
+            tmpfs.mount(plan, root)?;
+        }
+        // a read-only root still needs somewhere to scribble
+        let own_tmp = self.tmpfs.iter().any(|t| t.target == Path::new("/tmp"));
+        if self.readonly && !own_tmp {
+            mount::mount_tmpfs(plan, &root.join("tmp"), "mode=1777")?;
+        }
+        // while /proc/sys is still writable
+        if let Some(proc) = &self.proc {
+            let proc = mount::resolve_in_root(root, proc)?;
+            for sysctl in &self.sysctls {
+                debug!("setting sysctl {}", sysctl);
+                sysctl.apply(plan, &proc)?;
+            }
+        }
+        // last, so they cover volumes as well as /proc and /sys
+        let readonly = self.with_proc(mount::DEFAULT_READONLY_PATHS);
+        for path in readonly.chain(self.readonly_paths.iter().cloned()) {
+            mount::make_readonly(plan, root, &path)?;
+        }
+        let masked = self.with_proc(mount::DEFAULT_MASKED_PATHS);
+        for path in masked.chain(self.masked_paths.iter().cloned()) {
+            mount::mask(plan, root, &path)?;
+        }
+        Ok(())
+    }
+
+    /// Default paths, with those below /proc moved to wherever proc is, or
+    /// left out without one.
+    fn with_proc<'a>(&'a self, paths: &'a [&str]) -> impl Iterator<Item = PathBuf> + 'a {
+        paths
+            .iter()
+            .filter_map(|path| match path.strip_prefix("/proc/") {
+                Some(rest) => self.proc.as_ref().map(|proc| proc.join(rest)),
+                None => Some(PathBuf::from(path)),
+            })
+    }
+
+    /// Makes `root` the root directory, read-only if asked.
+    fn switch_root(&self, root: &Path) -> Result<()> {
+        let plan = self.plan();
+        debug!(
+            "switching root to {} with {}",
+            root.display(),
+            if self.pivot { "pivot_root" } else { "chroot" }
+        );
+        if self.pivot {
+            mount::pivot_root(plan, root)?;
+        } else {
+            // pivot_root makes the root a mount of its own, chroot doesn't,
+            // and only a mount can be made read-only
+            if self.readonly {
+                mount::mount(
+                    plan,
+                    Some(root),
+                    root,
+                    None,
+                    libc::MS_BIND | libc::MS_REC,
+                    None,
+                )?;
+            }
+            mount::chroot(plan, root)?;
+        }
+        if self.readonly {
+            mount::remount_readonly(plan, Path::new("/"))?;
+        }
+        Ok(())
+    }
+
+    fn run_command(
+        &self,
+        args: &[String],
+        env: &[(String, String)],
+        user: Option<User>,
+        tty: Option<OwnedFd>,
+    ) -> Result<i32> {
+        if self.plan().skip(format!(
+            "exec {:?} as {}, with {} variables set",
+            args,
+            user.map_or("root".to_string(), |user| format!(
+                "{}:{}",
+                user.uid, user.gid
+            )),
+            env.len()
+        )) {
+            return Ok(0);
+        }
+        trace!("environment {:?}", env);
+        // all of it allocated now, nothing is after the fork
+        let program = Program::new(args, env)?;
+        let stdin = match (&tty, self.interactive) {
+            (None, false) => Some(
+                fs::File::open("/dev/null")
+                    .map(OwnedFd::from)
+                    .context("Cannot open /dev/null")?,
+            ),
+            _ => None,
+        };
+
+        // only the command is confined, PID 1 still has to unmount. The
+        // filter goes first, installing it takes CAP_SYS_ADMIN.
+        let caps = self.caps;
+        let seccomp = self.seccomp.as_ref().map(Filter::program);
+        // on the host's PID namespace there is no PID 1 of ours for orphans
+        // to go to, nor to take the command down with it
+        let host_pid = !self.private_pid;
+        // nor anything for it to do: the command takes its place, signals
+        // reach it directly and the mounts go with the namespace. In a
+        // private one we stay as PID 1 even without --init: the kernel drops
+        // every signal PID 1 has no handler for, so a command exec'd there
+        // would shrug off SIGTERM and ^C, and `stop` would only get it with
+        // SIGKILL after the grace period
+        let in_place = host_pid && !self.init;
+        if host_pid && self.init {
+            check(
+                unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1) },
+                "Failed to become a subreaper",
+            )?;
+        }
+        // only syscalls, which is all a forked child of a possibly
+        // multi-threaded process may do before exec
+        let prepare = || -> std::result::Result<(), StartError> {
+            let failed = || StartError::Setup(std::io::Error::last_os_error());
+            let dup = |fd: &OwnedFd, to| unsafe { libc::dup2(fd.as_raw_fd(), to) };
+            if let Some(slave) = &tty {
+                if (0..3).any(|to| dup(slave, to) < 0) {
+                    return Err(failed());
+                }
+                // a session of its own, with the PTY as controlling terminal
+                if unsafe { libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 } {
+                    return Err(failed());
+                }
+            }
+            if let Some(null) = &stdin
+                && dup(null, 0) < 0
+            {
+                return Err(failed());
+            }
+            if !in_place
+                && host_pid
+                && unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) } < 0
+            {
+                return Err(failed());
+            }
+            if let Some(program) = &seccomp {
+                program.apply().map_err(StartError::Privileges)?;
+            }
+            caps.apply(user).map_err(StartError::Privileges)
+        };
+        let start = || match prepare() {
+            Ok(()) => StartError::Exec(program.exec()),
+            Err(e) => e,
+        };
+
+        if in_place {
+            debug!("exec'ing {} in place of PID 1", args[0]);
+            let e = start();
+            // the capabilities to unmount may be gone already, the mounts go
+            // with the namespace instead
+            std::process::exit(not_runnable(&args[0], e).unwrap_or_else(|e| {
+                eprintln!("cfs: {}", e);
+                1
+            }));
+        }
+        let pid = spawn(start);
+        // the command has its own copies, ours would keep the PTY open
+        drop((tty, stdin));
+        let pid = match pid {
+            Ok(pid) => pid,
+            // returned rather than raised, PID 1 still unmounts /proc
+            Err(e) => return not_runnable(&args[0], e),
+        };
+        // as PID 1 of the namespace we'd silently drop signals from the
+        // parent without a handler, so pass them on to the command
+        signals::forward_to(pid)?;
+        if self.init {
+            return Ok(reap_until(pid));
+        }
+        Ok(signals::wait_for(pid).map_or(1, |status| exit_code(ExitStatus::from_raw(status))))
+    }
+}
+
+/// Why the command never ran. Only a failed execve is down to the command
+/// itself.
+enum StartError {
+    /// Setting up its process: stdio, the terminal, the parent death signal.
+    Setup(std::io::Error),
+    /// Installing the seccomp filter, dropping capabilities, switching user.
+    Privileges(std::io::Error),
+    Exec(std::io::Error),
+}
+
+impl StartError {
+    /// As sent through spawn's pipe: which step, then errno.
+    fn to_bytes(&self) -> [u8; 5] {
+        let (step, e) = match self {
+            StartError::Setup(e) => (0, e),
+            StartError::Privileges(e) => (1, e),
+            StartError::Exec(e) => (2, e),
+        };
+        let errno = e.raw_os_error().unwrap_or(libc::EINVAL).to_ne_bytes();
+        [step, errno[0], errno[1], errno[2], errno[3]]
+    }
+
+    fn from_bytes(bytes: [u8; 5]) -> StartError {
+        let e = std::io::Error::from_raw_os_error(i32::from_ne_bytes([
+            bytes[1], bytes[2], bytes[3], bytes[4],
+        ]));
+        match bytes[0] {
+            0 => StartError::Setup(e),
+            1 => StartError::Privileges(e),
+            _ => StartError::Exec(e),
+        }
+    }
+}
+
+/// Forks a child that runs `start`, which only returns on failure, and
+/// reports that failure through a close-on-exec pipe, so the child is
+/// running the command once this returns Ok.
+fn spawn(start: impl Fn() -> StartError) -> std::result::Result<libc::pid_t, StartError> {
+    let failed = || StartError::Setup(std::io::Error::last_os_error());
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(failed());
+    }
+    // SAFETY: the pipe is ours alone
+    let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        return Err(failed());
+    }
+    if pid == 0 {
+        let failure = start().to_bytes();
+        unsafe {
+            libc::write(write.as_raw_fd(), failure.as_ptr().cast(), failure.len());
+            libc::_exit(127);
+        }
+    }
+    drop(write);
+    let mut failure = [0u8; 5];
+    let n = loop {
+        let n = unsafe { libc::read(read.as_raw_fd(), failure.as_mut_ptr().cast(), failure.len()) };
+        if n >= 0 || std::io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+            break n;
+        }
+    };
+    if n == failure.len() as isize {
+        signals::wait_for(pid);
+        return Err(StartError::from_bytes(failure));
+    }
+    Ok(pid)
+}
+
+/// Stops a container with SIGTERM, SIGKILL once `timeout` is over, then does
+/// whatever cleanup its supervising cfs didn't get to.
+pub fn stop(name: &str, timeout: Duration) -> Result<()> {
+    let mut state = State::load(name)?;
+    if state.is_running() {
+        // so a restart policy leaves it stopped
+        state.stopping = true;
+        state.update()?;
+        unsafe { libc::kill(state.pid, libc::SIGTERM) };
+        // a paused container only gets its signals once thawed
+        let _ = cgroup::freeze(&state.cgroups, false);
+        if !state.wait_for_exit(timeout) {
+            unsafe { libc::kill(state.pid, libc::SIGKILL) };
+            if !state.wait_for_exit(Duration::from_secs(5)) {
+                return Err(CfsError::Command(format!(
+                    "{} (PID {}) survived SIGKILL",
+                    name, state.pid
+                )));
+            }
+        }
+    }
+
+    // proc, the volumes and the overlay went away with the container's mount
+    // namespace; this catches an overlay still held from the host side
+    mount::unmount_below(Plan::RUN, &Overlay::new(&state.id).merged())?;
+    EtcFiles::new(&state.id).remove()?;
+    cgroup::remove_dirs(&state.cgroups)?;
+    state.remove()
+}
+
+/// Copies `src` on the host into a running container as `dest`, a path in
+/// its filesystem, or into `dest` if that is a directory. What arrives
+/// belongs to the container's root, whichever host user that is.
+pub fn copy_in(name: &str, src: &Path, dest: &Path) -> Result<()> {
+    let state = running(name)?;
+    let root = container_root(&state)?;
+    let dest = into_dir(src, mount::resolve_in_root(&root, dest)?)?;
+    copy::tree(src, &dest, Some(User::root_of(state.pid)?))
+}
+
+/// Copies `src`, a path in a running container, out to `dest` on the host,
+/// or into `dest` if that is a directory.
+pub fn copy_out(name: &str, src: &Path, dest: &Path) -> Result<()> {
+    let state = running(name)?;
+    let resolved = mount::resolve_in_root(&container_root(&state)?, src)?;
+    copy::tree(&resolved, &into_dir(&resolved, dest.to_path_buf())?, None)
+}
+
+/// The container's root as seen from the host. A container that has just
+/// started may not have switched to it yet; until then this would be the
+/// host's own.
+fn container_root(state: &State) -> Result<PathBuf> {
+    let root = PathBuf::from(format!("/proc/{}/root", state.pid));
+    let same = |a: &fs::Metadata, b: &fs::Metadata| a.dev() == b.dev() && a.ino() == b.ino();
+    let ours = fs::metadata("/").context("Cannot access /")?;
+    let theirs = fs::metadata(&root).context(format!("Cannot access {}", root.display()))?;
+    if same(&ours, &theirs) {
+        return Err(CfsError::Usage(format!(
+            "{} is still starting, try again in a moment",
+            state.name
+        )));
+    }
+    Ok(root)
+}
+
+// as with cp, a copy into an existing directory keeps its name
+fn into_dir(src: &Path, dest: PathBuf) -> Result<PathBuf> {
+    if !dest.is_dir() || dest.is_symlink() {
+        return Ok(dest);
+    }
+    let name = src.file_name().ok_or_else(|| {
+        CfsError::Usage(format!("Cannot copy {} into a directory", src.display()))
+    })?;
+    Ok(dest.join(name))
+}
+
+/// Saves the filesystem of a stopped container, the rootfs it ran on with
+/// the changes from its overlay, as a new rootfs for `--rootfs
+/// <rootfs_name>`. Returns where it went.
+pub fn commit(name: &str, rootfs_name: &str) -> Result<PathBuf> {
+    if State::load(name).is_ok_and(|state| state.is_running()) {
+        return Err(CfsError::Usage(format!(
+            "{} is still running, stop it before committing",
+            name
+        )));
+    }
+    state::validate_name(rootfs_name)?;
+    let dest = rootfs::committed(rootfs_name);
+    if dest.exists() {
+        return Err(CfsError::Usage(format!(
+            "A rootfs named {} already exists at {}",
+            rootfs_name,
+            dest.display()
+        )));
+    }
+    let state = State::find_kept(&crate::data_dir().join("containers"), name).ok_or_else(|| {
+        CfsError::Usage(format!(
+            "No stopped container named {} with an overlay to commit",
+            name
+        ))
+    })?;
+
+    let overlay = Overlay::new(&state.id);
+    overlay.mount_readonly(Plan::RUN, &state.rootfs)?;
+    // next to its final place, so a failed copy never passes for a rootfs
+    let partial = dest.with_file_name(format!("{}.partial", rootfs_name));
+    let copied = archive::copy(&overlay.merged(), &partial);
+    let unmounted = mount::unmount(Plan::RUN, &overlay.merged());
+    if let Err(e) = copied.and(unmounted) {
+        let _ = fs::remove_dir_all(&partial);
+        return Err(e);
+    }
+    fs::rename(&partial, &dest).context(format!("Failed to move {} into place", dest.display()))?;
+    Ok(dest)
+}
+
+/// Builds a new rootfs named `rootfs_name` from the one `container` runs
+/// on: each of `steps`, a shell command, runs in a container of its own on
+/// what the step before left. With an overlay every step is committed for
+/// the next, without one the base is copied first and the steps write to
+/// the copy. The container's command, name and `--rm` are set per step.
+/// Returns where the rootfs went.
+pub fn build(mut container: Container, steps: &[String], rootfs_name: &str) -> Result<PathBuf> {
+    state::validate_name(rootfs_name)?;
+    if container.image.is_some() {
+        return Err(CfsError::Usage(
+            "A build starts from a rootfs or a distro, not an image".into(),
+        ));
+    }
+    let dest = rootfs::committed(rootfs_name);
+    if dest.exists() {
+        return Err(CfsError::Usage(format!(
+            "A rootfs named {} already exists at {}",
+            rootfs_name,
+            dest.display()
+        )));
+    }
+    let partial = dest.with_file_name(format!("{}.partial", rootfs_name));
+    let base = container
+        .rootfs
+        .as_deref()
+        .map_or(PathBuf::from(DEFAULT_ROOTFS), rootfs::resolve);
+    rootfs::bootstrap(
+        container.plan(),
+        &base,
+        &container.distro,
+        container.auto_install_deps,
+        container.cache_dir.as_deref(),
+        container.proxy.as_deref(),
+        !container.insecure,
+    )?;
+    let overlay = container.overlay && !steps.is_empty();
+    if !overlay {
+        archive::copy(&base, &partial)?;
+    }
+
+    let name = format!("{}-build", rootfs_name);
+    container.name = Some(name.clone());
+    container.rm = false;
+    // the step before's, gone once the next one is committed
+    let mut step_rootfs: Option<String> = None;
+    for (i, step) in steps.iter().enumerate() {
+        info!("Step {}/{}: {}", i + 1, steps.len(), step);
+        container.rootfs = Some(match &step_rootfs {
+            Some(step_rootfs) => rootfs::committed(step_rootfs),
+            None if overlay => base.clone(),
+            None => partial.clone(),
+        });
+        container.command = vec!["/bin/sh".into(), "-c".into(), step.clone()];
+        let mut result = container.run().and_then(|status| {
+            if status.success() {
+                Ok(())
+            } else {
+                Err(CfsError::Command(format!(
+                    "Step {}, {}, failed with {}",
+                    i + 1,
+                    step,
+                    status
+                )))
+            }
+        });
+        if overlay {
+            let committed = format!("{}.step{}", rootfs_name, i + 1);
+            result = result.and_then(|_| commit(&name, &committed).map(drop));
+            discard_kept(&name);
+            if let Some(previous) = step_rootfs.replace(committed) {
+                let _ = fs::remove_dir_all(rootfs::committed(&previous));
+            }
+        }
+        if let Err(e) = result {
+            let _ = fs::remove_dir_all(&partial);
+            if let Some(step_rootfs) = &step_rootfs {
+                let _ = fs::remove_dir_all(rootfs::committed(step_rootfs));
+            }
+            return Err(e);
+        }
+    }
+    let built = match &step_rootfs {
+        Some(step_rootfs) => rootfs::committed(step_rootfs),
+        None => partial,
+    };
+    fs::rename(&built, &dest).context(format!("Failed to move {} into place", dest.display()))?;
+    Ok(dest)
+}
+
+/// Removes the overlay a stopped container named `name` left for `commit`.
+fn discard_kept(name: &str) {
+    if let Some(state) = State::find_kept(&crate::data_dir().join("containers"), name)
+        && let Err(e) = Overlay::new(&state.id).remove(Plan::RUN)
+    {
+        warn!("cannot remove the overlay of {}: {}", name, e);
+    }
+}
+
+/// Suspends every process of a running container until [`resume`].
+pub fn pause(name: &str) -> Result<()> {
+    cgroup::freeze(&running(name)?.cgroups, true)
+}
+
+pub fn resume(name: &str) -> Result<()> {
+    cgroup::freeze(&running(name)?.cgroups, false)
+}
+
+/// What a running container uses, from its cgroups.
+pub fn stats(name: &str) -> Result<cgroup::Usage> {
+    let state = running(name)?;
+    if state.cgroups.is_empty() {
+        return Err(CfsError::Usage(format!(
+            "{} has no cgroups to count with, it runs rootless",
+            name
+        )));
+    }
+    Ok(cgroup::usage(&state.cgroups))
+}
+
+/// Writes the root filesystem of a running container to `writer` as a tar
+/// archive, with the changes made so far but without what is mounted over
+/// it, such as `/proc` or the volumes.
+pub fn export(name: &str, writer: impl Write) -> Result<()> {
+    let state = running(name)?;
+    let root = container_root(&state)?;
+    let proc = PathBuf::from(format!("/proc/{}", state.pid));
+    let mounts = mount::mount_points(&proc.join("mountinfo"))?
+        .into_iter()
+        .filter(|target| target != Path::new("/"))
+        .collect();
+    archive::pack(&root, &mounts, writer)
+}
+
+/// Everything there is to know about a running container, see
+/// [`Inspection`].
+pub fn inspect(name: &str) -> Result<Inspection> {
+    let state = running(name)?;
+    let mountinfo = PathBuf::from(format!("/proc/{}/mountinfo", state.pid));
+    Ok(Inspection {
+        namespaces: ns::ids(state.pid),
+        mounts: mount::mount_points(&mountinfo)?,
+        usage: (!state.cgroups.is_empty()).then(|| cgroup::usage(&state.cgroups)),
+        env: state
+            .env
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect(),
+        capabilities: state.caps.names(),
+        seccomp: state.seccomp.is_some(),
+        ulimits: state.ulimits.iter().map(Ulimit::to_string).collect(),
+        name: state.name,
+        id: state.id,
+        pid: state.pid,
+        command: state.command,
+        user: state.user,
+        rootfs: state.rootfs,
+        cgroups: state.cgroups,
+        address: state.address,
+        health: state.health,
+        restarts: state.restarts,
+        labels: state.labels,
+        started: state.started,
+    })
+}
+
+fn running(name: &str) -> Result<State> {
+    let state = State::load(name)?;
+    if !state.is_running() {
+        return Err(CfsError::Usage(format!("{} is not running", name)));
+    }
+    Ok(state)
+}
+
+/// Runs a command inside a running container, in its namespaces, root and
+/// cgroups, with the environment and confinement its own command got. The
+/// calling process moves into the container for good.
+pub fn exec(name: &str, command: &[String]) -> Result<ExitStatus> {
+    if command.is_empty() {
+        return Err(CfsError::Usage("Need a command to run".into()));
+    }
+    let state = running(name)?;
+
+    ns::join_cgroups(&state.cgroups)?;
+    ns::enter(state.pid)?;
+
+    let program = Program::new(command, &state.env)?;
+    let caps = state.caps;
+    let seccomp = state.seccomp.as_ref().map(Filter::program);
+    let user = state.user;
+    // as in run_command, only syscalls between fork and exec
+    let prepare = || -> std::result::Result<(), StartError> {
+        for ulimit in &state.ulimits {
+            ulimit.apply().map_err(StartError::Setup)?;
+        }
+        if let Some(program) = &seccomp {
+            program.apply().map_err(StartError::Privileges)?;
+        }
+        caps.apply(user).map_err(StartError::Privileges)
+    };
+    // forking is what lands the command in the PID namespace
+    let pid = match spawn(|| match prepare() {
+        Ok(()) => StartError::Exec(program.exec()),
+        Err(e) => e,
+    }) {
+        Ok(pid) => pid,
+        // as if the command had exited with the code
+        Err(e) => return not_runnable(&command[0], e).map(|code| ExitStatus::from_raw(code << 8)),
+    };
+    signals::forward_to(pid)?;
+    signals::wait_for(pid)
+        .map(ExitStatus::from_raw)
+        .ok_or_else(|| CfsError::last_os_error(format!("failed to wait for {}", &command[0])))
+}
+
+/// Moves the rest of `run` into the background: forks twice, so the daemon
+/// is in its own session and not its leader, and points its stdio at the
+/// container's log. In the daemon it returns the pipe end to report on once
+/// the container is registered; the foreground process waits for that,
+/// reports the container in `format` and gets `None`.
+fn detach(name: &str, format: Format) -> Result<Option<libc::c_int>> {
+    let log_path = State::log_path(name);
+    let dir = state::run_dir();
+    fs::create_dir_all(&dir).context(format!("Failed to create {}", dir.display()))?;
+    let log =
+        fs::File::create(&log_path).context(format!("Failed to create {}", log_path.display()))?;
+    let devnull = fs::File::open("/dev/null").context("Failed to open /dev/null")?;
+
+    let mut ready = [0; 2];
+    check(
+        unsafe { libc::pipe2(ready.as_mut_ptr(), libc::O_CLOEXEC) },
+        "pipe failed",
+    )?;
+    let pid = unsafe { libc::fork() };
+    check(pid, "fork failed")?;
+    if pid > 0 {
+        unsafe {
+            libc::close(ready[1]);
+        }
+        signals::wait_for(pid);
+        let mut byte = [0u8];
+        let n = unsafe { libc::read(ready[0], byte.as_mut_ptr() as *mut libc::c_void, 1) };
+        unsafe { libc::close(ready[0]) };
+        if n == 1 {
+            match format {
+                Format::Text => println!("{}", name),
+                Format::Json => {
+                    let state = State::load(name)?;
+                    format::print_json(&RunReport {
+                        name: state.name,
+                        id: state.id,
+                        pid: state.pid,
+                        exit_code: None,
+                        restarts: state.restarts,
+                    });
+                }
+            }
+            return Ok(None);
+        }
+        return Err(CfsError::Command(format!(
+            "The container failed to start, see {}",
+            log_path.display()
+        )));
+    }
+
+    unsafe {
+        libc::close(ready[0]);
+        libc::setsid();
+        match libc::fork() {
+            0 => {}
+            -1 => libc::_exit(1),
+            _ => libc::_exit(0),
+        }
+        libc::dup2(devnull.as_raw_fd(), 0);
+        libc::dup2(log.as_raw_fd(), 1);
+        libc::dup2(log.as_raw_fd(), 2);
+    }
+    Ok(Some(ready[1]))
+}
+
+/// A random 12 hex digit container id.
+fn generate_id() -> Result<String> {
+    let mut bytes = [0u8; 6];
+    check(
+        unsafe { libc::getrandom(bytes.as_mut_ptr() as *mut libc::c_void, bytes.len(), 0) },
+        "getrandom failed",
+    )?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Sets how readily the OOM killer picks `pid`, which everything it starts
+/// inherits.
+fn set_oom_score_adj(plan: Plan, pid: libc::pid_t, score: i32) -> Result<()> {
+    let path = PathBuf::from(format!("/proc/{}/oom_score_adj", pid));
+    if plan.skip(format!("write {} to {}", score, path.display())) {
+        return Ok(());
+    }
+    fs::write(&path, score.to_string()).context(format!("Failed to set --oom-score-adj {}", score))
+}
+
+// Maps the invoking user to root inside the container. The kernel only accepts
+// a gid_map from an unprivileged writer once setgroups has been denied.
+fn write_id_maps(pid: libc::pid_t, uid: libc::uid_t, gid: libc::gid_t) -> Result<()> {
+    debug!("mapping root in the container to uid {} gid {}", uid, gid);
+    let proc_dir = PathBuf::from(format!("/proc/{}", pid));
+    fs::write(proc_dir.join("uid_map"), format!("0 {} 1", uid))
+        .context("Failed to write uid_map")?;
+    fs::write(proc_dir.join("setgroups"), "deny").context("Failed to write setgroups")?;
+    fs::write(proc_dir.join("gid_map"), format!("0 {} 1", gid))
+        .context("Failed to write gid_map")?;
+    Ok(())
+}
+
+// Blocks until the parent signals that the setup it does on our behalf (id
+// maps, ...) is complete. EOF without a byte means the parent gave up.
+fn wait_for_parent(sync_pipe: [libc::c_int; 2]) -> Result<()> {
+    let mut buf = [0u8; 1];
+    let n = unsafe {
+        libc::close(sync_pipe[1]);
+        let n = libc::read(sync_pipe[0], buf.as_mut_ptr() as *mut libc::c_void, 1);
+        libc::close(sync_pipe[0]);
+        n
+    };
+    if n != 1 {
+        return Err(CfsError::Command(
+            "parent exited before the container was set up".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Takes back what a start that failed partway had set up: the child,
+/// killed and reaped so its cgroup is no longer busy, then the cgroup, the
+/// overlay unless `remove_overlay` is false, and the /etc files. What won't
+/// go is only logged, the error that stopped the start is the one to report.
+fn abort_start(
+    child_args: &ChildArgs,
+    pid: Option<libc::pid_t>,
+    cgroup: Option<&Cgroup>,
+    remove_overlay: bool,
+) {
+    if let Some(pid) = pid {
+        unsafe { libc::kill(pid, libc::SIGKILL) };
+        signals::wait_for(pid);
+    }
+    let plan = child_args.container.plan();
+    let removed = [
+        cgroup.map_or(Ok(()), Cgroup::remove),
+        child_args
+            .overlay
+            .as_ref()
+            .filter(|_| remove_overlay)
+            .map_or(Ok(()), |overlay| overlay.remove(plan)),
+        child_args.etc.remove(),
+    ];
+    for e in removed.into_iter().filter_map(Result::err) {
+        warn!("{}", e);
+    }
+}
+
+extern "C" fn child_func(arg: *mut libc::c_void) -> i32 {
+    // SAFETY: see Container::clone_child, `arg` points into our private copy
+    // of the parent's ChildArgs
+    let child_args = unsafe { &*(arg as *const ChildArgs) };
+    match child(child_args) {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("cfs: {}", e);
+            1
+        }
+    }
+}
+
+fn child(child_args: &ChildArgs) -> Result<i32> {
+    let container = child_args.container;
+    let plan = container.plan();
+
+    if !plan.dry_run() {
+        wait_for_parent(child_args.sync_pipe)?;
+    }
+    // before any mount: the copy of a shared host mount table we start with
+    // would carry our mounts back to the host
+    let follows_host = container
+        .volumes
+        .iter()
+        .any(|v| v.propagation == Propagation::RSlave);
+    mount::make_private(
+        plan,
+        if follows_host {
+            Propagation::RSlave
+        } else {
+            Propagation::RPrivate
+        },
+    )?;
+
+    // only now, the namespace is rooted at the cgroup we are in when
+    // entering it and the parent has just moved us into ours
+    if child_args.cgroupns && !plan.skip("unshare the cgroup namespace") {
+        check(
+            unsafe { libc::unshare(libc::CLONE_NEWCGROUP) },
+            "unshare of the cgroup namespace failed",
+        )?;
+    }
+    // clone can't create time namespaces, so PID 1 makes one for the
+    // command, before anything has run in it
+    if !container.time_offsets.is_empty() {
+        ns::unshare_time(plan, &container.time_offsets)?;
+    }
+    if let Some(netns) = &child_args.netns {
+        net::join(plan, netns)?;
+    }
+    // still on the host filesystem, so the host's `ip` binary is at hand
+    if container.net.is_private() {
+        net::configure(plan, child_args.veth.as_ref())?;
+    }
+    set_hostname(plan, &child_args.hostname)?;
+    if let Some(domainname) = &container.domainname {
+        set_domainname(plan, domainname)?;
+    }
+
+    let root = match &child_args.overlay {
+        Some(overlay) => {
+            overlay.mount(plan, &child_args.rootfs)?;
+            overlay.merged()
+        }
+        None => child_args.rootfs.clone(),
+    };
+
+    // mount proc while the host's /proc is still visible: inside a user
+    // namespace the kernel only allows it when an unobstructed proc is present
+    let mut mounts = MountGuard::default();
+    let mounted = container
+        .proc
+        .as_ref()
+        .map_or(Ok(()), |proc| {
+            mount::mount_proc(plan, &mount::resolve_in_root(&root, proc)?)
+        })
+        .and_then(|_| container.mount_filesystems(&root, &child_args.etc, child_args.cgroupns));
+    // whatever made it, on failure too, so the guard takes it down again
+    mounts.track_below(plan, &root)?;
+    mounted?;
+    container.switch_root(&root)?;
+    mounts.rebase(&root);
+
+    let status = setup_and_run(child_args);
+    mounts.teardown();
+    status
+}
+
+fn setup_and_run(child_args: &ChildArgs) -> Result<i32> {
+    let plan = child_args.container.plan();
+    info!("Child running as PID {}", std::process::id());
+    // after the root switch, so the workdir can be a volume
+    if let Some(dir) = &child_args.workdir
+        && !plan.skip(format!("enter {}", dir.display()))
+    {
+        env::set_current_dir(dir).context(format!("Cannot enter workdir {}", dir.display()))?;
+    }
+    let tty = match child_args.tty_socket {
+        Some(socket) => {
+            // SAFETY: our copy of the parent's descriptor, nothing else owns it
+            let socket = unsafe { OwnedFd::from_raw_fd(socket) };
+            let (master, slave) = tty::open_pty()?;
+            tty::send_fd(&socket, &master)?;
+            Some(slave)
+        }
+        None => None,
+    };
+    for ulimit in &child_args.container.ulimits {
+        if plan.skip(format!("set --ulimit {}", ulimit)) {
+            continue;
+        }
+        ulimit
+            .apply()
+            .context(format!("Failed to set --ulimit {}", ulimit))?;
+    }
+    child_args
+        .container
+        .run_command(&child_args.command, &child_args.env, child_args.user, tty)
+}
+
+/// `--rm` without an overlay. Only a rootfs in cfs's own data directory,
+/// such as an image's, goes; resolved first, so a symlink can't point the
+/// removal anywhere else.
+fn remove_rootfs(plan: Plan, rootfs: &Path) -> Result<()> {
+    let managed = fs::canonicalize(crate::data_dir()).ok();
+    let resolved = fs::canonicalize(rootfs)
+        .context(format!("Cannot resolve rootfs path {}", rootfs.display()))?;
+    if !managed.is_some_and(|dir| resolved.starts_with(&dir) && resolved != dir) {
+        warn!(
+            "keeping {}, --rm only deletes a rootfs cfs manages",
+            rootfs.display()
+        );
+        return Ok(());
+    }
+    if plan.skip(format!("remove {}", resolved.display())) {
+        return Ok(());
+    }
+    mount::unmount_below(plan, &resolved)?;
+    fs::remove_dir_all(&resolved).context(format!("Failed to remove {}", resolved.display()))
+}
+
+/// Reports a command that could not be executed the way a shell does, with
+/// 127 when it doesn't exist and 126 when it can't be executed. A failure
+/// before execve, a seccomp or capability setup say, remains an error.
+fn not_runnable(program: &str, e: StartError) -> Result<i32> {
+    let e = match e {
+        StartError::Setup(e) => {
+            return Err(e).context(format!("Failed to set up the process for {}", program));
+        }
+        StartError::Privileges(e) => {
+            return Err(e).context(format!("Failed to drop privileges for {}", program));
+        }
+        StartError::Exec(e) => e,
+    };
+    match e.raw_os_error() {
+        Some(libc::ENOENT) => {
+            eprintln!("cfs: {}: command not found", program);
+            Ok(127)
+        }
+        Some(libc::EACCES | libc::ENOEXEC) => {
+            eprintln!("cfs: {}: permission denied", program);
+            Ok(126)
+        }
+        _ => Err(e).context(format!("failed to run {}", program)),
+    }
+}
+
+/// The init duty of PID 1: orphans anywhere in the namespace get reparented
+/// to us, so reap every child until the main command exits, then return its
+/// exit code.
+fn reap_until(main: libc::pid_t) -> i32 {
+    loop {
+        let mut status = 0;
+        let pid = unsafe { libc::waitpid(-1, &mut status, 0) };
+        if pid == main {
+            return exit_code(ExitStatus::from_raw(status));
+        }
+        if pid < 0 && std::io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+            // ECHILD: nothing left to wait for, the main child must be gone
+            return 1;
+        }
+    }
+}
+
+/// Watches over a container from a thread of its own, and stops it when
+/// its time is up unless disarmed first.
+struct Deadline {
+    disarm: mpsc::Sender<()>,
+    watcher: thread::JoinHandle<bool>,
+}
+
+impl Deadline {
+    fn arm(pid: libc::pid_t, limit: Duration, cgroups: Vec<PathBuf>) -> Deadline {
+        let (disarm, disarmed) = mpsc::channel();
+        let watcher = thread::spawn(move || {
+            if disarmed.recv_timeout(limit) != Err(RecvTimeoutError::Timeout) {
+                return false;
+            }
+            // PID 1 takes the rest of its PID namespace, and so the cgroup,
+            // down with it
+            debug!("timeout: sending SIGTERM to {}", pid);
+            unsafe { libc::kill(pid, libc::SIGTERM) };
+            // a paused container only gets its signals once thawed
+            let _ = cgroup::freeze(&cgroups, false);
+            if disarmed.recv_timeout(TIMEOUT_GRACE) == Err(RecvTimeoutError::Timeout) {
+                debug!("timeout: sending SIGKILL to {}", pid);
+                unsafe { libc::kill(pid, libc::SIGKILL) };
+            }
+            true
+        });
+        Deadline { disarm, watcher }
+    }
+
+    /// Call once the container has exited, and before it is reaped. Tells
+    /// whether it was stopped for running out of time.
+    fn disarm(self) -> bool {
+        let _ = self.disarm.send(());
+        self.watcher.join().unwrap_or(false)
+    }
+}
+
+/// Shell convention for an exit status: the exit code, or 128 + the signal
+/// number for a process killed by a signal.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        _ => 1,
+    }
+}
+
+/// Host names as RFC 1123 has them: dot separated labels of letters,
+/// digits and hyphens, within the kernel's limit.
+fn validate_hostname(hostname: &str) -> Result<()> {
+    let invalid = |why: String| {
+        Err(CfsError::Usage(format!(
+            "Invalid hostname {:?}: {}",
+            hostname, why
+        )))
+    };
+    if hostname.is_empty() || hostname.len() > HOST_NAME_MAX {
+        return invalid(format!("must be between 1 and {} bytes", HOST_NAME_MAX));
+    }
+    for label in hostname.split('.') {
+        if label.is_empty() || label.len() > LABEL_MAX {
+            return invalid(format!(
+                "each dot separated part must be between 1 and {} characters",
+                LABEL_MAX
+            ));
+        }
+        if !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return invalid("only letters, digits, hyphens and dots are allowed".into());
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return invalid("a part can't start or end with a hyphen".into());
+        }
+    }
+    Ok(())
+}
+
+/// The container's name as a host name, which can't have underscores. A
+/// name that still isn't one, such as one with `..`, gets the id instead.
+fn default_hostname(name: &str, id: &str) -> String {
+    // names are ASCII, any cut is on a character boundary
+    let mut hostname = name.replace('_', "-");
+    hostname.truncate(HOST_NAME_MAX);
+    let hostname = hostname.trim_end_matches(['-', '.']);
+    match validate_hostname(hostname) {
+        Ok(()) => hostname.to_string(),
+        Err(_) => id.to_string(),
+    }
+}
+
+/// Sets the hostname and reads it back, so what the command gets from
+/// gethostname is sure to be what `/etc/hostname` says.
+fn set_hostname(plan: Plan, name: &str) -> Result<()> {
+    if plan.skip(format!("set the hostname to {}", name)) {
+        return Ok(());
+    }
+    trace!("setting hostname {}", name);
+    check(
+        unsafe { libc::sethostname(name.as_ptr() as *const libc::c_char, name.len()) },
+        "Failed to set hostname",
+    )?;
+    let mut buf = [0u8; HOST_NAME_MAX + 1];
+    check(
+        unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) },
+        "Failed to read back the hostname",
+    )?;
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    if &buf[..len] != name.as_bytes() {
+        return Err(CfsError::Command(format!(
+            "The hostname is {:?} rather than {:?}",
+            String::from_utf8_lossy(&buf[..len]),
+            name
+        )));
+    }
+    Ok(())
+}
+
+fn set_domainname(plan: Plan, name: &str) -> Result<()> {
+    if plan.skip(format!("set the domain name to {}", name)) {
+        return Ok(());
+    }
+    check(
+        unsafe { libc::setdomainname(name.as_ptr() as *const libc::c_char, name.len()) },
+        "Failed to set domain name",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hostnames_follow_rfc_1123_within_the_kernel_limit() {
+        let label = "a".repeat(LABEL_MAX);
+        let longest = format!("{}.{}", "a".repeat(31), "b".repeat(32));
+        // the most DNS allows, but more than sethostname takes
+        let dns_longest = format!("{}a", "a.".repeat(126));
+        for (hostname, valid) in [
+            ("web", true),
+            ("web-1.example.com", true),
+            ("0day", true),
+            (label.as_str(), true),
+            (longest.as_str(), true),
+            (&format!("{}a", label), false),
+            (&format!("{}b", longest), false),
+            (dns_longest.as_str(), false),
+            ("", false),
+            ("-web", false),
+            ("web-", false),
+            ("web.-db", false),
+            ("web..db", false),
+            (".web", false),
+            ("web.", false),
+            ("web_1", false),
+            ("wéb", false),
+        ] {
+            assert_eq!(validate_hostname(hostname).is_ok(), valid, "{:?}", hostname);
+        }
+    }
+
+    #[test]
+    fn default_hostname_comes_from_the_name_or_the_id() {
+        let long = format!("{}_{}", "a".repeat(HOST_NAME_MAX - 1), "b");
+        for (name, hostname) in [
+            ("web", "web"),
+            ("web_1", "web-1"),
+            ("web_", "web"),
+            ("web.", "web"),
+            (long.as_str(), &"a".repeat(HOST_NAME_MAX - 1)),
+            ("web..db", "abc123"),
+            ("_web", "abc123"),
+        ] {
+            assert_eq!(default_hostname(name, "abc123"), hostname, "{:?}", name);
+        }
+    }
+}
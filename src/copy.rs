@@ -0,0 +1,57 @@
+//! What `cfs cp` does once both ends are paths on the host.
+
+use std::fs;
+use std::os::unix::fs::{lchown, symlink};
+use std::path::Path;
+
+use crate::error::{CfsError, Context, Result};
+use crate::user::User;
+
+/// Copies `src`, a file, a symlink or a whole directory tree, to `dest`,
+/// with its permissions. Everything copied belongs to `owner` if given,
+/// whoever runs cfs otherwise.
+///
+/// `dest` may be inside a container, so an existing symlink there is never
+/// followed: its target would be resolved against the host.
+pub fn tree(src: &Path, dest: &Path, owner: Option<User>) -> Result<()> {
+    if dest.is_symlink() {
+        return Err(CfsError::Usage(format!(
+            "Not copying over the symlink {}",
+            dest.display()
+        )));
+    }
+    let metadata = fs::symlink_metadata(src).context(format!("Cannot access {}", src.display()))?;
+    let kind = metadata.file_type();
+    if kind.is_symlink() {
+        let target = fs::read_link(src).context(format!("Failed to read {}", src.display()))?;
+        symlink(&target, dest).context(format!("Failed to create {}", dest.display()))?;
+    } else if kind.is_dir() {
+        if !dest.is_dir() {
+            fs::create_dir(dest).context(format!("Failed to create {}", dest.display()))?;
+        }
+        let entries = fs::read_dir(src).context(format!("Failed to read {}", src.display()))?;
+        for entry in entries {
+            let entry = entry.context(format!("Failed to read {}", src.display()))?;
+            tree(&entry.path(), &dest.join(entry.file_name()), owner)?;
+        }
+        fs::set_permissions(dest, metadata.permissions())
+            .context(format!("Failed to set the mode of {}", dest.display()))?;
+    } else if kind.is_file() {
+        fs::copy(src, dest).context(format!(
+            "Failed to copy {} to {}",
+            src.display(),
+            dest.display()
+        ))?;
+    } else {
+        return Err(CfsError::Usage(format!(
+            "{} is not a file, directory or symlink",
+            src.display()
+        )));
+    }
+
+    if let Some(owner) = owner {
+        lchown(dest, Some(owner.uid), Some(owner.gid))
+            .context(format!("Failed to change the owner of {}", dest.display()))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,67 @@
+//! SHA256 checks of what cfs downloads, against the digest a manifest or a
+//! mirror publishes for it.
+
+use std::io::{self, Read};
+
+use sha2::{Digest, Sha256};
+
+use crate::error::{CfsError, Context, Result};
+
+/// Hashes everything read through it, so a layer is checked as it is
+/// unpacked rather than buffered first.
+pub(crate) struct Hashing<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Hashing<R> {
+    pub(crate) fn new(inner: R) -> Hashing<R> {
+        Hashing {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Reads whatever the consumer left, padding after a tar archive's end
+    /// say, then checks the whole against `expected`, in hex.
+    pub(crate) fn verify(mut self, expected: &str, what: &str) -> Result<()> {
+        io::copy(&mut self, &mut io::sink()).context(format!("Failed to read {}", what))?;
+        compare(&hex(&self.hasher.finalize()), expected, what)
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Checks `bytes` against `expected`, in hex.
+pub(crate) fn verify(bytes: &[u8], expected: &str, what: &str) -> Result<()> {
+    compare(&hex(&Sha256::digest(bytes)), expected, what)
+}
+
+/// The hex of an OCI digest, `sha256:<hex>`, the only algorithm registries
+/// use in practice.
+pub(crate) fn oci_sha256(digest: &str) -> Result<&str> {
+    digest
+        .strip_prefix("sha256:")
+        .filter(|hex| hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+        .ok_or_else(|| CfsError::Command(format!("Unsupported digest {}", digest)))
+}
+
+fn compare(actual: &str, expected: &str, what: &str) -> Result<()> {
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(CfsError::Command(format!(
+            "{} doesn't match its checksum: expected sha256 {}, got {}",
+            what, expected, actual
+        )));
+    }
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
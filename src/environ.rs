@@ -0,0 +1,72 @@
+use std::path::Path;
+use std::{env, fs};
+
+use log::debug;
+
+use crate::error::{CfsError, Context, Result};
+
+/// What the container command starts with instead of the host environment.
+pub const DEFAULTS: &[(&str, &str)] = &[
+    (
+        "PATH",
+        "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+    ),
+    ("HOME", "/root"),
+    ("TERM", "xterm"),
+];
+
+/// The host's values of `keys`, leaving out those it doesn't set.
+pub fn from_host(keys: &[String]) -> Vec<(String, String)> {
+    keys.iter()
+        .filter_map(|key| match env::var(key) {
+            Ok(value) => Some((key.clone(), value)),
+            Err(_) => {
+                debug!("not keeping {}, the host doesn't set it", key);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Parses a `KEY=VALUE` assignment.
+pub fn parse_var(s: &str) -> Result<(String, String)> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(CfsError::Usage(format!(
+            "Invalid variable {}, expected KEY=VALUE",
+            s
+        ))),
+    }
+}
+
+/// Reads a dotenv-style file: one `KEY=VALUE` per line, optionally prefixed
+/// with `export`, values optionally quoted, blank lines and `#` comments skipped.
+pub fn read_env_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let contents =
+        fs::read_to_string(path).context(format!("Failed to read env file {}", path.display()))?;
+    let mut vars = Vec::new();
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (key, value) = parse_var(line).map_err(|_| {
+            CfsError::Usage(format!("{}:{}: expected KEY=VALUE", path.display(), n + 1))
+        })?;
+        vars.push((key.trim().to_string(), unquote(value.trim()).to_string()));
+    }
+    Ok(vars)
+}
+
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(inner) = value
+            .strip_prefix(quote)
+            .and_then(|v| v.strip_suffix(quote))
+        {
+            return inner;
+        }
+    }
+    value
+}
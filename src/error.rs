@@ -0,0 +1,65 @@
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum CfsError {
+    /// Bad command line input.
+    Usage(String),
+    /// A syscall or filesystem operation failed.
+    Io { context: String, source: io::Error },
+    /// An external program (debootstrap, apt-get, ...) failed.
+    Command(String),
+}
+
+pub type Result<T> = std::result::Result<T, CfsError>;
+
+impl CfsError {
+    /// Captures errno of the syscall that just failed.
+    pub fn last_os_error(context: impl Into<String>) -> Self {
+        CfsError::Io {
+            context: context.into(),
+            source: io::Error::last_os_error(),
+        }
+    }
+}
+
+impl fmt::Display for CfsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CfsError::Usage(msg) => write!(f, "{}", msg),
+            CfsError::Io { context, source } => write!(f, "{}: {}", context, source),
+            CfsError::Command(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for CfsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CfsError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Turns the `-1` convention of libc calls into a `Result`.
+pub fn check<T: Default + PartialOrd>(ret: T, context: &str) -> Result<()> {
+    if ret < T::default() {
+        Err(CfsError::last_os_error(context))
+    } else {
+        Ok(())
+    }
+}
+
+pub trait Context<T> {
+    fn context(self, context: impl Into<String>) -> Result<T>;
+}
+
+impl<T> Context<T> for io::Result<T> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|source| CfsError::Io {
+            context: context.into(),
+            source,
+        })
+    }
+}
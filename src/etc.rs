@@ -0,0 +1,171 @@
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+
+use log::trace;
+
+use crate::error::{Context, Result};
+use crate::mount::{self, Propagation, Volume};
+use crate::plan::Plan;
+
+const HOST_RESOLV_CONF: &str = "/etc/resolv.conf";
+// systemd-resolved's stub listens on 127.0.0.53, unreachable from another
+// network namespace; this one lists the upstream servers instead
+const RESOLVED_UPSTREAM: &str = "/run/systemd/resolve/resolv.conf";
+/// Left in place of resolv.conf by a container that binds the host's, so
+/// one joining its network knows to follow.
+const HOST_RESOLV_MARKER: &str = "resolv.conf.host";
+
+/// Where the name servers in `/etc/resolv.conf` come from, short of `--dns`.
+pub enum ResolvConf {
+    /// The host's, minus the loopback ones a network namespace of its own
+    /// can't reach.
+    Host,
+    /// The host's file itself, bound read-only, for a container on the
+    /// host's network.
+    HostNetwork,
+    /// Those of the container whose network namespace is joined.
+    Shared(EtcFiles),
+}
+
+/// `/etc/hosts`, `/etc/hostname` and `/etc/resolv.conf` for one container.
+/// They are generated on the host and bound over the image's, which stays
+/// untouched.
+pub struct EtcFiles {
+    dir: PathBuf,
+    /// Whether resolv.conf is the host's own rather than one of ours.
+    bind_host_resolv: bool,
+}
+
+impl EtcFiles {
+    /// Kept in `<run dir>/<id>`, they only matter while the container runs.
+    pub fn new(id: &str) -> EtcFiles {
+        EtcFiles {
+            dir: crate::state::run_dir().join(id),
+            bind_host_resolv: false,
+        }
+    }
+
+    /// Follows [`ResolvConf::HostNetwork`] unless `dns` replaces the name
+    /// servers.
+    pub fn with_resolv_conf(mut self, resolv: &ResolvConf, dns: &[Ipv4Addr]) -> EtcFiles {
+        self.bind_host_resolv = matches!(resolv, ResolvConf::HostNetwork) && dns.is_empty();
+        self
+    }
+
+    /// `address` is the container's own, when it has a network; `dns`
+    /// replaces the name servers `resolv` points at.
+    pub fn write(
+        &self,
+        plan: Plan,
+        hostname: &str,
+        address: Option<Ipv4Addr>,
+        dns: &[Ipv4Addr],
+        resolv: &ResolvConf,
+    ) -> Result<()> {
+        let dir = &self.dir;
+        if plan.skip(format!(
+            "write hosts, hostname and resolv.conf to {}",
+            dir.display()
+        )) {
+            return Ok(());
+        }
+        trace!("writing /etc files to {}", dir.display());
+        fs::create_dir_all(dir).context(format!("Failed to create {}", dir.display()))?;
+
+        let own = address.map_or("127.0.1.1".to_string(), |a| a.to_string());
+        let hosts = format!(
+            "127.0.0.1\tlocalhost\n::1\tlocalhost ip6-localhost ip6-loopback\n{}\t{}\n",
+            own, hostname
+        );
+        let resolv = if !dns.is_empty() {
+            dns.iter()
+                .map(|ip| format!("nameserver {}\n", ip))
+                .collect()
+        } else {
+            match resolv {
+                ResolvConf::Host => host_resolv_conf(false),
+                // bound as it is, see mount
+                ResolvConf::HostNetwork => String::new(),
+                ResolvConf::Shared(other) if other.dir.join(HOST_RESOLV_MARKER).exists() => {
+                    host_resolv_conf(true)
+                }
+                ResolvConf::Shared(other) => {
+                    let path = other.dir.join("resolv.conf");
+                    fs::read_to_string(&path)
+                        .context(format!("Failed to read {}", path.display()))?
+                }
+            }
+        };
+
+        let mut files = vec![("hosts", hosts), ("hostname", format!("{}\n", hostname))];
+        if self.bind_host_resolv {
+            files.push((HOST_RESOLV_MARKER, String::new()));
+        } else {
+            files.push(("resolv.conf", resolv));
+        }
+        for (name, contents) in files {
+            let path = dir.join(name);
+            fs::write(&path, contents).context(format!("Failed to write {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Binds the files over their counterparts below `root`, before the root
+    /// switch like any other volume.
+    pub fn mount(&self, plan: Plan, root: &Path) -> Result<()> {
+        // still the host's filesystem around us: an /etc that is a symlink
+        // could have us unlink the host's files below
+        let etc = mount::resolve_in_root(root, Path::new("/etc"))?;
+        for name in ["hosts", "hostname", "resolv.conf"] {
+            let target = Path::new("/etc").join(name);
+            // images often ship resolv.conf as a symlink into /run, which
+            // can't be bound over from here; a plain file takes its place
+            let in_root = etc.join(name);
+            if in_root.is_symlink() && !plan.skip(format!("remove {}", in_root.display())) {
+                fs::remove_file(&in_root)
+                    .context(format!("Failed to replace {}", in_root.display()))?;
+            }
+            let host = name == "resolv.conf" && self.bind_host_resolv;
+            Volume {
+                source: if host {
+                    PathBuf::from(HOST_RESOLV_CONF)
+                } else {
+                    self.dir.join(name)
+                },
+                target,
+                readonly: host,
+                propagation: Propagation::default(),
+            }
+            .mount(plan, root)?;
+        }
+        Ok(())
+    }
+
+    pub fn remove(&self) -> Result<()> {
+        match fs::remove_dir_all(&self.dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).context(format!("Failed to remove {}", self.dir.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The host's resolver configuration. Loopback name servers would be the
+/// container's own loopback, unless it shares the host's.
+fn host_resolv_conf(keep_loopback: bool) -> String {
+    let contents = fs::read_to_string(RESOLVED_UPSTREAM)
+        .or_else(|_| fs::read_to_string(HOST_RESOLV_CONF))
+        .unwrap_or_default();
+    contents
+        .lines()
+        .filter(|line| {
+            line.strip_prefix("nameserver")
+                .map(str::trim)
+                .and_then(|ip| ip.parse::<Ipv4Addr>().ok())
+                .is_none_or(|ip| keep_loopback || !ip.is_loopback())
+        })
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
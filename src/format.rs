@@ -0,0 +1,122 @@
+//! Output for scripts rather than people, with `--format json`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::cgroup::Usage;
+use crate::error::{CfsError, Result};
+use crate::health::Health;
+use crate::state::State;
+use crate::user::User;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Format {
+    /// Tables and plain lines.
+    #[default]
+    Text,
+    /// One JSON document per report, on a line of its own.
+    Json,
+}
+
+impl Format {
+    /// Parses `--format`, where `table` is another name for `text`.
+    pub fn parse(s: &str) -> Result<Format> {
+        match s {
+            "text" | "table" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(CfsError::Usage(format!(
+                "Unknown format {}, expected text, table or json",
+                s
+            ))),
+        }
+    }
+}
+
+/// A running container as `list` shows it.
+#[derive(Serialize)]
+pub struct Summary {
+    pub name: String,
+    pub id: String,
+    pub pid: libc::pid_t,
+    pub command: Vec<String>,
+    pub rootfs: PathBuf,
+    pub address: Option<Ipv4Addr>,
+    pub health: Option<Health>,
+    pub restarts: u32,
+    pub labels: HashMap<String, String>,
+    /// Seconds since the epoch.
+    pub started: u64,
+}
+
+impl From<&State> for Summary {
+    fn from(state: &State) -> Summary {
+        Summary {
+            name: state.name.clone(),
+            id: state.id.clone(),
+            pid: state.pid,
+            command: state.command.clone(),
+            rootfs: state.rootfs.clone(),
+            address: state.address,
+            health: state.health,
+            restarts: state.restarts,
+            labels: state.labels.clone(),
+            started: state.started,
+        }
+    }
+}
+
+/// What `run` reports: the container it started, and how it exited unless
+/// it was detached.
+#[derive(Serialize)]
+pub struct RunReport {
+    pub name: String,
+    pub id: String,
+    /// The last one, a restarted container has had several.
+    pub pid: libc::pid_t,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    pub restarts: u32,
+}
+
+/// What `inspect` shows of a running container: everything its state
+/// records, and what /proc and its cgroups tell about it now.
+#[derive(Serialize)]
+pub struct Inspection {
+    pub name: String,
+    pub id: String,
+    pub pid: libc::pid_t,
+    pub command: Vec<String>,
+    /// As `KEY=value`.
+    pub env: Vec<String>,
+    pub capabilities: Vec<&'static str>,
+    /// Whether a seccomp profile confines the command.
+    pub seccomp: bool,
+    /// As `--ulimit` takes them.
+    pub ulimits: Vec<String>,
+    pub user: Option<User>,
+    pub rootfs: PathBuf,
+    pub cgroups: Vec<PathBuf>,
+    pub address: Option<Ipv4Addr>,
+    pub health: Option<Health>,
+    pub restarts: u32,
+    pub labels: HashMap<String, String>,
+    /// Seconds since the epoch.
+    pub started: u64,
+    /// By type, as in `/proc/<pid>/ns`.
+    pub namespaces: BTreeMap<&'static str, u64>,
+    /// Relative to the container's root, in mount order.
+    pub mounts: Vec<PathBuf>,
+    /// None for a rootless container, which has no cgroups.
+    pub usage: Option<Usage>,
+}
+
+/// Prints `value` as JSON on a line of its own.
+pub fn print_json(value: &impl Serialize) {
+    println!(
+        "{}",
+        serde_json::to_string(value).expect("reports serialize")
+    );
+}
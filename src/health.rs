@@ -0,0 +1,151 @@
+//! `--health-cmd`: a command run in the container every so often, whose
+//! exit code says whether the container still does its job.
+
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{CfsError, Result};
+use crate::state::State;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_RETRIES: u32 = 3;
+
+/// What the state file says about a container with a health check.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Health {
+    /// No check has passed or failed often enough yet.
+    Starting,
+    Healthy,
+    /// The last `retries` checks failed.
+    Unhealthy,
+}
+
+impl Health {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Health::Starting => "starting",
+            Health::Healthy => "healthy",
+            Health::Unhealthy => "unhealthy",
+        }
+    }
+}
+
+/// A shell command run as with `cfs exec` every `interval`, the first
+/// time one interval after the start.
+#[derive(Clone)]
+pub struct HealthCheck {
+    pub command: String,
+    pub interval: Duration,
+    /// Failures in a row that make the container unhealthy.
+    pub retries: u32,
+}
+
+impl HealthCheck {
+    pub fn new(command: impl Into<String>) -> HealthCheck {
+        HealthCheck {
+            command: command.into(),
+            interval: DEFAULT_INTERVAL,
+            retries: DEFAULT_RETRIES,
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.command.trim().is_empty() {
+            return Err(CfsError::Usage("The health check command is empty".into()));
+        }
+        if self.retries == 0 {
+            return Err(CfsError::Usage(
+                "Health check retries must be at least 1".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Runs the checks of one container from a thread of the supervisor,
+/// until `stop`.
+pub(crate) struct Monitor {
+    stop: mpsc::Sender<()>,
+    checker: thread::JoinHandle<()>,
+}
+
+impl Monitor {
+    /// With `kill_unhealthy`, an unhealthy container gets SIGKILL, for its
+    /// restart policy to start it again.
+    pub(crate) fn start(
+        check: HealthCheck,
+        name: String,
+        pid: libc::pid_t,
+        kill_unhealthy: bool,
+    ) -> Monitor {
+        let (stop, stopped) = mpsc::channel();
+        let checker = thread::spawn(move || {
+            let mut failures = 0;
+            let mut health = Health::Starting;
+            while stopped.recv_timeout(check.interval) == Err(RecvTimeoutError::Timeout) {
+                let code = run_check(&name, &check.command);
+                debug!("health check of {} exited with {}", name, code);
+                failures = if code == 0 { 0 } else { failures + 1 };
+                let now = match failures {
+                    0 => Health::Healthy,
+                    n if n >= check.retries => Health::Unhealthy,
+                    _ => health,
+                };
+                if now == health {
+                    continue;
+                }
+                health = now;
+                record(&name, health);
+                if health == Health::Unhealthy {
+                    warn!("{} is unhealthy after {} failed checks", name, failures);
+                    if kill_unhealthy {
+                        unsafe { libc::kill(pid, libc::SIGKILL) };
+                    }
+                }
+            }
+        });
+        Monitor { stop, checker }
+    }
+
+    /// Call once the container has exited.
+    pub(crate) fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.checker.join();
+    }
+}
+
+// exec moves the caller into the container for good, so it's done by a cfs
+// of its own, with nowhere to print to. Not a fork of ours: the supervisor
+// has threads, after a fork only syscalls are safe until exec.
+fn run_check(name: &str, command: &str) -> i32 {
+    let status = Command::new("/proc/self/exe")
+        .args(["exec", name, "/bin/sh", "-c", command])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    match status {
+        Ok(status) => crate::exit_code(status),
+        Err(e) => {
+            debug!("failed to run the health check of {}: {}", name, e);
+            1
+        }
+    }
+}
+
+// a failed read-modify-write only costs a stale status until the next change
+fn record(name: &str, health: Health) {
+    let result = State::load(name).and_then(|mut state| {
+        state.health = Some(health);
+        state.update()
+    });
+    if let Err(e) = result {
+        warn!("Failed to record the health of {}: {}", name, e);
+    }
+}
@@ -0,0 +1,541 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::digest::{self, Hashing};
+use crate::error::{CfsError, Context, Result};
+use crate::plan::Plan;
+
+const DOCKER_HUB: &str = "registry-1.docker.io";
+
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// A parsed `[registry/]repository[:tag|@digest]` image reference.
+pub struct Reference {
+    registry: String,
+    repository: String,
+    tag: String,
+}
+
+impl Reference {
+    pub fn parse(s: &str) -> Result<Reference> {
+        if s.is_empty() {
+            return Err(CfsError::Usage("Empty image reference".into()));
+        }
+
+        // the first component is a registry only if it looks like a host
+        let (registry, rest) = match s.split_once('/') {
+            Some((host, rest))
+                if host.contains('.') || host.contains(':') || host == "localhost" =>
+            {
+                (host.to_string(), rest)
+            }
+            _ => (DOCKER_HUB.to_string(), s),
+        };
+
+        let (repository, tag) = if let Some((repo, digest)) = rest.split_once('@') {
+            (repo, digest)
+        } else {
+            match rest.rsplit_once(':') {
+                Some((repo, tag)) if !tag.contains('/') => (repo, tag),
+                _ => (rest, "latest"),
+            }
+        };
+
+        // official Docker Hub images live under library/
+        let repository = if registry == DOCKER_HUB && !repository.contains('/') {
+            format!("library/{}", repository)
+        } else {
+            repository.to_string()
+        };
+
+        Ok(Reference {
+            registry,
+            repository,
+            tag: tag.to_string(),
+        })
+    }
+
+    /// Directory name the unpacked image is cached under.
+    fn cache_name(&self) -> String {
+        format!("{}/{}/{}", self.registry, self.repository, self.tag).replace(['/', ':', '@'], "_")
+    }
+}
+
+/// Subset of the image config that decides how the container command runs.
+#[derive(Serialize, Deserialize, Default)]
+pub struct ImageConfig {
+    #[serde(rename = "Env", default)]
+    pub env: Option<Vec<String>>,
+    #[serde(rename = "Entrypoint", default)]
+    pub entrypoint: Option<Vec<String>>,
+    #[serde(rename = "Cmd", default)]
+    pub cmd: Option<Vec<String>>,
+    #[serde(rename = "WorkingDir", default)]
+    pub working_dir: Option<String>,
+}
+
+impl ImageConfig {
+    /// Docker semantics: the entrypoint always runs, a command given on the
+    /// command line replaces the image's default `Cmd`. So does an
+    /// `entrypoint` given there, which drops the `Cmd` as well.
+    pub fn command(&self, entrypoint: Option<&[String]>, args: &[String]) -> Vec<String> {
+        if let Some(entrypoint) = entrypoint {
+            return entrypoint.iter().chain(args).cloned().collect();
+        }
+        let mut command = self.entrypoint.clone().unwrap_or_default();
+        if args.is_empty() {
+            command.extend(self.cmd.clone().unwrap_or_default());
+        } else {
+            command.extend(args.iter().cloned());
+        }
+        command
+    }
+
+    /// The `Env` entries split into key/value pairs.
+    pub fn env(&self) -> Vec<(String, String)> {
+        self.env
+            .iter()
+            .flatten()
+            .filter_map(|var| var.split_once('='))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+}
+
+#[derive(Deserialize)]
+struct ConfigBlob {
+    #[serde(default)]
+    config: ImageConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    #[serde(default)]
+    media_type: String,
+    digest: String,
+    #[serde(default)]
+    platform: Option<Platform>,
+}
+
+#[derive(Deserialize)]
+struct Platform {
+    architecture: String,
+    os: String,
+}
+
+/// Either an image manifest or a multi-platform index, told apart by which
+/// fields are present.
+#[derive(Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+    config: Option<Descriptor>,
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+}
+
+struct Registry<'a> {
+    reference: &'a Reference,
+    token: Option<String>,
+    /// Whether what comes by digest is checked against it.
+    verify: bool,
+}
+
+impl Registry<'_> {
+    fn url(&self, kind: &str, name: &str) -> String {
+        format!(
+            "https://{}/v2/{}/{}/{}",
+            self.reference.registry, self.reference.repository, kind, name
+        )
+    }
+
+    /// GETs `url`, fetching a bearer token the first time the registry asks
+    /// for one.
+    fn get(&mut self, url: &str, accept: &str) -> Result<ureq::Response> {
+        let failed = |e| CfsError::Command(format!("GET {} failed: {}", url, e));
+        match self.request(url, accept).call() {
+            Err(ureq::Error::Status(401, response)) if self.token.is_none() => {
+                let challenge = response.header("www-authenticate").unwrap_or_default();
+                self.token = Some(fetch_token(challenge)?);
+                self.request(url, accept).call().map_err(failed)
+            }
+            result => result.map_err(failed),
+        }
+    }
+
+    fn request(&self, url: &str, accept: &str) -> ureq::Request {
+        let request = ureq::get(url).set("Accept", accept);
+        match &self.token {
+            Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+            None => request,
+        }
+    }
+
+    /// GETs a JSON document, checked against `digest` if it was asked for
+    /// by one.
+    fn json<T: serde::de::DeserializeOwned>(
+        &mut self,
+        url: &str,
+        accept: &str,
+        digest: Option<&str>,
+    ) -> Result<T> {
+        let expected = self.expected(digest)?;
+        let mut body = Vec::new();
+        self.get(url, accept)?
+            .into_reader()
+            .read_to_end(&mut body)
+            .context(format!("Failed to read {}", url))?;
+        if let Some(expected) = expected {
+            digest::verify(&body, expected, url)?;
+        }
+        serde_json::from_slice(&body)
+            .map_err(std::io::Error::from)
+            .context(format!("Invalid JSON from {}", url))
+    }
+
+    /// The hex to check a download of `digest` against, if at all.
+    fn expected<'d>(&self, digest: Option<&'d str>) -> Result<Option<&'d str>> {
+        digest
+            .filter(|_| self.verify)
+            .map(digest::oci_sha256)
+            .transpose()
+    }
+}
+
+/// Answers a `Bearer realm="...",service="...",scope="..."` challenge with an
+/// anonymous token request.
+fn fetch_token(challenge: &str) -> Result<String> {
+    let params = challenge.strip_prefix("Bearer ").ok_or_else(|| {
+        CfsError::Command(format!(
+            "Unsupported registry auth challenge {:?}",
+            challenge
+        ))
+    })?;
+
+    let mut realm = None;
+    let mut query = Vec::new();
+    for param in params.split(',') {
+        if let Some((key, value)) = param.trim().split_once('=') {
+            let value = value.trim_matches('"');
+            match key {
+                "realm" => realm = Some(value),
+                _ => query.push((key, value)),
+            }
+        }
+    }
+    let realm = realm.ok_or_else(|| CfsError::Command("Auth challenge without realm".into()))?;
+
+    #[derive(Deserialize)]
+    struct Token {
+        #[serde(alias = "access_token")]
+        token: String,
+    }
+    let mut request = ureq::get(realm);
+    for (key, value) in query {
+        request = request.query(key, value);
+    }
+    let token: Token = request
+        .call()
+        .map_err(|e| CfsError::Command(format!("token request to {} failed: {}", realm, e)))?
+        .into_json()
+        .context("Invalid token response")?;
+    Ok(token.token)
+}
+
+fn platform_arch() -> &'static str {
+    if cfg!(target_arch = "aarch64") {
+        "arm64"
+    } else {
+        "amd64"
+    }
+}
+
+fn images_dir() -> PathBuf {
+    crate::data_dir().join("images")
+}
+
+/// Default location of the unpacked image `reference`.
+pub fn default_rootfs(reference: &Reference) -> PathBuf {
+    images_dir().join(reference.cache_name()).join("rootfs")
+}
+
+/// Pulls `reference` and unpacks its layers into `rootfs`, returning the
+/// image config. An image that has already been unpacked there is reused.
+/// With `verify`, everything the manifest names by digest is checked
+/// against it, each layer as it is unpacked.
+pub fn pull(plan: Plan, reference: &Reference, rootfs: &Path, verify: bool) -> Result<ImageConfig> {
+    let cache = images_dir().join(reference.cache_name());
+    let config_path = cache.join("config.json");
+    if rootfs.join(".cfs-image").exists()
+        && let Ok(config) = fs::read(&config_path)
+    {
+        return serde_json::from_slice(&config)
+            .map_err(std::io::Error::from)
+            .context("Invalid cached image config");
+    }
+
+    if plan.skip(format!(
+        "pull {}/{}:{} into {}",
+        reference.registry,
+        reference.repository,
+        reference.tag,
+        rootfs.display()
+    )) {
+        return Ok(ImageConfig::default());
+    }
+    let mut registry = Registry {
+        reference,
+        token: None,
+        verify,
+    };
+
+    let url = registry.url("manifests", &reference.tag);
+    // a tag can move, only one pulled by digest has one to check
+    let pinned = Some(reference.tag.as_str()).filter(|tag| tag.contains(':'));
+    let mut manifest: Manifest = registry.json(&url, MANIFEST_TYPES, pinned)?;
+    if manifest.config.is_none() {
+        let arch = platform_arch();
+        let entry = manifest
+            .manifests
+            .iter()
+            .find(|m| {
+                m.platform
+                    .as_ref()
+                    .is_some_and(|p| p.os == "linux" && p.architecture == arch)
+            })
+            .ok_or_else(|| CfsError::Command(format!("No linux/{} image in the index", arch)))?;
+        let url = registry.url("manifests", &entry.digest);
+        manifest = registry.json(&url, MANIFEST_TYPES, Some(&entry.digest))?;
+    }
+
+    let config = manifest
+        .config
+        .as_ref()
+        .ok_or_else(|| CfsError::Command("Image manifest has no config".into()))?;
+    let url = registry.url("blobs", &config.digest);
+    let blob: ConfigBlob = registry.json(&url, "*/*", Some(&config.digest))?;
+
+    fs::create_dir_all(rootfs).context(format!("Failed to create {}", rootfs.display()))?;
+    for (n, layer) in manifest.layers.iter().enumerate() {
+        info!(
+            "Unpacking layer {}/{} {}",
+            n + 1,
+            manifest.layers.len(),
+            layer.digest
+        );
+        let expected = registry.expected(Some(&layer.digest))?;
+        let url = registry.url("blobs", &layer.digest);
+        let mut reader = Hashing::new(registry.get(&url, "*/*")?.into_reader());
+        if layer.media_type.ends_with("gzip") {
+            unpack_layer(GzDecoder::new(&mut reader), rootfs)?;
+        } else {
+            unpack_layer(&mut reader, rootfs)?;
+        }
+        let Some(expected) = expected else { continue };
+        if let Err(e) = reader.verify(expected, &format!("Layer {}", layer.digest)) {
+            // what's unpacked can't be trusted; ours goes, the user's is
+            // theirs to clear
+            if rootfs == default_rootfs(reference) {
+                let _ = fs::remove_dir_all(rootfs);
+                return Err(e);
+            }
+            return Err(CfsError::Command(format!(
+                "{}, and {} holds what was unpacked of it",
+                e,
+                rootfs.display()
+            )));
+        }
+    }
+
+    fs::create_dir_all(&cache).context(format!("Failed to create {}", cache.display()))?;
+    let json = serde_json::to_vec(&blob.config).expect("image config serializes");
+    fs::write(&config_path, json).context("Failed to save image config")?;
+    fs::write(rootfs.join(".cfs-image"), "").context("Failed to mark image as unpacked")?;
+    Ok(blob.config)
+}
+
+/// Extracts one layer on top of the ones below it. `.wh.<name>` entries delete
+/// `<name>` from lower layers, `.wh..wh..opq` empties its directory of
+/// everything that did not come from this layer.
+fn unpack_layer(reader: impl Read, rootfs: &Path) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(true);
+    archive.set_overwrite(true);
+    // so paths below it compare equal to those whiteouts resolve
+    let rootfs =
+        &fs::canonicalize(rootfs).context(format!("Cannot resolve {}", rootfs.display()))?;
+
+    let mut unpacked = HashSet::new();
+    let entries = archive.entries().context("Failed to read layer")?;
+    for entry in entries {
+        let mut entry = entry.context("Failed to read layer entry")?;
+        let path: PathBuf = entry
+            .path()
+            .context("Invalid path in layer")?
+            .components()
+            .filter(|c| !matches!(c, Component::CurDir))
+            .collect();
+        if path
+            .components()
+            .any(|c| matches!(c, Component::ParentDir | Component::RootDir))
+        {
+            return Err(CfsError::Command(format!(
+                "Layer entry {} escapes the rootfs",
+                path.display()
+            )));
+        }
+
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        if name == OPAQUE_WHITEOUT {
+            if let Some(dir) = whiteout_dir(rootfs, &path)? {
+                remove_lower(&dir, &unpacked)?;
+            }
+        } else if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            if let Some(dir) = whiteout_dir(rootfs, &path)? {
+                remove(&dir.join(hidden))?;
+            }
+        } else {
+            entry
+                .unpack_in(rootfs)
+                .context(format!("Failed to extract {}", path.display()))?;
+            unpacked.insert(rootfs.join(&path));
+        }
+    }
+    Ok(())
+}
+
+/// The directory a whiteout removes from, where it really is: an earlier
+/// entry may have made part of the way a symlink, which must not lead the
+/// removal out of the rootfs. None when there is no such directory, and so
+/// nothing to remove.
+fn whiteout_dir(rootfs: &Path, whiteout: &Path) -> Result<Option<PathBuf>> {
+    let parent = rootfs.join(whiteout.parent().unwrap_or(Path::new("")));
+    let Ok(dir) = fs::canonicalize(&parent) else {
+        return Ok(None);
+    };
+    if !dir.starts_with(rootfs) {
+        return Err(CfsError::Command(format!(
+            "Layer entry {} escapes the rootfs",
+            whiteout.display()
+        )));
+    }
+    Ok(Some(dir))
+}
+
+/// Removes everything below `dir` that was not extracted from the current
+/// layer, descending into directories the current layer itself created.
+fn remove_lower(dir: &Path, unpacked: &HashSet<PathBuf>) -> Result<()> {
+    let Ok(children) = fs::read_dir(dir) else {
+        return Ok(());
+    };
+    for child in children.flatten() {
+        let path = child.path();
+        if !unpacked.contains(&path) {
+            remove(&path)?;
+        } else if child.file_type().is_ok_and(|t| t.is_dir()) {
+            remove_lower(&path, unpacked)?;
+        }
+    }
+    Ok(())
+}
+
+fn remove(path: &Path) -> Result<()> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(_) => return Ok(()),
+    };
+    result.context(format!("Failed to remove {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory for a test, gone with it.
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new(test: &str) -> Dir {
+            let path =
+                std::env::temp_dir().join(format!("cfs-image-{}-{}", test, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Dir(path)
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A layer of empty files, and symlinks for the entries with a target.
+    fn layer(entries: &[(&str, Option<&Path>)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, target) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            header.set_size(0);
+            match target {
+                Some(target) => {
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    builder.append_link(&mut header, path, target).unwrap();
+                }
+                None => builder.append_data(&mut header, path, &[][..]).unwrap(),
+            }
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn whiteouts_remove_from_lower_layers() {
+        let root = Dir::new("whiteouts");
+        let lower = layer(&[("etc/passwd", None), ("etc/group", None), ("opt/a", None)]);
+        unpack_layer(&lower[..], &root.0).unwrap();
+        let upper = layer(&[
+            ("etc/.wh.passwd", None),
+            ("opt/b", None),
+            ("opt/.wh..wh..opq", None),
+            ("gone/.wh.missing", None),
+        ]);
+        unpack_layer(&upper[..], &root.0).unwrap();
+
+        assert!(!root.0.join("etc/passwd").exists());
+        assert!(root.0.join("etc/group").exists());
+        assert!(!root.0.join("opt/a").exists());
+        assert!(root.0.join("opt/b").exists());
+    }
+
+    #[test]
+    fn whiteouts_stay_in_the_rootfs() {
+        let host = Dir::new("host");
+        fs::write(host.0.join("passwd"), "root").unwrap();
+        for whiteout in ["etc/.wh.passwd", "etc/.wh..wh..opq"] {
+            let root = Dir::new("escape");
+            let lower = layer(&[("etc", Some(&host.0))]);
+            unpack_layer(&lower[..], &root.0).unwrap();
+            let upper = layer(&[(whiteout, None)]);
+            assert!(unpack_layer(&upper[..], &root.0).is_err(), "{}", whiteout);
+            assert!(host.0.join("passwd").exists(), "{}", whiteout);
+        }
+    }
+}
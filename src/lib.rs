@@ -0,0 +1,55 @@
+//! Linux containers from scratch: namespaces, a root switch, cgroups and
+//! little else. [`Container`] runs one; the `cfs` binary is a command line
+//! over it.
+//!
+//! Linux only: on other systems the crate is empty.
+
+#![cfg(target_os = "linux")]
+
+pub mod archive;
+pub mod caps;
+pub mod cgroup;
+mod container;
+mod copy;
+mod digest;
+pub mod environ;
+pub mod error;
+mod etc;
+pub mod format;
+pub mod health;
+pub mod image;
+pub mod mount;
+mod names;
+pub mod net;
+pub mod ns;
+pub mod plan;
+mod program;
+pub mod rlimit;
+pub mod rootfs;
+pub mod seccomp;
+mod signals;
+mod stack;
+pub mod state;
+mod syscalls;
+pub mod sysctl;
+mod tty;
+pub mod units;
+pub mod user;
+
+use std::env;
+use std::path::PathBuf;
+
+pub use container::{
+    Container, RestartPolicy, build, commit, copy_in, copy_out, exec, exit_code, export, inspect,
+    pause, resume, stats, stop,
+};
+pub use error::{CfsError, Result};
+
+/// Where cfs keeps images and per-container data: system wide for root, in
+/// the user's home for rootless use.
+pub fn data_dir() -> PathBuf {
+    match env::var("HOME") {
+        Ok(home) if unsafe { libc::geteuid() } != 0 => PathBuf::from(home).join(".local/share/cfs"),
+        _ => PathBuf::from("/var/lib/cfs"),
+    }
+}
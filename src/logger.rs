@@ -0,0 +1,40 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Prints the library's log on stderr: progress as it is, the rest tagged
+/// with its level. Whatever the dependencies log stays out.
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target().starts_with("cfs")
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let tag = match record.level() {
+            Level::Info => return eprintln!("{}", record.args()),
+            Level::Error => "error",
+            Level::Warn => "warning",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        };
+        eprintln!("cfs: {}: {}", tag, record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+/// Progress and up by default, each `verbosity` one level more, down to
+/// every step with two. Quiet leaves just warnings and errors.
+pub fn init(verbosity: usize, quiet: bool) {
+    let level = match (quiet, verbosity) {
+        (true, _) => LevelFilter::Warn,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    log::set_logger(&Logger).expect("the logger is only set once");
+    log::set_max_level(level);
+}
@@ -0,0 +1,1193 @@
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+
+use crate::error::{CfsError, Context, Result, check};
+use crate::plan::Plan;
+
+pub fn cstring(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| CfsError::Usage(format!("Path {} contains a NUL byte", path.display())))
+}
+
+/// Thin wrapper around mount(plan, 2) with an error naming what was mounted where.
+pub fn mount(
+    plan: Plan,
+    source: Option<&Path>,
+    target: &Path,
+    fstype: Option<&str>,
+    flags: libc::c_ulong,
+    data: Option<&str>,
+) -> Result<()> {
+    let what = fstype
+        .map(String::from)
+        .or_else(|| source.map(|s| s.display().to_string()))
+        .unwrap_or_default();
+    let step = if what.is_empty() {
+        format!(
+            "change the mount on {} (flags {:#x})",
+            target.display(),
+            flags
+        )
+    } else {
+        format!(
+            "mount {} on {} (flags {:#x}{})",
+            what,
+            target.display(),
+            flags,
+            data.map(|d| format!(", {}", d)).unwrap_or_default()
+        )
+    };
+    if plan.skip(&step) {
+        return Ok(());
+    }
+    debug!("{}", step);
+    let source_c = source.map(cstring).transpose()?;
+    let target_c = cstring(target)?;
+    let fstype_c = fstype.map(|t| CString::new(t).unwrap());
+    let data_c = data
+        .map(|d| {
+            CString::new(d).map_err(|_| CfsError::Usage("Mount options contain a NUL byte".into()))
+        })
+        .transpose()?;
+
+    let ret = unsafe {
+        libc::mount(
+            source_c.as_ref().map_or(std::ptr::null(), |s| s.as_ptr()),
+            target_c.as_ptr(),
+            fstype_c.as_ref().map_or(std::ptr::null(), |t| t.as_ptr()),
+            flags,
+            data_c
+                .as_ref()
+                .map_or(std::ptr::null(), |d| d.as_ptr() as *const libc::c_void),
+        )
+    };
+    check(
+        ret,
+        &format!("mount of {} on {} failed", what, target.display()),
+    )
+}
+
+pub fn unmount(plan: Plan, target: &Path) -> Result<()> {
+    if plan.skip(format!("unmount {}", target.display())) {
+        return Ok(());
+    }
+    debug!("unmounting {}", target.display());
+    check(
+        unsafe { libc::umount(cstring(target)?.as_ptr()) },
+        &format!("unmount of {} failed", target.display()),
+    )
+}
+
+/// Detaches every mount at or below `path` in the caller's namespace, in
+/// [`teardown_order`].
+pub fn unmount_below(plan: Plan, path: &Path) -> Result<()> {
+    if plan.skip(format!("unmount everything below {}", path.display())) {
+        return Ok(());
+    }
+    // once per entry, so each of several mounts stacked on one target goes
+    let targets: Vec<PathBuf> = mount_points(Path::new("/proc/self/mountinfo"))?
+        .into_iter()
+        .filter(|target| target.starts_with(path))
+        .collect();
+    for target in teardown_order(targets) {
+        debug!("unmounting {}", target.display());
+        check(
+            unsafe { libc::umount2(cstring(&target)?.as_ptr(), libc::MNT_DETACH) },
+            &format!("unmount of {} failed", target.display()),
+        )?;
+    }
+    Ok(())
+}
+
+/// Mount targets in the order they can come off: the deepest first, as a
+/// parent is busy for as long as something is mounted below it, whatever
+/// order they were mounted in, say a volume inside another bound before
+/// it. At the same depth the most recent goes first, so stacked mounts come
+/// off top down.
+fn teardown_order(mut mounts: Vec<PathBuf>) -> Vec<PathBuf> {
+    // stable, mount order stays among equals
+    mounts.sort_by_key(|target| target.components().count());
+    mounts.reverse();
+    mounts
+}
+
+/// The mounts cfs made for a container, to come off in reverse once its
+/// command is done, or when setting it up fails halfway. Dropping the guard
+/// tears them down as well.
+#[derive(Default)]
+pub struct MountGuard {
+    mounts: Vec<PathBuf>,
+}
+
+impl MountGuard {
+    pub fn track(&mut self, target: &Path) {
+        self.mounts.push(target.to_path_buf());
+    }
+
+    /// Tracks whatever is mounted at or below `root` and not yet tracked, in
+    /// mount order. Reading it back from the kernel also catches what a
+    /// mount brings along, such as `/dev/pts` with `/dev`.
+    pub fn track_below(&mut self, plan: Plan, root: &Path) -> Result<()> {
+        // nothing was
+        if plan.dry_run() {
+            return Ok(());
+        }
+        // one entry per mount, so each of those stacked on a target comes
+        // off; the ones tracked already count against its entries
+        let mut tracked = self.mounts.clone();
+        for target in mount_points(Path::new("/proc/self/mountinfo"))? {
+            if !target.starts_with(root) {
+                continue;
+            }
+            match tracked.iter().position(|t| *t == target) {
+                Some(seen) => {
+                    tracked.swap_remove(seen);
+                }
+                None => self.mounts.push(target),
+            }
+        }
+        Ok(())
+    }
+
+    /// Follows the mounts into `root` once it has become `/`. The root
+    /// itself can't come off and is no longer tracked.
+    pub fn rebase(&mut self, root: &Path) {
+        self.mounts = self
+            .mounts
+            .iter()
+            .filter_map(|target| target.strip_prefix(root).ok())
+            .filter(|relative| !relative.as_os_str().is_empty())
+            .map(|relative| Path::new("/").join(relative))
+            .collect();
+    }
+
+    /// Unmounts everything tracked, in [`teardown_order`]. A mount still in
+    /// use is detached instead; one that won't go is logged and left to the
+    /// kernel, which clears it with the mount namespace.
+    pub fn teardown(&mut self) {
+        for target in teardown_order(std::mem::take(&mut self.mounts)) {
+            let Ok(path) = cstring(&target) else { continue };
+            debug!("unmounting {}", target.display());
+            if unsafe { libc::umount(path.as_ptr()) } == 0 {
+                continue;
+            }
+            debug!(
+                "unmount of {} failed: {}, detaching it",
+                target.display(),
+                std::io::Error::last_os_error()
+            );
+            if unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) } != 0 {
+                warn!(
+                    "failed to unmount {}: {}",
+                    target.display(),
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+    }
+}
+
+impl Drop for MountGuard {
+    fn drop(&mut self) {
+        self.teardown();
+    }
+}
+
+/// The mount points a `/proc/<pid>/mountinfo` lists, in mount order and
+/// relative to that process's root.
+pub fn mount_points(mountinfo: &Path) -> Result<Vec<PathBuf>> {
+    let contents =
+        fs::read_to_string(mountinfo).context(format!("Failed to read {}", mountinfo.display()))?;
+    Ok(contents
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(|target| PathBuf::from(unescape(target)))
+        .collect())
+}
+
+/// mountinfo escapes space, tab, newline and backslash as `\ooo`.
+fn unescape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(pos) = rest.find('\\') {
+        out.push_str(&rest[..pos]);
+        match u8::from_str_radix(rest.get(pos + 1..pos + 4).unwrap_or(""), 8) {
+            Ok(byte) => {
+                out.push(byte as char);
+                rest = &rest[pos + 4..];
+            }
+            Err(_) => {
+                out.push('\\');
+                rest = &rest[pos + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+// Our mounts (and pivot_root, which refuses shared mounts) must not propagate
+// back to the host's mount namespace. `Propagation::RSlave` still lets the
+// host's reach us.
+pub fn make_private(plan: Plan, propagation: Propagation) -> Result<()> {
+    propagation.apply(plan, Path::new("/"))
+}
+
+/// Creates a directory to mount on, with any parents, unless it's a dry
+/// run, which only shows the mount.
+fn create_dir(plan: Plan, path: &Path) -> Result<()> {
+    if plan.dry_run() {
+        return Ok(());
+    }
+    fs::create_dir_all(path).context(format!("Failed to create {}", path.display()))
+}
+
+/// Like [`create_dir`], for a file to bind another file onto.
+fn create_file(plan: Plan, path: &Path) -> Result<()> {
+    if plan.dry_run() {
+        return Ok(());
+    }
+    path.parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(path, ""))
+        .context(format!("Failed to create {}", path.display()))
+}
+
+pub fn mount_proc(plan: Plan, target: &Path) -> Result<()> {
+    create_dir(plan, target)?;
+    mount(
+        plan,
+        Some(Path::new("proc")),
+        target,
+        Some("proc"),
+        libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+        None,
+    )
+}
+
+/// Device nodes bound from the host into the container's `/dev`. Binding
+/// instead of mknod works inside a user namespace too.
+const DEVICES: &[&str] = &["null", "zero", "full", "random", "urandom", "tty"];
+
+const DEV_LINKS: &[(&str, &str)] = &[
+    ("fd", "/proc/self/fd"),
+    ("stdin", "/proc/self/fd/0"),
+    ("stdout", "/proc/self/fd/1"),
+    ("stderr", "/proc/self/fd/2"),
+    ("ptmx", "pts/ptmx"),
+];
+
+/// The size of `/dev/shm` unless told otherwise, as Docker has it.
+pub const DEFAULT_SHM_SIZE: u64 = 64 << 20;
+
+/// Replaces whatever `/dev` the rootfs came with by a fresh tmpfs holding
+/// just the basic devices, and a `/dev/shm` of `shm_size` bytes. Needs the
+/// host's /dev, so before the root switch.
+pub fn setup_dev(plan: Plan, root: &Path, shm_size: u64) -> Result<()> {
+    let dev = root.join("dev");
+    create_dir(plan, &dev)?;
+    mount(
+        plan,
+        Some(Path::new("tmpfs")),
+        &dev,
+        Some("tmpfs"),
+        libc::MS_NOSUID | libc::MS_STRICTATIME,
+        Some("mode=755,size=65536k"),
+    )?;
+
+    for device in DEVICES {
+        let target = dev.join(device);
+        create_file(plan, &target)?;
+        mount(
+            plan,
+            Some(&Path::new("/dev").join(device)),
+            &target,
+            None,
+            libc::MS_BIND,
+            None,
+        )?;
+    }
+    for (link, target) in DEV_LINKS {
+        if !plan.skip(format!("link /dev/{} to {}", link, target)) {
+            std::os::unix::fs::symlink(target, dev.join(link))
+                .context(format!("Failed to create /dev/{}", link))?;
+        }
+    }
+    for dir in ["pts", "shm"] {
+        create_dir(plan, &dev.join(dir))?;
+    }
+    // a private instance, so the container sees its own PTYs and none of the host's
+    mount(
+        plan,
+        Some(Path::new("devpts")),
+        &dev.join("pts"),
+        Some("devpts"),
+        libc::MS_NOSUID | libc::MS_NOEXEC,
+        Some("newinstance,ptmxmode=0666,mode=0620"),
+    )?;
+    mount(
+        plan,
+        Some(Path::new("shm")),
+        &dev.join("shm"),
+        Some("tmpfs"),
+        libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+        Some(&format!("mode=1777,size={}", shm_size)),
+    )
+}
+
+/// Mounts sysfs read-only. sysfs takes a user namespace owning the network
+/// namespace, failing that the host's /sys is bound instead.
+pub fn mount_sysfs(plan: Plan, target: &Path) -> Result<()> {
+    create_dir(plan, target)?;
+    let flags = libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+    if mount(
+        plan,
+        Some(Path::new("sysfs")),
+        target,
+        Some("sysfs"),
+        flags,
+        None,
+    )
+    .is_ok()
+    {
+        return Ok(());
+    }
+    // recursive, as a user namespace can't take the host's mounts apart;
+    // for the same reason the remount has to keep the flags they have
+    mount(
+        plan,
+        Some(Path::new("/sys")),
+        target,
+        None,
+        libc::MS_BIND | libc::MS_REC,
+        None,
+    )?;
+    mount(
+        plan,
+        None,
+        target,
+        None,
+        libc::MS_BIND | libc::MS_REMOUNT | flags,
+        None,
+    )
+}
+
+/// Mounts the unified hierarchy, as seen from our cgroup namespace.
+pub fn mount_cgroup2(plan: Plan, target: &Path) -> Result<()> {
+    create_dir(plan, target)?;
+    mount(
+        plan,
+        Some(Path::new("cgroup2")),
+        target,
+        Some("cgroup2"),
+        libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+        None,
+    )
+}
+
+pub fn mount_tmpfs(plan: Plan, target: &Path, options: &str) -> Result<()> {
+    create_dir(plan, target)?;
+    mount(
+        plan,
+        Some(Path::new("tmpfs")),
+        target,
+        Some("tmpfs"),
+        libc::MS_NOSUID | libc::MS_NODEV,
+        Some(options),
+    )
+}
+
+/// Makes the mount at `target` read-only. Without MS_REC, so what is
+/// mounted below it stays writable.
+pub fn remount_readonly(plan: Plan, target: &Path) -> Result<()> {
+    mount(
+        plan,
+        None,
+        target,
+        None,
+        libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY,
+        None,
+    )
+}
+
+/// Masked in every container, as the OCI runtime spec suggests: kernel
+/// memory, keyrings and timer and scheduler dumps that tell about the host,
+/// and its firmware tables.
+pub const DEFAULT_MASKED_PATHS: &[&str] = &[
+    "/proc/acpi",
+    "/proc/kcore",
+    "/proc/keys",
+    "/proc/latency_stats",
+    "/proc/sched_debug",
+    "/proc/scsi",
+    "/proc/timer_list",
+    "/proc/timer_stats",
+    "/sys/firmware",
+];
+
+/// Read-only in every container: what would tune the host's kernel.
+pub const DEFAULT_READONLY_PATHS: &[&str] = &[
+    "/proc/bus",
+    "/proc/fs",
+    "/proc/irq",
+    "/proc/sys",
+    "/proc/sysrq-trigger",
+];
+
+/// Hides `path` below `root`, a file behind /dev/null, a directory behind
+/// an empty read-only tmpfs. Before the root switch, /dev/null is the
+/// host's. What isn't there is left alone.
+pub fn mask(plan: Plan, root: &Path, path: &Path) -> Result<()> {
+    let target = resolve_in_root(root, path)?;
+    match fs::metadata(&target) {
+        Ok(meta) if meta.is_dir() => mount(
+            plan,
+            Some(Path::new("tmpfs")),
+            &target,
+            Some("tmpfs"),
+            libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+            Some("size=0"),
+        ),
+        Ok(_) => mount(
+            plan,
+            Some(Path::new("/dev/null")),
+            &target,
+            None,
+            libc::MS_BIND,
+            None,
+        ),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Binds `path` below `root` onto itself read-only. What isn't there is
+/// left alone.
+pub fn make_readonly(plan: Plan, root: &Path, path: &Path) -> Result<()> {
+    let target = resolve_in_root(root, path)?;
+    if fs::symlink_metadata(&target).is_err() {
+        return Ok(());
+    }
+    mount(
+        plan,
+        Some(&target),
+        &target,
+        None,
+        libc::MS_BIND | libc::MS_REC,
+        None,
+    )?;
+    // in a user namespace the remount has to keep the flags locked on the
+    // mount it was bound from, like /proc's nosuid, nodev and noexec
+    mount(
+        plan,
+        None,
+        &target,
+        None,
+        libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | locked_flags(&target)?,
+        None,
+    )
+}
+
+fn locked_flags(target: &Path) -> Result<libc::c_ulong> {
+    let target_c = cstring(target)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    check(
+        unsafe { libc::statvfs(target_c.as_ptr(), &mut stat) },
+        &format!("statvfs of {} failed", target.display()),
+    )?;
+    Ok([
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ]
+    .iter()
+    .filter(|&&(st, _)| stat.f_flag & st != 0)
+    .fold(0, |flags, &(_, ms)| flags | ms))
+}
+
+/// Makes the mount at `target` and every one below it read-only. Only that
+/// flag changes, so those locked in a user namespace, such as the nosuid of
+/// a mount bound from the host, stay as they are.
+fn make_tree_readonly(plan: Plan, target: &Path) -> Result<()> {
+    if plan.skip(format!(
+        "make {} and the mounts below it read-only",
+        target.display()
+    )) {
+        return Ok(());
+    }
+    debug!("making {} read-only, recursively", target.display());
+    let attr = libc::mount_attr {
+        attr_set: libc::MOUNT_ATTR_RDONLY,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: 0,
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            libc::AT_FDCWD,
+            cstring(target)?.as_ptr(),
+            libc::AT_RECURSIVE,
+            &attr as *const libc::mount_attr,
+            std::mem::size_of::<libc::mount_attr>(),
+        )
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+    let e = std::io::Error::last_os_error();
+    if e.raw_os_error() != Some(libc::ENOSYS) {
+        return Err(e).context(format!("Failed to make {} read-only", target.display()));
+    }
+    // before Linux 5.12: a remount of each, which has to repeat the flags
+    for submount in mount_points(Path::new("/proc/self/mountinfo"))? {
+        if submount.starts_with(target) {
+            mount(
+                plan,
+                None,
+                &submount,
+                None,
+                libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | locked_flags(&submount)?,
+                None,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+pub fn chroot(plan: Plan, path: &Path) -> Result<()> {
+    if plan.skip(format!("chroot to {}", path.display())) {
+        return Ok(());
+    }
+    check(
+        unsafe { libc::chroot(cstring(path)?.as_ptr()) },
+        "chroot failed",
+    )?;
+    std::env::set_current_dir("/").context("chdir failed")
+}
+
+// Unlike chroot, pivot_root swaps the root mount of the whole mount namespace,
+// so once the old root is detached nothing of the host filesystem is reachable.
+pub fn pivot_root(plan: Plan, path: &Path) -> Result<()> {
+    let old_root = path.join("old_root");
+
+    // the new root has to be a mount point, so bind it onto itself
+    mount(
+        plan,
+        Some(path),
+        path,
+        None,
+        libc::MS_BIND | libc::MS_REC,
+        None,
+    )?;
+    if plan.skip(format!("pivot_root to {}", path.display())) {
+        return Ok(());
+    }
+
+    fs::create_dir_all(&old_root).context("Failed to create old_root dir")?;
+
+    check(
+        unsafe {
+            libc::syscall(
+                libc::SYS_pivot_root,
+                cstring(path)?.as_ptr(),
+                cstring(&old_root)?.as_ptr(),
+            )
+        },
+        "pivot_root failed",
+    )?;
+    std::env::set_current_dir("/").context("chdir failed")?;
+
+    check(
+        unsafe { libc::umount2(c"/old_root".as_ptr(), libc::MNT_DETACH) },
+        "unmount of old root failed",
+    )?;
+    fs::remove_dir("/old_root").context("Failed to remove old_root dir")
+}
+
+/// Whether mounts made on the host below a bind mount show up in the
+/// container. Nothing ever propagates the other way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Propagation {
+    #[default]
+    RPrivate,
+    /// Follows the host's mounts, if the source is a shared one there.
+    RSlave,
+}
+
+impl Propagation {
+    pub fn parse(s: &str) -> Result<Propagation> {
+        match s {
+            "rprivate" => Ok(Propagation::RPrivate),
+            "rslave" => Ok(Propagation::RSlave),
+            _ => Err(CfsError::Usage(format!(
+                "Unknown propagation {}, expected rprivate or rslave",
+                s
+            ))),
+        }
+    }
+
+    fn apply(self, plan: Plan, target: &Path) -> Result<()> {
+        let flag = match self {
+            Propagation::RPrivate => libc::MS_PRIVATE,
+            Propagation::RSlave => libc::MS_SLAVE,
+        };
+        mount(plan, None, target, None, libc::MS_REC | flag, None)
+    }
+}
+
+/// A `-v host:container[:ro]` bind mount.
+pub struct Volume {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub readonly: bool,
+    pub propagation: Propagation,
+}
+
+impl Volume {
+    pub fn parse(s: &str) -> Result<Volume> {
+        let invalid = || {
+            CfsError::Usage(format!(
+                "Invalid volume {}, expected /host/path:/container/path[:ro]",
+                s
+            ))
+        };
+        let mut parts = s.split(':');
+        let source = parts.next().filter(|p| !p.is_empty()).ok_or_else(invalid)?;
+        let target = parts
+            .next()
+            .filter(|p| p.starts_with('/'))
+            .ok_or_else(invalid)?;
+        let readonly = match parts.next() {
+            None | Some("rw") => false,
+            Some("ro") => true,
+            Some(_) => return Err(invalid()),
+        };
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+
+        Ok(Volume {
+            source: existing_source(source)?,
+            target: PathBuf::from(target),
+            readonly,
+            propagation: Propagation::default(),
+        })
+    }
+
+    /// Binds the source onto its target below `root`. This has to happen
+    /// while the host filesystem is still reachable, before the root switch.
+    pub fn mount(&self, plan: Plan, root: &Path) -> Result<()> {
+        let target = resolve_in_root(root, &self.target)?;
+        if !target.exists() {
+            if self.source.is_dir() {
+                create_dir(plan, &target)?;
+            } else {
+                create_file(plan, &target)?;
+            }
+        }
+
+        mount(
+            plan,
+            Some(&self.source),
+            &target,
+            None,
+            libc::MS_BIND | libc::MS_REC,
+            None,
+        )?;
+        // a bind copies the propagation of the tree it comes from, which is
+        // rslave if any volume asked for it
+        self.propagation.apply(plan, &target)?;
+        // the kernel ignores MS_RDONLY on the initial bind, it takes a
+        // change of the mounts the recursive bind made
+        if self.readonly {
+            make_tree_readonly(plan, &target)?;
+        }
+        Ok(())
+    }
+}
+
+fn existing_source(source: &str) -> Result<PathBuf> {
+    let source = std::path::absolute(source).context(format!("Cannot resolve {}", source))?;
+    if !source.exists() {
+        return Err(CfsError::Usage(format!(
+            "Volume source {} does not exist",
+            source.display()
+        )));
+    }
+    Ok(source)
+}
+
+/// A `--tmpfs /path[:size]` mount.
+pub struct Tmpfs {
+    pub target: PathBuf,
+    pub size: u64,
+    pub readonly: bool,
+}
+
+impl Tmpfs {
+    const DEFAULT_SIZE: u64 = 64 << 20;
+
+    pub fn parse(s: &str) -> Result<Tmpfs> {
+        let (target, size) = match s.split_once(':') {
+            Some((target, size)) => (target, crate::units::parse_size(size)?),
+            None => (s, Tmpfs::DEFAULT_SIZE),
+        };
+        if !target.starts_with('/') {
+            return Err(CfsError::Usage(format!(
+                "Invalid tmpfs {}, expected /container/path[:size]",
+                s
+            )));
+        }
+        Ok(Tmpfs {
+            target: PathBuf::from(target),
+            size,
+            readonly: false,
+        })
+    }
+
+    pub fn mount(&self, plan: Plan, root: &Path) -> Result<()> {
+        let target = resolve_in_root(root, &self.target)?;
+        let options = format!("size={}", self.size);
+        mount_tmpfs(plan, &target, &options)?;
+        if self.readonly {
+            mount(
+                plan,
+                None,
+                &target,
+                None,
+                libc::MS_REMOUNT | libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV,
+                Some(&options),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A `--mount type=bind|tmpfs,key=value,...` mount, the long form of `-v`
+/// and `--tmpfs`:
+///
+/// - `type`: `bind`, the default, or `tmpfs`
+/// - `source` or `src`: the host path, binds only
+/// - `target`, `destination` or `dst`: the path in the container
+/// - `ro` or `readonly`, optionally `=true` or `=false`
+/// - `propagation`: `rprivate`, the default, or `rslave`, binds only
+/// - `size`: e.g. `256M`, tmpfs only
+pub enum MountSpec {
+    Bind(Volume),
+    Tmpfs(Tmpfs),
+}
+
+impl MountSpec {
+    pub fn parse(s: &str) -> Result<MountSpec> {
+        let invalid = |why: &str| CfsError::Usage(format!("Invalid mount {}: {}", s, why));
+        let (mut kind, mut source, mut target) = ("bind", None, None);
+        let (mut readonly, mut propagation, mut size) = (false, None, None);
+        for field in s.split(',') {
+            let (key, value) = match field.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (field, None),
+            };
+            match (key, value) {
+                ("type", Some(value)) => kind = value,
+                ("source" | "src", Some(value)) => source = Some(value),
+                ("target" | "destination" | "dst", Some(value)) => target = Some(value),
+                ("ro" | "readonly", None | Some("true" | "1")) => readonly = true,
+                ("ro" | "readonly", Some("false" | "0")) => readonly = false,
+                ("propagation", Some(value)) => propagation = Some(Propagation::parse(value)?),
+                ("size", Some(value)) => size = Some(crate::units::parse_size(value)?),
+                _ => return Err(invalid(&format!("unknown option {}", field))),
+            }
+        }
+
+        let target = target
+            .filter(|t| t.starts_with('/'))
+            .ok_or_else(|| invalid("needs an absolute target"))?;
+        match kind {
+            "bind" => {
+                if size.is_some() {
+                    return Err(invalid("size is for tmpfs mounts"));
+                }
+                let source = source.ok_or_else(|| invalid("a bind mount needs a source"))?;
+                Ok(MountSpec::Bind(Volume {
+                    source: existing_source(source)?,
+                    target: PathBuf::from(target),
+                    readonly,
+                    propagation: propagation.unwrap_or_default(),
+                }))
+            }
+            "tmpfs" => {
+                if source.is_some() || propagation.is_some() {
+                    return Err(invalid("a tmpfs takes neither source nor propagation"));
+                }
+                Ok(MountSpec::Tmpfs(Tmpfs {
+                    target: PathBuf::from(target),
+                    size: size.unwrap_or(Tmpfs::DEFAULT_SIZE),
+                    readonly,
+                }))
+            }
+            _ => Err(invalid(&format!(
+                "unknown type {}, expected bind or tmpfs",
+                kind
+            ))),
+        }
+    }
+}
+
+/// A `--device /host/node[:/container/node][:rwm]` device node.
+pub struct Device {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    /// `c` or `b`, as in a devices cgroup rule.
+    pub kind: char,
+    pub major: u32,
+    pub minor: u32,
+    /// Some of `rwm`: read, write, mknod.
+    pub access: String,
+}
+
+impl Device {
+    pub fn parse(s: &str) -> Result<Device> {
+        let invalid = || {
+            CfsError::Usage(format!(
+                "Invalid device {}, expected /host/node[:/container/node][:rwm]",
+                s
+            ))
+        };
+        let mut parts = s.split(':');
+        let source = PathBuf::from(parts.next().filter(|p| !p.is_empty()).ok_or_else(invalid)?);
+        let mut target = source.clone();
+        let mut access = String::from("rwm");
+        match parts.next() {
+            Some(path) if path.starts_with('/') => {
+                target = PathBuf::from(path);
+                if let Some(perms) = parts.next() {
+                    access = perms.to_string();
+                }
+            }
+            Some(perms) => access = perms.to_string(),
+            None => {}
+        }
+        if parts.next().is_some() || access.is_empty() || !access.chars().all(|c| "rwm".contains(c))
+        {
+            return Err(invalid());
+        }
+
+        let meta =
+            fs::metadata(&source).context(format!("Cannot access device {}", source.display()))?;
+        let kind = match meta.file_type() {
+            t if t.is_char_device() => 'c',
+            t if t.is_block_device() => 'b',
+            _ => {
+                return Err(CfsError::Usage(format!(
+                    "{} is not a device node",
+                    source.display()
+                )));
+            }
+        };
+        Ok(Device {
+            source,
+            target,
+            kind,
+            major: libc::major(meta.rdev()),
+            minor: libc::minor(meta.rdev()),
+            access,
+        })
+    }
+
+    /// The rule that lets the container use the device.
+    pub fn rule(&self) -> String {
+        format!(
+            "{} {}:{} {}",
+            self.kind, self.major, self.minor, self.access
+        )
+    }
+
+    /// Binds the node below `root`, after [`setup_dev`] for one in /dev.
+    pub fn mount(&self, plan: Plan, root: &Path) -> Result<()> {
+        Volume {
+            source: self.source.clone(),
+            target: self.target.clone(),
+            readonly: false,
+            propagation: Propagation::default(),
+        }
+        .mount(plan, root)
+    }
+}
+
+/// Joins a container path onto `root`. The root is still resolved against
+/// the host at this point, so a symlink inside the rootfs could point a mount
+/// anywhere on the host; those are refused.
+pub(crate) fn resolve_in_root(root: &Path, path: &Path) -> Result<PathBuf> {
+    let mut resolved = root.to_path_buf();
+    for component in path.components() {
+        match component {
+            std::path::Component::Normal(part) => resolved.push(part),
+            std::path::Component::ParentDir => {
+                return Err(CfsError::Usage(format!(
+                    "Container path {} must not contain ..",
+                    path.display()
+                )));
+            }
+            _ => continue,
+        }
+        if resolved.is_symlink() {
+            return Err(CfsError::Usage(format!(
+                "Container path {} goes through a symlink",
+                path.display()
+            )));
+        }
+    }
+    Ok(resolved)
+}
+
+/// Per-container copy-on-write layer over a shared, never modified rootfs.
+/// Lives in `<data dir>/containers/<id>` as `upper`, `work` and `merged`.
+pub struct Overlay {
+    dir: PathBuf,
+}
+
+impl Overlay {
+    pub fn new(id: &str) -> Overlay {
+        Overlay {
+            dir: crate::data_dir().join("containers").join(id),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn merged(&self) -> PathBuf {
+        self.dir.join("merged")
+    }
+
+    pub fn create(&self, plan: Plan) -> Result<()> {
+        for sub in ["upper", "work", "merged"] {
+            create_dir(plan, &self.dir.join(sub))?;
+        }
+        Ok(())
+    }
+
+    /// Mounts the overlay of `lower` on `merged`, in the caller's namespace.
+    pub fn mount(&self, plan: Plan, lower: &Path) -> Result<()> {
+        let options = format!(
+            "lowerdir={},upperdir={},workdir={}",
+            lower.display(),
+            self.dir.join("upper").display(),
+            self.dir.join("work").display()
+        );
+        mount(
+            plan,
+            Some(Path::new("overlay")),
+            &self.merged(),
+            Some("overlay"),
+            0,
+            Some(&options),
+        )
+    }
+
+    /// Mounts the container's changes over `lower` on `merged`, read-only.
+    /// Stacked as the topmost lower layer, the upper one needs no workdir.
+    pub fn mount_readonly(&self, plan: Plan, lower: &Path) -> Result<()> {
+        let options = format!(
+            "lowerdir={}:{}",
+            self.dir.join("upper").display(),
+            lower.display()
+        );
+        mount(
+            plan,
+            Some(Path::new("overlay")),
+            &self.merged(),
+            Some("overlay"),
+            libc::MS_RDONLY,
+            Some(&options),
+        )
+    }
+
+    /// Throws the upper layer, and with it every change the container made,
+    /// away. Whatever is still mounted there comes off first, so nothing
+    /// below a mount point is taken along.
+    pub fn remove(&self, plan: Plan) -> Result<()> {
+        if plan.skip(format!("remove {}", self.dir.display())) {
+            return Ok(());
+        }
+        unmount_below(plan, &self.dir)?;
+        fs::remove_dir_all(&self.dir).context(format!("Failed to remove {}", self.dir.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn teardown_takes_children_before_parents() {
+        // in mount order: the overlay, a volume inside one bound after it,
+        // and a second mount stacked on /data
+        let mounts = paths(&[
+            "/merged",
+            "/merged/proc",
+            "/merged/data/nested",
+            "/merged/dev",
+            "/merged/dev/pts",
+            "/merged/data",
+            "/merged/data",
+        ]);
+        assert_eq!(
+            teardown_order(mounts),
+            paths(&[
+                "/merged/dev/pts",
+                "/merged/data/nested",
+                "/merged/data",
+                "/merged/data",
+                "/merged/dev",
+                "/merged/proc",
+                "/merged",
+            ])
+        );
+    }
+
+    /// What a parsed `--mount` comes to, to compare in a table.
+    fn summary(spec: MountSpec) -> String {
+        match spec {
+            MountSpec::Bind(volume) => format!(
+                "bind {} {} ro={} {:?}",
+                volume.source.display(),
+                volume.target.display(),
+                volume.readonly,
+                volume.propagation
+            ),
+            MountSpec::Tmpfs(tmpfs) => format!(
+                "tmpfs {} {} ro={}",
+                tmpfs.target.display(),
+                tmpfs.size,
+                tmpfs.readonly
+            ),
+        }
+    }
+
+    #[test]
+    fn mount_specs_take_aliases_and_options() {
+        for (spec, parsed) in [
+            ("src=/tmp,dst=/data", "bind /tmp /data ro=false RPrivate"),
+            (
+                "type=bind,source=/tmp,target=/data",
+                "bind /tmp /data ro=false RPrivate",
+            ),
+            (
+                "source=/tmp,destination=/data,ro",
+                "bind /tmp /data ro=true RPrivate",
+            ),
+            (
+                "src=/tmp,dst=/data,readonly",
+                "bind /tmp /data ro=true RPrivate",
+            ),
+            (
+                "src=/tmp,dst=/data,ro=true",
+                "bind /tmp /data ro=true RPrivate",
+            ),
+            (
+                "src=/tmp,dst=/data,ro=1",
+                "bind /tmp /data ro=true RPrivate",
+            ),
+            (
+                "src=/tmp,dst=/data,ro,ro=false",
+                "bind /tmp /data ro=false RPrivate",
+            ),
+            (
+                "src=/tmp,dst=/data,ro=0",
+                "bind /tmp /data ro=false RPrivate",
+            ),
+            (
+                "src=/tmp,dst=/data,propagation=rslave",
+                "bind /tmp /data ro=false RSlave",
+            ),
+            (
+                "type=tmpfs,dst=/scratch",
+                "tmpfs /scratch 67108864 ro=false",
+            ),
+            (
+                "type=tmpfs,target=/scratch,size=1M,ro",
+                "tmpfs /scratch 1048576 ro=true",
+            ),
+        ] {
+            let summarized = MountSpec::parse(spec).map(summary);
+            assert_eq!(summarized.ok().as_deref(), Some(parsed), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn bad_mount_specs_say_why() {
+        for (spec, error) in [
+            (
+                "src=/tmp",
+                "Invalid mount src=/tmp: needs an absolute target",
+            ),
+            (
+                "src=/tmp,dst=data",
+                "Invalid mount src=/tmp,dst=data: needs an absolute target",
+            ),
+            (
+                "src=/tmp,dst=/data,bogus",
+                "Invalid mount src=/tmp,dst=/data,bogus: unknown option bogus",
+            ),
+            (
+                "src=/tmp,dst=/data,ro=yes",
+                "Invalid mount src=/tmp,dst=/data,ro=yes: unknown option ro=yes",
+            ),
+            (
+                "src=/tmp,dst",
+                "Invalid mount src=/tmp,dst: unknown option dst",
+            ),
+            (
+                "type=volume,src=/tmp,dst=/data",
+                "Invalid mount type=volume,src=/tmp,dst=/data: unknown type volume, expected bind or tmpfs",
+            ),
+            (
+                "dst=/data",
+                "Invalid mount dst=/data: a bind mount needs a source",
+            ),
+            (
+                "src=/tmp,dst=/data,size=1M",
+                "Invalid mount src=/tmp,dst=/data,size=1M: size is for tmpfs mounts",
+            ),
+            (
+                "type=tmpfs,src=/tmp,dst=/data",
+                "Invalid mount type=tmpfs,src=/tmp,dst=/data: a tmpfs takes neither source nor propagation",
+            ),
+            (
+                "type=tmpfs,dst=/data,propagation=rslave",
+                "Invalid mount type=tmpfs,dst=/data,propagation=rslave: a tmpfs takes neither source nor propagation",
+            ),
+            (
+                "src=/tmp,dst=/data,propagation=shared",
+                "Unknown propagation shared, expected rprivate or rslave",
+            ),
+            (
+                "type=tmpfs,dst=/data,size=lots",
+                "Invalid size lots, expected e.g. 512K, 256M, 1G",
+            ),
+            (
+                "src=/nonexistent/cfs,dst=/data",
+                "Volume source /nonexistent/cfs does not exist",
+            ),
+        ] {
+            match MountSpec::parse(spec) {
+                Err(CfsError::Usage(message)) => assert_eq!(message, error, "{}", spec),
+                _ => panic!("{} should not parse", spec),
+            }
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Readable names for containers run without `--name`, `brave_turing`.
+
+use crate::error::{Result, check};
+use crate::state::State;
+
+const ADJECTIVES: &[&str] = &[
+    "agile", "bold", "brave", "bright", "busy", "calm", "clever", "cool", "crisp", "curious",
+    "daring", "eager", "fair", "fancy", "fierce", "focused", "gentle", "happy", "hardy", "humble",
+    "jolly", "keen", "kind", "lively", "lucid", "merry", "modest", "nimble", "noble", "patient",
+    "plucky", "proud", "quick", "quiet", "rapid", "sharp", "shiny", "silent", "sleepy", "snappy",
+    "steady", "sturdy", "swift", "tender", "tidy", "upbeat", "vivid", "wise", "witty", "zesty",
+];
+
+const SURNAMES: &[&str] = &[
+    "babbage",
+    "bardeen",
+    "bohr",
+    "boole",
+    "cerf",
+    "curie",
+    "darwin",
+    "dijkstra",
+    "einstein",
+    "euclid",
+    "euler",
+    "faraday",
+    "fermat",
+    "fermi",
+    "feynman",
+    "galileo",
+    "gauss",
+    "goodall",
+    "hamilton",
+    "hawking",
+    "hopper",
+    "hypatia",
+    "kepler",
+    "knuth",
+    "lamarr",
+    "lamport",
+    "lovelace",
+    "maxwell",
+    "meitner",
+    "mendel",
+    "mirzakhani",
+    "newton",
+    "noether",
+    "pascal",
+    "pasteur",
+    "planck",
+    "ramanujan",
+    "ritchie",
+    "shannon",
+    "sinoussi",
+    "stallman",
+    "tesla",
+    "thompson",
+    "torvalds",
+    "turing",
+    "volta",
+    "wilson",
+    "wozniak",
+    "wright",
+    "yalow",
+];
+
+/// A name no running container has. After a few collisions the last pick
+/// gets a number, so this always ends.
+pub(crate) fn generate() -> Result<String> {
+    let taken = |name: &str| State::load(name).is_ok_and(|state| state.is_running());
+    let mut name = String::new();
+    for _ in 0..8 {
+        name = pick()?;
+        if !taken(&name) {
+            return Ok(name);
+        }
+    }
+    let base = name;
+    Ok((2..)
+        .map(|n| format!("{}_{}", base, n))
+        .find(|name| !taken(name))
+        .expect("the numbers run out after the containers do"))
+}
+
+fn pick() -> Result<String> {
+    let mut bytes = [0u8; 4];
+    check(
+        unsafe { libc::getrandom(bytes.as_mut_ptr() as *mut libc::c_void, bytes.len(), 0) },
+        "getrandom failed",
+    )?;
+    let adjective =
+        ADJECTIVES[u16::from_le_bytes([bytes[0], bytes[1]]) as usize % ADJECTIVES.len()];
+    let surname = SURNAMES[u16::from_le_bytes([bytes[2], bytes[3]]) as usize % SURNAMES.len()];
+    Ok(format!("{}_{}", adjective, surname))
+}
@@ -0,0 +1,436 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::net::Ipv4Addr;
+use std::os::fd::AsRawFd;
+use std::process::Command;
+
+use log::{debug, warn};
+
+use crate::error::{CfsError, Context, Result, check};
+use crate::plan::Plan;
+
+pub const BRIDGE: &str = "cfs0";
+pub const DEFAULT_SUBNET: &str = "10.88.0.0/24";
+
+#[derive(Clone, PartialEq)]
+pub enum NetMode {
+    /// Own network namespace with only loopback.
+    None,
+    /// Own network namespace attached to the `cfs0` host bridge via a veth pair.
+    Bridge,
+    /// The host's network namespace, interfaces and all.
+    Host,
+    /// The network namespace of the running container of that name.
+    Container(String),
+}
+
+impl NetMode {
+    pub fn parse(s: &str) -> Result<NetMode> {
+        match s {
+            "none" => Ok(NetMode::None),
+            "bridge" => Ok(NetMode::Bridge),
+            "host" => Ok(NetMode::Host),
+            _ => match s.strip_prefix("container:") {
+                Some(name) if !name.is_empty() => Ok(NetMode::Container(name.to_string())),
+                _ => Err(CfsError::Usage(format!(
+                    "Unknown network mode {}, expected none, bridge, host or container:<name>",
+                    s
+                ))),
+            },
+        }
+    }
+
+    /// Whether the container gets a network namespace created for it.
+    pub fn is_private(&self) -> bool {
+        matches!(self, NetMode::None | NetMode::Bridge)
+    }
+}
+
+/// Opens the network namespace of `pid`, for the child to join.
+pub(crate) fn namespace_of(pid: libc::pid_t) -> Result<File> {
+    let path = format!("/proc/{}/ns/net", pid);
+    File::open(&path).context(format!("Failed to open {}", path))
+}
+
+/// Moves the caller into the network namespace `ns`.
+pub(crate) fn join(plan: Plan, ns: &File) -> Result<()> {
+    if plan.skip("join the other container's network namespace") {
+        return Ok(());
+    }
+    check(
+        unsafe { libc::setns(ns.as_raw_fd(), libc::CLONE_NEWNET) },
+        "setns into the network namespace failed",
+    )
+}
+
+pub struct Subnet {
+    network: u32,
+    prefix: u8,
+}
+
+impl Subnet {
+    pub fn parse(s: &str) -> Result<Subnet> {
+        let invalid = || CfsError::Usage(format!("Invalid subnet {}, expected a.b.c.d/len", s));
+        let (addr, prefix) = s.split_once('/').ok_or_else(invalid)?;
+        let addr: Ipv4Addr = addr.parse().map_err(|_| invalid())?;
+        let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+        if !(8..=30).contains(&prefix) {
+            return Err(invalid());
+        }
+        let mask = u32::MAX << (32 - prefix);
+        Ok(Subnet {
+            network: u32::from(addr) & mask,
+            prefix,
+        })
+    }
+
+    /// The bridge always takes the first address of the subnet.
+    pub fn gateway(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.network + 1)
+    }
+
+    /// Picks a container address from the rest of the subnet, skipping the
+    /// gateway, the broadcast address and those `taken`. The search starts
+    /// from one derived from `id`, so addresses don't all pile up at the
+    /// bottom.
+    pub fn host(&self, id: u32, taken: &[Ipv4Addr]) -> Result<Ipv4Addr> {
+        let usable = (1u32 << (32 - self.prefix)) - 3;
+        (0..usable)
+            .map(|n| Ipv4Addr::from(self.network + 2 + (id + n) % usable))
+            .find(|address| !taken.contains(address))
+            .ok_or_else(|| CfsError::Usage(format!("No address left in subnet {}", self)))
+    }
+
+    /// Whether `address` can go to a container: in the subnet, and neither
+    /// the network, the gateway nor the broadcast address.
+    pub fn is_host(&self, address: Ipv4Addr) -> bool {
+        let first = self.network + 2;
+        let last = self.network + (1u32 << (32 - self.prefix)) - 2;
+        (first..=last).contains(&u32::from(address))
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", Ipv4Addr::from(self.network), self.prefix)
+    }
+}
+
+/// `--ip 10.88.0.5[/24]`: a fixed address for the container, from the
+/// bridge's subnet. The prefix, if given, has to be the subnet's.
+#[derive(Clone, Copy)]
+pub struct StaticIp {
+    pub address: Ipv4Addr,
+    pub prefix: Option<u8>,
+}
+
+impl StaticIp {
+    pub fn parse(s: &str) -> Result<StaticIp> {
+        let invalid = || CfsError::Usage(format!("Invalid address {}, expected a.b.c.d[/len]", s));
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix.parse().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        Ok(StaticIp {
+            address: address.parse().map_err(|_| invalid())?,
+            prefix,
+        })
+    }
+}
+
+/// A hardware address, `02:42:ac:11:00:02`.
+#[derive(Clone, Copy)]
+pub struct MacAddr([u8; 6]);
+
+impl MacAddr {
+    /// Takes unicast addresses only, an interface can't have any other.
+    pub fn parse(s: &str) -> Result<MacAddr> {
+        let invalid = || {
+            CfsError::Usage(format!(
+                "Invalid MAC address {}, expected six hex bytes as in 02:42:ac:11:00:02",
+                s
+            ))
+        };
+        let mut bytes = [0u8; 6];
+        let mut parts = s.split(':');
+        for byte in &mut bytes {
+            let part = parts.next().filter(|p| p.len() == 2).ok_or_else(invalid)?;
+            *byte = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+        }
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        if bytes[0] & 1 != 0 || bytes == [0; 6] {
+            return Err(CfsError::Usage(format!(
+                "MAC address {} is not a unicast address",
+                s
+            )));
+        }
+        Ok(MacAddr(bytes))
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hex: Vec<String> = self.0.iter().map(|b| format!("{:02x}", b)).collect();
+        write!(f, "{}", hex.join(":"))
+    }
+}
+
+/// Both ends of the veth pair of one container, plus the addressing the
+/// container side gets.
+pub struct Veth {
+    pub host: String,
+    pub peer: String,
+    pub address: Ipv4Addr,
+    pub mac: Option<MacAddr>,
+    /// Of both ends, the kernel's 1500 if None.
+    pub mtu: Option<u32>,
+    pub gateway: Ipv4Addr,
+    pub prefix: u8,
+}
+
+impl Veth {
+    /// Interface names are capped at 15 bytes, so they are derived from a
+    /// short numeric id (the supervising pid) rather than anything user given.
+    pub fn new(
+        subnet: &Subnet,
+        id: u32,
+        address: Ipv4Addr,
+        mac: Option<MacAddr>,
+        mtu: Option<u32>,
+    ) -> Veth {
+        Veth {
+            host: format!("cfs{}h", id),
+            peer: format!("cfs{}c", id),
+            address,
+            mac,
+            mtu,
+            gateway: subnet.gateway(),
+            prefix: subnet.prefix,
+        }
+    }
+}
+
+/// `-p 8080:80/udp`: a host port forwarded to one of the container's.
+#[derive(Clone, Copy)]
+pub struct PortMapping {
+    pub host: u16,
+    pub container: u16,
+    pub protocol: Protocol,
+}
+
+#[derive(Clone, Copy)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl PortMapping {
+    /// Parses `<host>:<container>[/tcp|/udp]`, TCP by default.
+    pub fn parse(s: &str) -> Result<PortMapping> {
+        let invalid = || {
+            CfsError::Usage(format!(
+                "Invalid port mapping {}, expected host:container[/tcp|udp]",
+                s
+            ))
+        };
+        let (ports, protocol) = match s.split_once('/') {
+            Some((ports, "tcp")) => (ports, Protocol::Tcp),
+            Some((ports, "udp")) => (ports, Protocol::Udp),
+            Some(_) => return Err(invalid()),
+            None => (s, Protocol::Tcp),
+        };
+        let (host, container) = ports.split_once(':').ok_or_else(invalid)?;
+        let port = |p: &str| {
+            p.parse::<u16>()
+                .ok()
+                .filter(|&p| p != 0)
+                .ok_or_else(invalid)
+        };
+        Ok(PortMapping {
+            host: port(host)?,
+            container: port(container)?,
+            protocol,
+        })
+    }
+
+    fn protocol(&self) -> &'static str {
+        match self.protocol {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+/// The iptables rules forwarding host ports to a container, taken out
+/// again with `remove`.
+#[derive(Default)]
+pub struct Forwarding {
+    plan: Plan,
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    table: &'static str,
+    chain: &'static str,
+    args: Vec<String>,
+}
+
+impl Forwarding {
+    /// DNATs each host port to `address`: packets from outside through
+    /// PREROUTING, the host's own through OUTPUT, and lets them through a
+    /// FORWARD chain that may drop by default.
+    pub fn add(plan: Plan, ports: &[PortMapping], address: Ipv4Addr) -> Result<Forwarding> {
+        let mut forwarding = Forwarding {
+            plan,
+            rules: Vec::new(),
+        };
+        if ports.is_empty() {
+            return Ok(forwarding);
+        }
+        enable_ip_forward(plan)?;
+        for port in ports {
+            let protocol = port.protocol();
+            let dnat = format!(
+                "-m addrtype --dst-type LOCAL -p {} --dport {} -j DNAT --to-destination {}:{}",
+                protocol, port.host, address, port.container
+            );
+            let accept = format!(
+                "-d {} -p {} --dport {} -j ACCEPT",
+                address, protocol, port.container
+            );
+            for (table, chain, args) in [
+                ("nat", "PREROUTING", &dnat),
+                ("nat", "OUTPUT", &dnat),
+                ("filter", "FORWARD", &accept),
+            ] {
+                let rule = Rule {
+                    table,
+                    chain,
+                    args: args.split(' ').map(String::from).collect(),
+                };
+                if let Err(e) = rule.apply(plan, "-I") {
+                    forwarding.remove();
+                    return Err(e);
+                }
+                forwarding.rules.push(rule);
+            }
+        }
+        Ok(forwarding)
+    }
+
+    /// Deletes the rules, newest first. A rule that won't go is left
+    /// behind with a warning rather than failing the rest.
+    pub fn remove(&mut self) {
+        while let Some(rule) = self.rules.pop() {
+            if let Err(e) = rule.apply(self.plan, "-D") {
+                warn!("{}", e);
+            }
+        }
+    }
+}
+
+impl Rule {
+    /// Inserts the rule with `-I`, deletes it with `-D`.
+    fn apply(&self, plan: Plan, command: &str) -> Result<()> {
+        let mut args = vec!["-t", self.table, command, self.chain];
+        args.extend(self.args.iter().map(String::as_str));
+        run(plan, "iptables", &args)
+    }
+}
+
+// DNAT'ed packets are routed on to the bridge, which takes forwarding
+fn enable_ip_forward(plan: Plan) -> Result<()> {
+    let path = "/proc/sys/net/ipv4/ip_forward";
+    if fs::read_to_string(path).is_ok_and(|value| value.trim() == "1") {
+        return Ok(());
+    }
+    if plan.skip(format!("write 1 to {}", path)) {
+        return Ok(());
+    }
+    debug!("enabling IPv4 forwarding");
+    fs::write(path, "1").context("Failed to enable IPv4 forwarding")
+}
+
+fn ip(plan: Plan, args: &[&str]) -> Result<()> {
+    run(plan, "ip", args)
+}
+
+fn run(plan: Plan, program: &str, args: &[&str]) -> Result<()> {
+    if plan.skip(format!("run {} {}", program, args.join(" "))) {
+        return Ok(());
+    }
+    debug!("running {} {}", program, args.join(" "));
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .context(format!("failed to run {}", program))?;
+    if !status.success() {
+        return Err(CfsError::Command(format!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            status
+        )));
+    }
+    Ok(())
+}
+
+fn bridge_exists() -> bool {
+    std::path::Path::new("/sys/class/net").join(BRIDGE).exists()
+}
+
+/// Runs in the parent once the child exists: makes sure the host bridge is up
+/// and moves the container end of a fresh veth pair into the child's namespace.
+/// The bridge follows the smallest MTU among its ports by itself.
+pub fn create_veth(plan: Plan, veth: &Veth, pid: libc::pid_t) -> Result<()> {
+    if !bridge_exists() {
+        let gateway = format!("{}/{}", veth.gateway, veth.prefix);
+        ip(plan, &["link", "add", BRIDGE, "type", "bridge"])?;
+        ip(plan, &["addr", "add", &gateway, "dev", BRIDGE])?;
+        ip(plan, &["link", "set", BRIDGE, "up"])?;
+    }
+
+    ip(
+        plan,
+        &[
+            "link", "add", &veth.host, "type", "veth", "peer", "name", &veth.peer,
+        ],
+    )?;
+    if let Some(mtu) = veth.mtu {
+        let mtu = mtu.to_string();
+        ip(plan, &["link", "set", &veth.host, "mtu", &mtu])?;
+        ip(plan, &["link", "set", &veth.peer, "mtu", &mtu])?;
+    }
+    ip(plan, &["link", "set", &veth.host, "master", BRIDGE, "up"])?;
+    ip(
+        plan,
+        &["link", "set", &veth.peer, "netns", &pid.to_string()],
+    )
+}
+
+/// Runs in the child, inside the new network namespace: brings up loopback
+/// and, in bridge mode, configures the container end of the veth as `eth0`.
+pub fn configure(plan: Plan, veth: Option<&Veth>) -> Result<()> {
+    ip(plan, &["link", "set", "lo", "up"])?;
+
+    if let Some(veth) = veth {
+        let address = format!("{}/{}", veth.address, veth.prefix);
+        ip(plan, &["link", "set", &veth.peer, "name", "eth0"])?;
+        if let Some(mac) = veth.mac {
+            ip(plan, &["link", "set", "eth0", "address", &mac.to_string()])?;
+        }
+        ip(plan, &["addr", "add", &address, "dev", "eth0"])?;
+        ip(plan, &["link", "set", "eth0", "up"])?;
+        ip(
+            plan,
+            &["route", "add", "default", "via", &veth.gateway.to_string()],
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::error::{CfsError, Context, Result, check};
+use crate::plan::Plan;
+
+/// Namespaces `exec` joins, in order. The user namespace goes first, it is
+/// what grants the privileges to enter the others; mnt goes last, once it's
+/// joined /proc may no longer show the host.
+const NAMESPACES: &[(&str, libc::c_int)] = &[
+    ("user", libc::CLONE_NEWUSER),
+    ("uts", libc::CLONE_NEWUTS),
+    ("ipc", libc::CLONE_NEWIPC),
+    ("cgroup", libc::CLONE_NEWCGROUP),
+    ("net", libc::CLONE_NEWNET),
+    ("pid", libc::CLONE_NEWPID),
+    // PID 1 stays outside, only the command lives in the time namespace
+    ("time_for_children", libc::CLONE_NEWTIME),
+    ("mnt", libc::CLONE_NEWNS),
+];
+
+/// The namespace types among the `CLONE_NEW*` bits in `flags`.
+pub(crate) fn names(flags: libc::c_int) -> Vec<&'static str> {
+    NAMESPACES
+        .iter()
+        .filter(|&&(_, flag)| flags & flag != 0)
+        .map(|&(name, _)| name)
+        .collect()
+}
+
+/// The namespaces `pid` is in, as the inode numbers `/proc/<pid>/ns` shows
+/// them: two processes with the same number share that namespace.
+pub fn ids(pid: libc::pid_t) -> BTreeMap<&'static str, u64> {
+    let dir = PathBuf::from(format!("/proc/{}/ns", pid));
+    NAMESPACES
+        .iter()
+        .filter_map(|&(name, _)| Some((name, fs::metadata(dir.join(name)).ok()?.ino())))
+        .collect()
+}
+
+/// Whether the kernel has the namespace type `name`, as named in
+/// `/proc/<pid>/ns`.
+pub fn supported(name: &str) -> bool {
+    Path::new("/proc/self/ns").join(name).exists()
+}
+
+/// A clock a time namespace can shift.
+#[derive(Clone, Copy)]
+pub enum Clock {
+    Monotonic,
+    Boottime,
+}
+
+/// A `--time-offset <clock>=<secs>` shift of a clock in the container.
+#[derive(Clone, Copy)]
+pub struct TimeOffset {
+    pub clock: Clock,
+    pub secs: i64,
+}
+
+impl TimeOffset {
+    pub fn parse(s: &str) -> Result<TimeOffset> {
+        let invalid = || {
+            CfsError::Usage(format!(
+                "Invalid time offset {}, expected monotonic=<secs> or boottime=<secs>",
+                s
+            ))
+        };
+        let (clock, secs) = s.split_once('=').ok_or_else(invalid)?;
+        let clock = match clock {
+            "monotonic" => Clock::Monotonic,
+            "boottime" => Clock::Boottime,
+            _ => return Err(invalid()),
+        };
+        Ok(TimeOffset {
+            clock,
+            secs: secs.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Creates a time namespace with the clocks shifted by `offsets`. The caller
+/// stays where it is, the children it starts from now on go in. Offsets can
+/// only be set while nothing is inside yet.
+pub fn unshare_time(plan: Plan, offsets: &[TimeOffset]) -> Result<()> {
+    if plan.skip("unshare the time namespace, with the offsets") {
+        return Ok(());
+    }
+    check(
+        unsafe { libc::unshare(libc::CLONE_NEWTIME) },
+        "unshare of the time namespace failed",
+    )?;
+    let lines: String = offsets
+        .iter()
+        .map(|offset| {
+            let clock = match offset.clock {
+                Clock::Monotonic => "monotonic",
+                Clock::Boottime => "boottime",
+            };
+            format!("{} {} 0\n", clock, offset.secs)
+        })
+        .collect();
+    fs::write("/proc/self/timens_offsets", lines).context("Failed to set the time offsets")
+}
+
+/// Moves the calling process into the cgroups at `paths`, so whatever it
+/// starts counts against the container's limits.
+pub fn join_cgroups(paths: &[PathBuf]) -> Result<()> {
+    for path in paths {
+        let procs = path.join("cgroup.procs");
+        fs::write(&procs, std::process::id().to_string())
+            .context(format!("Failed to join {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Enters the namespaces and the root directory of `pid`. The PID namespace
+/// only applies to children forked afterwards, the caller itself stays out.
+pub fn enter(pid: libc::pid_t) -> Result<()> {
+    let proc_dir = PathBuf::from(format!("/proc/{}", pid));
+    let mut files = Vec::new();
+    for &(name, flag) in NAMESPACES {
+        let path = proc_dir.join("ns").join(name);
+        // joining the namespace we are already in fails for user namespaces
+        // and is pointless for the rest; a container without its own user
+        // namespace shares ours
+        if same_file(&path, &Path::new("/proc/self/ns").join(name))? {
+            continue;
+        }
+        let file = fs::File::open(&path).context(format!("Failed to open {}", path.display()))?;
+        files.push((name, flag, file));
+    }
+    // chroot'ed containers share the host's mount namespace root, so the
+    // root directory is taken from the process rather than from setns
+    let root =
+        fs::File::open(proc_dir.join("root")).context("Failed to open the container root")?;
+
+    for (name, flag, file) in files {
+        check(
+            unsafe { libc::setns(file.as_raw_fd(), flag) },
+            &format!("setns into the {} namespace failed", name),
+        )?;
+    }
+    check(unsafe { libc::fchdir(root.as_raw_fd()) }, "fchdir failed")?;
+    check(unsafe { libc::chroot(c".".as_ptr()) }, "chroot failed")?;
+    std::env::set_current_dir("/").context("chdir failed")
+}
+
+fn same_file(a: &Path, b: &Path) -> Result<bool> {
+    let meta =
+        |path: &Path| fs::metadata(path).context(format!("Failed to stat {}", path.display()));
+    let (a, b) = (meta(a)?, meta(b)?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
@@ -0,0 +1,33 @@
+//! `--dry-run`: every step that would change the system asks the [`Plan`]
+//! it was handed first, which in a dry run prints the step instead.
+
+use std::fmt::Display;
+
+/// Whether a container's steps are carried out or only shown. Passed down
+/// to whatever changes the system on its behalf, so containers started
+/// side by side in one process each keep their own.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Plan {
+    dry_run: bool,
+}
+
+impl Plan {
+    /// Carries every step out, for everything outside a dry run.
+    pub const RUN: Plan = Plan { dry_run: false };
+
+    pub fn new(dry_run: bool) -> Plan {
+        Plan { dry_run }
+    }
+
+    pub fn dry_run(self) -> bool {
+        self.dry_run
+    }
+
+    /// In a dry run, prints `step` and tells the caller to leave it out.
+    pub fn skip(self, step: impl Display) -> bool {
+        if self.dry_run {
+            println!("{}", step);
+        }
+        self.dry_run
+    }
+}
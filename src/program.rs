@@ -0,0 +1,110 @@
+//! The container's command, exec'd with execve rather than through
+//! `std::process::Command`. Everything it needs is allocated up front, so
+//! what runs between fork and exec, or in place of PID 1, is only syscalls.
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::error::{CfsError, Result};
+
+/// Where execvp looks without a PATH, as glibc has it.
+const DEFAULT_PATH: &str = "/bin:/usr/bin";
+
+pub(crate) struct Program {
+    /// None when no candidate on the PATH exists, which exec reports as
+    /// ENOENT, as execvp would.
+    path: Option<CString>,
+    // hold what the pointers point into
+    _argv: Vec<CString>,
+    _envp: Vec<CString>,
+    argv_ptrs: Vec<*const libc::c_char>,
+    envp_ptrs: Vec<*const libc::c_char>,
+}
+
+impl Program {
+    /// Looks `args[0]` up on the PATH in `env`, the command's own, as
+    /// execvp does but before there is a child to do it in.
+    pub(crate) fn new(args: &[String], env: &[(String, String)]) -> Result<Program> {
+        let cstring = |s: String| {
+            CString::new(s).map_err(|_| CfsError::Usage(format!("{:?} contains a NUL byte", args)))
+        };
+        let path = match resolve(&args[0], env) {
+            Some(path) => Some(
+                CString::new(path.as_os_str().as_bytes())
+                    .map_err(|_| CfsError::Usage(format!("Invalid command {:?}", args[0])))?,
+            ),
+            None => None,
+        };
+        let argv = args
+            .iter()
+            .map(|arg| cstring(arg.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        let envp = env
+            .iter()
+            .map(|(key, value)| cstring(format!("{}={}", key, value)))
+            .collect::<Result<Vec<_>>>()?;
+        let pointers = |strings: &[CString]| {
+            let mut ptrs: Vec<_> = strings.iter().map(|s| s.as_ptr()).collect();
+            ptrs.push(std::ptr::null());
+            ptrs
+        };
+        Ok(Program {
+            path,
+            argv_ptrs: pointers(&argv),
+            envp_ptrs: pointers(&envp),
+            _argv: argv,
+            _envp: envp,
+        })
+    }
+
+    /// Replaces the process with the command, so only returns with why it
+    /// couldn't. Safe after fork: it makes the one syscall.
+    pub(crate) fn exec(&self) -> io::Error {
+        let Some(path) = &self.path else {
+            return io::Error::from_raw_os_error(libc::ENOENT);
+        };
+        unsafe {
+            libc::execve(
+                path.as_ptr(),
+                self.argv_ptrs.as_ptr(),
+                self.envp_ptrs.as_ptr(),
+            )
+        };
+        io::Error::last_os_error()
+    }
+}
+
+/// A name with a slash is a path already; anything else is the first
+/// executable file of that name on the PATH.
+fn resolve(program: &str, env: &[(String, String)]) -> Option<PathBuf> {
+    if program.contains('/') {
+        return Some(PathBuf::from(program));
+    }
+    // the last setting wins, as it does for the command
+    let search = env
+        .iter()
+        .rev()
+        .find(|(key, _)| key == "PATH")
+        .map_or(DEFAULT_PATH, |(_, value)| value.as_str());
+    let candidates = search.split(':').map(|dir| {
+        // an empty entry is the working directory
+        Path::new(if dir.is_empty() { "." } else { dir }).join(program)
+    });
+    let mut first = None;
+    for candidate in candidates {
+        match candidate.metadata() {
+            Ok(meta) if meta.is_file() && meta.permissions().mode() & 0o111 != 0 => {
+                return Some(candidate);
+            }
+            // so exec reports why it isn't runnable, EACCES say
+            Ok(_) => {
+                first.get_or_insert(candidate);
+            }
+            Err(_) => {}
+        }
+    }
+    first
+}
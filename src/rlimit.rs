@@ -0,0 +1,98 @@
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{CfsError, Result};
+
+/// The resources `--ulimit` knows, see getrlimit(2).
+const RESOURCES: &[(&str, libc::__rlimit_resource_t)] = &[
+    ("core", libc::RLIMIT_CORE),
+    ("fsize", libc::RLIMIT_FSIZE),
+    ("nofile", libc::RLIMIT_NOFILE),
+    ("nproc", libc::RLIMIT_NPROC),
+    ("stack", libc::RLIMIT_STACK),
+];
+
+/// A resource limit for the container, in place of the one inherited from
+/// the host.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Ulimit {
+    resource: libc::__rlimit_resource_t,
+    soft: libc::rlim_t,
+    hard: libc::rlim_t,
+}
+
+impl Ulimit {
+    /// Parses `name=soft[:hard]`, e.g. `nofile=1024:2048`. Without a hard
+    /// limit both are the same; either can be `unlimited`.
+    pub fn parse(s: &str) -> Result<Ulimit> {
+        let invalid = || {
+            CfsError::Usage(format!(
+                "Invalid --ulimit {}, expected e.g. nofile=1024:2048",
+                s
+            ))
+        };
+        let (name, limits) = s.split_once('=').ok_or_else(invalid)?;
+        let resource = RESOURCES
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|&(_, resource)| resource)
+            .ok_or_else(|| {
+                let known: Vec<_> = RESOURCES.iter().map(|(name, _)| *name).collect();
+                CfsError::Usage(format!(
+                    "Unknown resource {} in --ulimit, expected one of {}",
+                    name,
+                    known.join(", ")
+                ))
+            })?;
+        let (soft, hard) = limits.split_once(':').unwrap_or((limits, limits));
+        let soft = parse_limit(soft).ok_or_else(invalid)?;
+        let hard = parse_limit(hard).ok_or_else(invalid)?;
+        if soft > hard {
+            return Err(CfsError::Usage(format!(
+                "Invalid --ulimit {}: the soft limit is above the hard one",
+                s
+            )));
+        }
+        Ok(Ulimit {
+            resource,
+            soft,
+            hard,
+        })
+    }
+
+    /// Sets the limit for the calling process and what it starts. Raising
+    /// the hard limit takes CAP_SYS_RESOURCE. Only makes a syscall, so it
+    /// can run between fork and exec.
+    pub fn apply(&self) -> io::Result<()> {
+        let limit = libc::rlimit {
+            rlim_cur: self.soft,
+            rlim_max: self.hard,
+        };
+        if unsafe { libc::setrlimit(self.resource, &limit) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Ulimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = RESOURCES
+            .iter()
+            .find(|&&(_, resource)| resource == self.resource)
+            .map_or("?", |(name, _)| name);
+        let limit = |value| match value {
+            libc::RLIM_INFINITY => "unlimited".to_string(),
+            value => value.to_string(),
+        };
+        write!(f, "{}={}:{}", name, limit(self.soft), limit(self.hard))
+    }
+}
+
+fn parse_limit(s: &str) -> Option<libc::rlim_t> {
+    match s {
+        "unlimited" => Some(libc::RLIM_INFINITY),
+        _ => s.parse().ok(),
+    }
+}
@@ -0,0 +1,556 @@
+use std::fs;
+use std::io::{BufRead, BufReader, IsTerminal};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use flate2::read::GzDecoder;
+use log::{Level, debug, info, log_enabled, warn};
+
+use crate::digest::Hashing;
+use crate::error::{CfsError, Context, Result};
+use crate::plan::Plan;
+
+const ALPINE_MIRROR: &str = "https://dl-cdn.alpinelinux.org/alpine";
+const DEFAULT_UBUNTU_RELEASE: &str = "jammy";
+const DEFAULT_ALPINE_VERSION: &str = "3.19.1";
+
+const BOOTSTRAP_MARKER: &str = ".cfs-bootstrap";
+const DEBOOTSTRAP_ATTEMPTS: u32 = 3;
+// before the second attempt, doubling for each one after
+const DEBOOTSTRAP_BACKOFF: Duration = Duration::from_secs(5);
+// about what a minbase system takes, for the progress of the download
+const MINBASE_PACKAGES: usize = 100;
+
+/// Where the root filesystem comes from when the rootfs directory is empty.
+pub enum Distro {
+    /// An Ubuntu release (e.g. `jammy`) installed with debootstrap.
+    Ubuntu { release: String },
+    /// An Alpine minirootfs tarball of a given point release (e.g. `3.19.1`).
+    Alpine { version: String },
+}
+
+impl Default for Distro {
+    fn default() -> Self {
+        Distro::Ubuntu {
+            release: DEFAULT_UBUNTU_RELEASE.into(),
+        }
+    }
+}
+
+impl Distro {
+    /// Parses `ubuntu`, `ubuntu:<release>`, `alpine` or `alpine:<version>`.
+    pub fn parse(s: &str) -> Result<Distro> {
+        let (name, tag) = match s.split_once(':') {
+            Some((name, tag)) if !tag.is_empty() => (name, Some(tag)),
+            Some(_) => {
+                return Err(CfsError::Usage(format!(
+                    "Missing version in --distro {}",
+                    s
+                )));
+            }
+            None => (s, None),
+        };
+        match name {
+            "ubuntu" => Ok(Distro::Ubuntu {
+                release: tag.unwrap_or(DEFAULT_UBUNTU_RELEASE).into(),
+            }),
+            "alpine" => {
+                let version = tag.unwrap_or(DEFAULT_ALPINE_VERSION);
+                if version.split('.').count() != 3 {
+                    return Err(CfsError::Usage(format!(
+                        "Invalid Alpine version {}, expected a point release like {}",
+                        version, DEFAULT_ALPINE_VERSION
+                    )));
+                }
+                Ok(Distro::Alpine {
+                    version: version.into(),
+                })
+            }
+            _ => Err(CfsError::Usage(format!(
+                "Unknown distro {}, expected ubuntu or alpine",
+                name
+            ))),
+        }
+    }
+}
+
+/// Where `cfs commit` saves a rootfs named `name`.
+pub fn committed(name: &str) -> PathBuf {
+    crate::data_dir().join("rootfs").join(name)
+}
+
+/// Where `cfs build --from <distro>` bootstraps the distro, once for every
+/// build from it.
+pub fn base(distro: &Distro) -> PathBuf {
+    let name = match distro {
+        Distro::Ubuntu { release } => format!("ubuntu-{}", release),
+        Distro::Alpine { version } => format!("alpine-{}", version),
+    };
+    crate::data_dir().join("bases").join(name)
+}
+
+/// A bare name with nothing by that name in the current directory is a
+/// committed rootfs, if there is one; any other path is taken as it is.
+pub fn resolve(path: &Path) -> PathBuf {
+    let mut components = path.components();
+    if let (Some(Component::Normal(name)), None) = (components.next(), components.next())
+        && !path.exists()
+        && let Some(name) = name.to_str()
+        && committed(name).is_dir()
+    {
+        return committed(name);
+    }
+    path.to_path_buf()
+}
+
+/// Fills `path` with a root filesystem unless it already holds one.
+/// `auto_install` allows installing missing tools on the host; debootstrap
+/// keeps the packages it downloads in `cache`, if given, for the next time.
+/// Downloads go through `proxy`, or the one `http_proxy` names, which an
+/// Ubuntu rootfs's apt keeps using. With `verify`, an Alpine tarball is
+/// checked against the checksum published next to it; debootstrap checks
+/// the Release signatures itself.
+pub fn bootstrap(
+    plan: Plan,
+    path: &Path,
+    distro: &Distro,
+    auto_install: bool,
+    cache: Option<&Path>,
+    proxy: Option<&str>,
+    verify: bool,
+) -> Result<()> {
+    if is_populated(path) && !interrupted(path) {
+        return Ok(());
+    }
+    let what = match distro {
+        Distro::Ubuntu { release } => format!("Ubuntu {}", release),
+        Distro::Alpine { version } => format!("Alpine {}", version),
+    };
+    if plan.skip(format!("bootstrap {} into {}", what, path.display())) {
+        return Ok(());
+    }
+    if is_populated(path) {
+        info!("{} was only partly bootstrapped, resuming", path.display());
+    }
+
+    // gone again once the rootfs is complete
+    let marker = path.join(BOOTSTRAP_MARKER);
+    fs::create_dir_all(path).context(format!("Failed to create {}", path.display()))?;
+    fs::write(&marker, "").context(format!("Failed to write {}", marker.display()))?;
+    let proxy = proxy.map(String::from).or_else(env_proxy);
+    match distro {
+        Distro::Ubuntu { release } => {
+            debootstrap(path, release, auto_install, cache, proxy.as_deref())?;
+            if let Some(proxy) = &proxy {
+                configure_apt_proxy(path, proxy)?;
+            }
+        }
+        Distro::Alpine { version } => alpine(path, version, proxy.as_deref(), verify)?,
+    }
+    fs::remove_file(&marker).context(format!("Failed to remove {}", marker.display()))
+}
+
+fn debootstrap(
+    path: &Path,
+    release: &str,
+    auto_install: bool,
+    cache: Option<&Path>,
+    proxy: Option<&str>,
+) -> Result<()> {
+    ensure_debootstrap(auto_install)?;
+
+    let mirror = if cfg!(target_arch = "aarch64") {
+        "http://ports.ubuntu.com/ubuntu-ports"
+    } else {
+        "http://archive.ubuntu.com/ubuntu"
+    };
+    let mut command = Command::new("debootstrap");
+    command.args(["--variant=minbase"]);
+    if let Some(cache) = cache {
+        // debootstrap insists on an absolute path
+        let cache =
+            std::path::absolute(cache).context(format!("Cannot resolve {}", cache.display()))?;
+        fs::create_dir_all(&cache).context(format!("Failed to create {}", cache.display()))?;
+        command.arg(format!("--cache-dir={}", cache.display()));
+    }
+    command.arg(release).arg(path).arg(mirror);
+    if let Some(proxy) = proxy {
+        command.env("http_proxy", proxy).env("https_proxy", proxy);
+    }
+
+    // a mirror hiccup fails the whole run; another picks up the packages
+    // already downloaded
+    let mut backoff = DEBOOTSTRAP_BACKOFF;
+    for attempt in 1..=DEBOOTSTRAP_ATTEMPTS {
+        let status = run_debootstrap(&mut command)?;
+        if status.success() {
+            return Ok(());
+        }
+        if attempt == DEBOOTSTRAP_ATTEMPTS {
+            return Err(CfsError::Command(format!(
+                "debootstrap failed {} times, last with {}",
+                DEBOOTSTRAP_ATTEMPTS, status
+            )));
+        }
+        warn!(
+            "debootstrap failed with {}, retrying in {}s",
+            status,
+            backoff.as_secs()
+        );
+        thread::sleep(backoff);
+        backoff *= 2;
+    }
+    unreachable!("the last attempt returns")
+}
+
+/// Runs debootstrap with its output turned into a progress line, or logged
+/// line by line at `-v`. Its warnings and errors always come through.
+fn run_debootstrap(command: &mut Command) -> Result<ExitStatus> {
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("failed to run debootstrap")?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+    // hardly anything comes on stdout, but a full pipe would hang debootstrap
+    let relay = thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(|line| line.ok()) {
+            debug!("debootstrap: {}", line);
+        }
+    });
+
+    let mut progress = Progress::default();
+    let show = log_enabled!(Level::Info) && !log_enabled!(Level::Debug);
+    let redraw = show && std::io::stderr().is_terminal();
+    let mut drawn = false;
+    let mut shown = String::new();
+    for line in BufReader::new(stderr).lines().map_while(|line| line.ok()) {
+        if let Some(message) = line.strip_prefix("W: ").or(line.strip_prefix("E: ")) {
+            if drawn {
+                eprintln!();
+                drawn = false;
+            }
+            warn!("debootstrap: {}", message);
+            continue;
+        }
+        debug!("debootstrap: {}", line);
+        let stage = progress.stage;
+        if !progress.update(&line) || !show {
+            continue;
+        }
+        if redraw {
+            let line = progress.to_string();
+            if line != shown {
+                eprint!("\r{}\x1b[K", line);
+                (drawn, shown) = (true, line);
+            }
+        } else if progress.stage != stage {
+            info!("{}", progress);
+        }
+    }
+    if drawn {
+        eprintln!();
+    }
+    let _ = relay.join();
+    child.wait().context("failed to run debootstrap")
+}
+
+/// A debootstrap stage, as its `I:` lines tell.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stage {
+    Retrieving,
+    Extracting,
+    Unpacking,
+    Configuring,
+    Done,
+}
+
+impl Stage {
+    /// The part of the whole run the stage takes, in percent. The download
+    /// takes the longest.
+    fn span(self) -> (usize, usize) {
+        match self {
+            Stage::Retrieving => (0, 40),
+            Stage::Extracting => (40, 50),
+            Stage::Unpacking => (50, 75),
+            Stage::Configuring => (75, 100),
+            Stage::Done => (100, 100),
+        }
+    }
+}
+
+/// Where debootstrap is at, estimated from its `I:` lines. The packages
+/// validated during the download tell how many the later stages go
+/// through; for the download itself [`MINBASE_PACKAGES`] stands in.
+#[derive(Debug, Default)]
+struct Progress {
+    stage: Option<Stage>,
+    packages: usize,
+    done: usize,
+}
+
+impl Progress {
+    /// Takes in a line of debootstrap's, returns whether that got it any
+    /// further.
+    fn update(&mut self, line: &str) -> bool {
+        let Some(message) = line.strip_prefix("I: ") else {
+            return false;
+        };
+        let (verb, rest) = message.split_once(' ').unwrap_or((message, ""));
+        // "Unpacking adduser..." goes through one package, "Unpacking
+        // required packages..." starts on a batch
+        let package = !rest.contains(' ') && rest.ends_with("...");
+        let stage = match verb {
+            "Retrieving" => Stage::Retrieving,
+            // one per package, cached or downloaded, the indexes aside
+            "Validating" if rest.split_whitespace().count() == 2 => {
+                self.packages += 1;
+                Stage::Retrieving
+            }
+            "Extracting" => Stage::Extracting,
+            "Installing" | "Unpacking" => Stage::Unpacking,
+            "Configuring" => Stage::Configuring,
+            "Base" if rest.starts_with("system installed") => Stage::Done,
+            _ => return false,
+        };
+        if self.stage != Some(stage) {
+            self.stage = Some(stage);
+            self.done = 0;
+        }
+        if package {
+            self.done += 1;
+        }
+        true
+    }
+
+    fn percent(&self) -> usize {
+        let Some(stage) = self.stage else { return 0 };
+        let (start, end) = stage.span();
+        let (done, total) = match stage {
+            Stage::Retrieving => (self.packages, self.packages.max(MINBASE_PACKAGES)),
+            _ => (self.done, self.packages.max(1)),
+        };
+        start + (end - start) * done.min(total) / total
+    }
+}
+
+impl std::fmt::Display for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let stage = match self.stage {
+            None => "starting",
+            Some(Stage::Retrieving) => "retrieving packages",
+            Some(Stage::Extracting) => "extracting packages",
+            Some(Stage::Unpacking) => "unpacking packages",
+            Some(Stage::Configuring) => "configuring packages",
+            Some(Stage::Done) => "done",
+        };
+        write!(f, "debootstrap: {} {}%", stage, self.percent())
+    }
+}
+
+fn alpine(path: &Path, version: &str, proxy: Option<&str>, verify: bool) -> Result<()> {
+    let arch = if cfg!(target_arch = "aarch64") {
+        "aarch64"
+    } else {
+        "x86_64"
+    };
+    // releases live under their minor branch, e.g. v3.19 for 3.19.1
+    let branch = version
+        .rsplit_once('.')
+        .map_or(version, |(branch, _)| branch);
+    let url = format!(
+        "{}/v{}/releases/{}/alpine-minirootfs-{}-{}.tar.gz",
+        ALPINE_MIRROR, branch, arch, version, arch
+    );
+
+    let mut agent = ureq::AgentBuilder::new();
+    if let Some(proxy) = proxy {
+        agent = agent.proxy(
+            ureq::Proxy::new(proxy)
+                .map_err(|e| CfsError::Usage(format!("Invalid proxy {}: {}", proxy, e)))?,
+        );
+    }
+    let agent = agent.build();
+    let get = |url: &str| {
+        agent
+            .get(url)
+            .call()
+            .map_err(|e| CfsError::Command(format!("download of {} failed: {}", url, e)))
+    };
+
+    // published as `<hex>  <file>`, like sha256sum prints it
+    let expected = if verify {
+        let checksum = format!("{}.sha256", url);
+        let line = get(&checksum)?
+            .into_string()
+            .context(format!("Failed to read {}", checksum))?;
+        let hex = line
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        if hex.len() != 64 {
+            return Err(CfsError::Command(format!("{} holds no checksum", checksum)));
+        }
+        Some(hex)
+    } else {
+        None
+    };
+
+    info!("Downloading {}...", url);
+    let mut reader = Hashing::new(get(&url)?.into_reader());
+    unpack(&mut reader, path)?;
+    let Some(expected) = expected else {
+        return Ok(());
+    };
+    reader.verify(&expected, &url).inspect_err(|_| {
+        // nothing of it is to be trusted
+        if let Ok(entries) = fs::read_dir(path) {
+            for entry in entries.flatten() {
+                let _ = fs::remove_dir_all(entry.path()).or_else(|_| fs::remove_file(entry.path()));
+            }
+        }
+    })
+}
+
+fn env_proxy() -> Option<String> {
+    ["http_proxy", "HTTP_PROXY"]
+        .iter()
+        .find_map(|name| std::env::var(name).ok())
+        .filter(|proxy| !proxy.is_empty())
+}
+
+/// Points apt in the new rootfs at the proxy debootstrap went through, for
+/// the packages installed in the container later on.
+fn configure_apt_proxy(path: &Path, proxy: &str) -> Result<()> {
+    let conf = path.join("etc/apt/apt.conf.d/95cfs-proxy");
+    let contents = format!(
+        "Acquire::http::Proxy \"{}\";\nAcquire::https::Proxy \"{}\";\n",
+        proxy, proxy
+    );
+    fs::write(&conf, contents).context(format!("Failed to write {}", conf.display()))
+}
+
+/// Extracts a gzipped tarball into `path`, keeping ownership and permissions.
+fn unpack(reader: impl std::io::Read, path: &Path) -> Result<()> {
+    fs::create_dir_all(path).context(format!("Failed to create {}", path.display()))?;
+    let mut archive = tar::Archive::new(GzDecoder::new(reader));
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(true);
+    archive
+        .unpack(path)
+        .context(format!("Failed to extract into {}", path.display()))
+}
+
+fn is_populated(path: &Path) -> bool {
+    fs::read_dir(path)
+        .map(|mut entries| entries.next().is_some())
+        .unwrap_or(false)
+}
+
+/// Whether `path` is what a bootstrap that didn't finish left behind: no
+/// shell yet, and our marker or debootstrap's work directory still there.
+/// A rootfs without a shell is fine by itself, one with just a static
+/// binary say.
+fn interrupted(path: &Path) -> bool {
+    let has = |name: &str| fs::symlink_metadata(path.join(name)).is_ok();
+    !has("bin/sh") && (has(BOOTSTRAP_MARKER) || has("debootstrap"))
+}
+
+/// Makes sure debootstrap is installed. Installing it is left to the user
+/// unless `auto_install` says we may run apt-get on the host, which only
+/// helps on Debian and Ubuntu.
+fn ensure_debootstrap(auto_install: bool) -> Result<()> {
+    if in_path("debootstrap") {
+        return Ok(());
+    }
+    if !auto_install {
+        return Err(CfsError::Command(
+            "debootstrap is needed to build an Ubuntu rootfs but is not installed; \
+             install it with your package manager, pass --auto-install-deps to have \
+             cfs apt-get install it, or use --distro alpine or --image instead"
+                .into(),
+        ));
+    }
+    if !in_path("apt-get") {
+        return Err(CfsError::Command(
+            "--auto-install-deps needs apt-get, install debootstrap yourself".into(),
+        ));
+    }
+
+    info!("Installing debootstrap with apt-get...");
+    Command::new("apt-get").args(["update"]).status().ok();
+    let status = Command::new("apt-get")
+        .args(["install", "-y", "debootstrap"])
+        .status()
+        .context("failed to install debootstrap")?;
+    if !status.success() {
+        return Err(CfsError::Command(format!(
+            "installing debootstrap failed: {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+fn in_path(program: &str) -> bool {
+    std::env::var_os("PATH")
+        .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(lines: &[&str]) -> Progress {
+        let mut progress = Progress::default();
+        for line in lines {
+            progress.update(line);
+        }
+        progress
+    }
+
+    #[test]
+    fn progress_follows_the_stages() {
+        let retrieving = [
+            "I: Retrieving InRelease",
+            "I: Validating Packages",
+            "I: Retrieving adduser 3.118ubuntu5",
+            "I: Validating adduser 3.118ubuntu5",
+            "I: Retrieving apt 2.4.5",
+            "I: Validating apt 2.4.5",
+        ];
+        let progress = run(&retrieving);
+        assert_eq!(progress.stage, Some(Stage::Retrieving));
+        assert_eq!(progress.packages, 2);
+
+        let unpacking = [
+            &retrieving[..],
+            &[
+                "I: Extracting adduser...",
+                "I: Extracting apt...",
+                "I: Installing core packages...",
+                "I: Unpacking required packages...",
+                "I: Unpacking adduser...",
+            ],
+        ]
+        .concat();
+        let progress = run(&unpacking);
+        assert_eq!(progress.stage, Some(Stage::Unpacking));
+        assert_eq!(progress.percent(), 62);
+
+        let done = [&unpacking[..], &["I: Base system installed successfully."]].concat();
+        assert_eq!(run(&done).percent(), 100);
+    }
+
+    #[test]
+    fn other_lines_are_no_progress() {
+        let mut progress = Progress::default();
+        assert!(!progress.update("I: Keyring file not available, skipping"));
+        assert!(!progress.update("W: Cannot check Release signature"));
+        assert_eq!(progress.percent(), 0);
+    }
+}
@@ -0,0 +1,258 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{CfsError, Context, Result};
+use crate::syscalls;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+// x32 syscalls share the x86_64 audit arch, told apart by this bit
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+// offsets into struct seccomp_data
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+
+/// Denied with EPERM unless the user brings a profile: syscalls that reach
+/// into the kernel or the host rather than the container, mostly the same
+/// ones Docker's default profile refuses.
+const DEFAULT_DENIED: &[&str] = &[
+    "acct",
+    "add_key",
+    "bpf",
+    "clock_adjtime",
+    "clock_settime",
+    "create_module",
+    "delete_module",
+    "finit_module",
+    "fsconfig",
+    "fsmount",
+    "fsopen",
+    "fspick",
+    "get_kernel_syms",
+    "init_module",
+    "ioperm",
+    "iopl",
+    "kcmp",
+    "kexec_file_load",
+    "kexec_load",
+    "keyctl",
+    "lookup_dcookie",
+    "mount",
+    "move_mount",
+    "move_pages",
+    "name_to_handle_at",
+    "nfsservctl",
+    "open_by_handle_at",
+    "open_tree",
+    "perf_event_open",
+    "pivot_root",
+    "process_vm_readv",
+    "process_vm_writev",
+    "ptrace",
+    "query_module",
+    "quotactl",
+    "reboot",
+    "request_key",
+    "set_mempolicy",
+    "setns",
+    "settimeofday",
+    "swapoff",
+    "swapon",
+    "sysfs",
+    "_sysctl",
+    "umount2",
+    "unshare",
+    "uselib",
+    "userfaultfd",
+    "ustat",
+];
+
+/// The subset of the Docker/OCI seccomp profile format cfs understands.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Profile {
+    default_action: Action,
+    #[serde(default)]
+    default_errno_ret: Option<u16>,
+    #[serde(default)]
+    syscalls: Vec<Rule>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Rule {
+    names: Vec<String>,
+    action: Action,
+    #[serde(default)]
+    errno_ret: Option<u16>,
+    // conditions we can't evaluate; refused rather than silently widened
+    #[serde(default)]
+    args: Option<serde_json::Value>,
+    #[serde(default)]
+    includes: Option<serde_json::Value>,
+    #[serde(default)]
+    excludes: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+enum Action {
+    #[serde(rename = "SCMP_ACT_ALLOW")]
+    Allow,
+    #[serde(rename = "SCMP_ACT_ERRNO")]
+    Errno,
+    #[serde(rename = "SCMP_ACT_KILL", alias = "SCMP_ACT_KILL_PROCESS")]
+    Kill,
+    #[serde(rename = "SCMP_ACT_LOG")]
+    Log,
+}
+
+impl Action {
+    fn ret(self, errno: Option<u16>) -> u32 {
+        match self {
+            Action::Allow => libc::SECCOMP_RET_ALLOW,
+            Action::Errno => {
+                libc::SECCOMP_RET_ERRNO | u32::from(errno.unwrap_or(libc::EPERM as u16))
+            }
+            Action::Kill => libc::SECCOMP_RET_KILL_PROCESS,
+            Action::Log => libc::SECCOMP_RET_LOG,
+        }
+    }
+}
+
+/// Syscall numbers mapped to seccomp return values, first match wins, and
+/// what everything else gets. Kept in the state file so `exec` can confine
+/// its command the same way.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Filter {
+    rules: Vec<(u32, u32)>,
+    default: u32,
+}
+
+impl Filter {
+    /// The built-in profile: everything allowed but [`DEFAULT_DENIED`].
+    pub fn default_profile() -> Filter {
+        let denied = Action::Errno.ret(None);
+        let rules = DEFAULT_DENIED
+            .iter()
+            .filter_map(|name| syscalls::number(name))
+            .map(|nr| (nr, denied))
+            .collect();
+        Filter {
+            rules,
+            default: libc::SECCOMP_RET_ALLOW,
+        }
+    }
+
+    /// Loads a JSON profile in the Docker format.
+    pub fn load(path: &Path) -> Result<Filter> {
+        let json =
+            fs::read(path).context(format!("Failed to read seccomp profile {}", path.display()))?;
+        let profile: Profile = serde_json::from_slice(&json)
+            .map_err(io::Error::from)
+            .context(format!("Invalid seccomp profile {}", path.display()))?;
+
+        let mut rules = Vec::new();
+        for rule in &profile.syscalls {
+            if rule.args.is_some() || rule.includes.is_some() || rule.excludes.is_some() {
+                return Err(CfsError::Usage(format!(
+                    "{}: args, includes and excludes are not supported",
+                    path.display()
+                )));
+            }
+            let ret = rule.action.ret(rule.errno_ret);
+            for name in &rule.names {
+                // like libseccomp, skip what this architecture doesn't have
+                if let Some(nr) = syscalls::number(name) {
+                    rules.push((nr, ret));
+                }
+            }
+        }
+        Ok(Filter {
+            rules,
+            default: profile.default_action.ret(profile.default_errno_ret),
+        })
+    }
+
+    /// Compiles the rules into a seccomp-bpf program, a linear list of
+    /// syscall number checks.
+    pub fn program(&self) -> Program {
+        let mut program = vec![
+            // a syscall made through another ABI would have other numbers
+            load(ARCH_OFFSET),
+            jump(libc::BPF_JEQ, AUDIT_ARCH, 1, 0),
+            ret(libc::SECCOMP_RET_KILL_PROCESS),
+            load(NR_OFFSET),
+        ];
+        if cfg!(target_arch = "x86_64") {
+            program.push(jump(libc::BPF_JGE, X32_SYSCALL_BIT, 0, 1));
+            program.push(ret(libc::SECCOMP_RET_ERRNO | libc::EPERM as u32));
+        }
+        for &(nr, action) in &self.rules {
+            program.push(jump(libc::BPF_JEQ, nr, 0, 1));
+            program.push(ret(action));
+        }
+        program.push(ret(self.default));
+        Program(program)
+    }
+}
+
+/// A compiled [`Filter`], ready to install.
+pub struct Program(Vec<libc::sock_filter>);
+
+impl Program {
+    /// Installs the filter on the calling thread, which its exec inherits.
+    /// Runs between fork and exec, so it doesn't allocate. Without
+    /// no_new_privs this needs CAP_SYS_ADMIN, so it has to come before the
+    /// capabilities are dropped.
+    pub fn apply(&self) -> io::Result<()> {
+        let prog = libc::sock_fprog {
+            len: self.0.len() as libc::c_ushort,
+            filter: self.0.as_ptr() as *mut libc::sock_filter,
+        };
+        if unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &prog as *const libc::sock_fprog,
+            )
+        } != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+fn load(offset: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16,
+        jt: 0,
+        jf: 0,
+        k: offset,
+    }
+}
+
+fn jump(op: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: (libc::BPF_JMP | op | libc::BPF_K) as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+fn ret(k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: (libc::BPF_RET | libc::BPF_K) as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
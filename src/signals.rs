@@ -0,0 +1,98 @@
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+
+use crate::error::{Result, check};
+
+// Signal handlers can only touch atomics, so the forwarding target and the
+// Ctrl-C count live in statics.
+static TARGET: AtomicI32 = AtomicI32::new(0);
+static INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+static PTY: AtomicI32 = AtomicI32::new(-1);
+static FORWARDED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn forward(sig: libc::c_int) {
+    let pid = TARGET.load(Ordering::SeqCst);
+    if pid <= 0 {
+        return;
+    }
+    // a second Ctrl-C means the container didn't react to the first one
+    let sig = if sig == libc::SIGINT && INTERRUPTS.fetch_add(1, Ordering::SeqCst) >= 1 {
+        libc::SIGKILL
+    } else {
+        sig
+    };
+    FORWARDED.store(true, Ordering::SeqCst);
+    unsafe { libc::kill(pid, sig) };
+}
+
+extern "C" fn resize(_sig: libc::c_int) {
+    let master = PTY.load(Ordering::SeqCst);
+    if master >= 0 {
+        crate::tty::copy_window_size(master);
+    }
+}
+
+/// Resizes the PTY `master` along with our terminal on every SIGWINCH.
+pub fn resize_on_winch(master: libc::c_int) -> Result<()> {
+    PTY.store(master, Ordering::SeqCst);
+    install(libc::SIGWINCH, resize)
+}
+
+/// Relays SIGINT and SIGTERM received by this process to `pid`, escalating
+/// to SIGKILL on the second SIGINT. SA_RESTART keeps a pending waitpid going.
+pub fn forward_to(pid: libc::pid_t) -> Result<()> {
+    TARGET.store(pid, Ordering::SeqCst);
+    install(libc::SIGINT, forward)?;
+    install(libc::SIGTERM, forward)
+}
+
+/// Whether a signal has been relayed since `forward_to`.
+pub fn forwarded() -> bool {
+    FORWARDED.load(Ordering::SeqCst)
+}
+
+/// Waits for the child `pid` to terminate and returns its wait status.
+/// waitpid fails with EINTR when a signal handled without SA_RESTART comes
+/// first, so keep waiting until the child is really gone. None if it isn't
+/// our child (ECHILD), say one already reaped.
+pub fn wait_for(pid: libc::pid_t) -> Option<i32> {
+    let mut status = 0;
+    loop {
+        if unsafe { libc::waitpid(pid, &mut status, 0) } == pid {
+            return Some(status);
+        }
+        if !interrupted() {
+            return None;
+        }
+    }
+}
+
+/// Like [`wait_for`], but leaves the terminated child to be reaped, so its pid
+/// can't be reused in the meantime.
+pub fn wait_for_exit(pid: libc::pid_t) {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    while unsafe {
+        libc::waitid(
+            libc::P_PID,
+            pid as libc::id_t,
+            &mut info,
+            libc::WEXITED | libc::WNOWAIT,
+        )
+    } != 0
+        && interrupted()
+    {}
+}
+
+fn interrupted() -> bool {
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EINTR)
+}
+
+fn install(sig: libc::c_int, handler: extern "C" fn(libc::c_int)) -> Result<()> {
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = handler as *const () as libc::sighandler_t;
+    action.sa_flags = libc::SA_RESTART;
+    unsafe { libc::sigemptyset(&mut action.sa_mask) };
+    check(
+        unsafe { libc::sigaction(sig, &action, std::ptr::null_mut()) },
+        "sigaction failed",
+    )
+}
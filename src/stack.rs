@@ -0,0 +1,89 @@
+use crate::error::{CfsError, Result};
+use crate::units;
+
+pub const DEFAULT_STACK_SIZE: usize = 1024 * 1024; // 1MB stack
+/// Below this the child crashes before it gets to exec.
+pub const MIN_STACK_SIZE: usize = 64 * 1024;
+
+/// Checks a stack size asked for: at least [`MIN_STACK_SIZE`], and no more
+/// than our own RLIMIT_STACK allows a stack.
+pub fn validate(size: usize) -> Result<()> {
+    if size < MIN_STACK_SIZE {
+        return Err(CfsError::Usage(format!(
+            "--stack-size must be at least {}",
+            units::format_size(MIN_STACK_SIZE as u64)
+        )));
+    }
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_STACK, &mut limit) } == 0
+        && limit.rlim_cur != libc::RLIM_INFINITY
+        && size as u64 > limit.rlim_cur
+    {
+        return Err(CfsError::Usage(format!(
+            "--stack-size {} is over the stack limit of {}, see ulimit -s",
+            units::format_size(size as u64),
+            units::format_size(limit.rlim_cur)
+        )));
+    }
+    Ok(())
+}
+
+/// Stack for the cloned child: an anonymous mapping with an inaccessible
+/// guard page below it, so an overflow faults instead of scribbling over
+/// whatever happens to be mapped next. Unmapped on drop, which must not
+/// happen before the child is done with it.
+pub struct Stack {
+    base: *mut libc::c_void,
+    len: usize,
+}
+
+impl Stack {
+    /// A stack of at least `size` bytes, rounded up to whole pages.
+    pub fn new(size: usize) -> Result<Stack> {
+        let page = page_size();
+        let len = size.next_multiple_of(page) + page;
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_STACK,
+                -1,
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(CfsError::last_os_error("mmap of the child stack failed"));
+        }
+
+        let stack = Stack { base, len };
+        // the stack grows down, so the guard goes at the lowest address
+        if unsafe { libc::mprotect(base, page, libc::PROT_NONE) } != 0 {
+            return Err(CfsError::last_os_error(
+                "mprotect of the stack guard failed",
+            ));
+        }
+        Ok(stack)
+    }
+
+    /// The initial stack pointer for clone(2). The stack grows down from the
+    /// end of the mapping; the x86-64 and aarch64 ABIs both require it to be
+    /// 16-byte aligned at the call into the child function.
+    pub fn top(&self) -> *mut libc::c_void {
+        let end = self.base as usize + self.len;
+        (end & !0xf) as *mut libc::c_void
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.base, self.len) };
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
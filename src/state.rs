@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::caps::Capabilities;
+use crate::error::{CfsError, Context, Result};
+use crate::health::Health;
+use crate::rlimit::Ulimit;
+use crate::seccomp::Filter;
+use crate::user::User;
+
+/// What `list`, `stop` and friends need to know about a running container,
+/// kept in `<run dir>/<name>.json` while it runs.
+#[derive(Serialize, Deserialize)]
+pub struct State {
+    pub name: String,
+    pub id: String,
+    pub pid: libc::pid_t,
+    pub command: Vec<String>,
+    /// How the command was started, for `exec` to do the same.
+    pub env: Vec<(String, String)>,
+    pub caps: Capabilities,
+    pub seccomp: Option<Filter>,
+    pub ulimits: Vec<Ulimit>,
+    pub user: Option<User>,
+    pub rootfs: PathBuf,
+    pub cgroups: Vec<PathBuf>,
+    /// On the bridge, so the next container picks another.
+    #[serde(default)]
+    pub address: Option<Ipv4Addr>,
+    /// How often a restart policy started it again.
+    #[serde(default)]
+    pub restarts: u32,
+    /// With a health check, how it last went.
+    #[serde(default)]
+    pub health: Option<Health>,
+    /// Set by `stop`, which isn't a failure to restart after.
+    #[serde(default)]
+    pub stopping: bool,
+    /// What `--label` attached, for `list --filter` to pick it by.
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// Seconds since the epoch.
+    pub started: u64,
+}
+
+const KEPT_STATE: &str = "state.json";
+
+/// Runtime state goes to tmpfs, so it doesn't outlive a reboot that took
+/// the containers with it.
+pub fn run_dir() -> PathBuf {
+    if unsafe { libc::geteuid() } == 0 {
+        return PathBuf::from("/run/cfs");
+    }
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir).join("cfs"),
+        _ => crate::data_dir().join("run"),
+    }
+}
+
+/// Names end up in file paths, so they are kept to a safe alphabet.
+pub fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with(['.', '-'])
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+    if !valid {
+        return Err(CfsError::Usage(format!(
+            "Invalid name {:?}: use letters, digits, _, . and -",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Parses `--label key=value`.
+pub fn parse_label(s: &str) -> Result<(String, String)> {
+    let (key, value) = s
+        .split_once('=')
+        .filter(|(key, _)| !key.is_empty())
+        .ok_or_else(|| CfsError::Usage(format!("Invalid --label {}, expected key=value", s)))?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// What `list --filter` narrows the containers down to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListFilter {
+    /// `label=key` for any value, `label=key=value` for that one.
+    Label(String, Option<String>),
+}
+
+impl ListFilter {
+    pub fn parse(s: &str) -> Result<ListFilter> {
+        match s.split_once('=') {
+            Some(("label", label)) if !label.is_empty() && !label.starts_with('=') => {
+                Ok(match label.split_once('=') {
+                    Some((key, value)) => {
+                        ListFilter::Label(key.to_string(), Some(value.to_string()))
+                    }
+                    None => ListFilter::Label(label.to_string(), None),
+                })
+            }
+            _ => Err(CfsError::Usage(format!(
+                "Invalid --filter {}, expected label=key or label=key=value",
+                s
+            ))),
+        }
+    }
+
+    pub fn matches(&self, state: &State) -> bool {
+        match self {
+            ListFilter::Label(key, value) => state
+                .labels
+                .get(key)
+                .is_some_and(|found| value.as_ref().is_none_or(|value| value == found)),
+        }
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+impl State {
+    fn path(name: &str) -> PathBuf {
+        run_dir().join(format!("{}.json", name))
+    }
+
+    /// Every container with a state file, running or not, sorted by name.
+    pub fn all() -> Result<Vec<State>> {
+        let dir = run_dir();
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).context(format!("Failed to read {}", dir.display())),
+        };
+        let mut states = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if let Some(name) = path.file_stem().and_then(|n| n.to_str())
+                && path.extension().is_some_and(|e| e == "json")
+            {
+                states.push(State::load(name)?);
+            }
+        }
+        states.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(states)
+    }
+
+    /// Keeps a copy in `dir`, the container's own directory, which outlives
+    /// the state file when the container leaves changes behind.
+    pub fn keep(&self, dir: &Path) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self).expect("state serializes");
+        let path = dir.join(KEPT_STATE);
+        fs::write(&path, json).context(format!("Failed to write {}", path.display()))
+    }
+
+    /// The most recently started container named `name` with a copy kept in
+    /// a directory below `dir`.
+    pub fn find_kept(dir: &Path, name: &str) -> Option<State> {
+        fs::read_dir(dir)
+            .ok()?
+            .flatten()
+            .filter_map(|entry| fs::read(entry.path().join(KEPT_STATE)).ok())
+            .filter_map(|json| serde_json::from_slice::<State>(&json).ok())
+            .filter(|state| state.name == name)
+            .max_by_key(|state| state.started)
+    }
+
+    /// Where a detached container's output goes.
+    pub fn log_path(name: &str) -> PathBuf {
+        run_dir().join(format!("{}.log", name))
+    }
+
+    pub fn load(name: &str) -> Result<State> {
+        let path = State::path(name);
+        let json = fs::read(&path).context(format!("No container named {}", name))?;
+        serde_json::from_slice(&json)
+            .map_err(io::Error::from)
+            .context(format!("Invalid state file {}", path.display()))
+    }
+
+    /// Fails if `name` is taken by a running container. A leftover from one
+    /// that is gone doesn't count.
+    pub fn ensure_available(name: &str) -> Result<()> {
+        match State::load(name) {
+            Ok(existing) if existing.is_running() => Err(CfsError::Usage(format!(
+                "A container named {} is already running as PID {}",
+                name, existing.pid
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Registers the container under its name.
+    pub fn save(&self) -> Result<()> {
+        State::ensure_available(&self.name)?;
+        let dir = run_dir();
+        fs::create_dir_all(&dir).context(format!("Failed to create {}", dir.display()))?;
+        self.update()
+    }
+
+    /// Writes the state of a container already registered.
+    pub fn update(&self) -> Result<()> {
+        let json = serde_json::to_vec_pretty(self).expect("state serializes");
+        let path = State::path(&self.name);
+        fs::write(&path, json).context(format!("Failed to write {}", path.display()))
+    }
+
+    pub fn remove(&self) -> Result<()> {
+        let path = State::path(&self.name);
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(e).context(format!("Failed to remove {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the recorded PID still exists. EPERM means it does, it just
+    /// isn't ours to signal.
+    pub fn is_running(&self) -> bool {
+        let alive = unsafe { libc::kill(self.pid, 0) } == 0;
+        alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+
+    /// Polls until the process is gone, for at most `timeout`. It isn't our
+    /// child, so there is nothing to wait on.
+    pub fn wait_for_exit(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.is_running() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        true
+    }
+}
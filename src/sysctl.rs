@@ -0,0 +1,97 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::{CfsError, Context, Result};
+use crate::plan::Plan;
+
+/// The IPC namespace's kernel parameters, see ipc_sysctls in the kernel.
+const IPC_KEYS: &[&str] = &[
+    "kernel.msgmax",
+    "kernel.msgmnb",
+    "kernel.msgmni",
+    "kernel.sem",
+    "kernel.shm_rmid_forced",
+    "kernel.shmall",
+    "kernel.shmmax",
+    "kernel.shmmni",
+];
+
+/// The namespace a sysctl belongs to, which the container needs its own of
+/// for the value not to change the host's.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Namespace {
+    Ipc,
+    Net,
+}
+
+/// A kernel parameter set in the container, like `net.core.somaxconn=1024`.
+/// Only namespaced ones, everything else is the host's.
+#[derive(Clone, Debug)]
+pub struct Sysctl {
+    key: String,
+    value: String,
+    namespace: Namespace,
+}
+
+impl Sysctl {
+    /// Parses `key=value`, the key dotted or as a path below /proc/sys.
+    pub fn parse(s: &str) -> Result<Sysctl> {
+        let (key, value) = s
+            .split_once('=')
+            .filter(|(key, value)| !key.is_empty() && !value.is_empty())
+            .ok_or_else(|| {
+                CfsError::Usage(format!(
+                    "Invalid --sysctl {}, expected e.g. net.core.somaxconn=1024",
+                    s
+                ))
+            })?;
+        let key = key.replace('/', ".");
+        if key.split('.').any(|part| part.is_empty() || part == "..") {
+            return Err(CfsError::Usage(format!("Invalid sysctl {}", key)));
+        }
+        let namespace = if IPC_KEYS.contains(&key.as_str()) || key.starts_with("fs.mqueue.") {
+            Namespace::Ipc
+        } else if key.starts_with("net.") {
+            Namespace::Net
+        } else {
+            return Err(CfsError::Usage(format!(
+                "{} is not namespaced, setting it would change the host",
+                key
+            )));
+        };
+        Ok(Sysctl {
+            key,
+            value: value.to_string(),
+            namespace,
+        })
+    }
+
+    pub fn namespace(&self) -> Namespace {
+        self.namespace
+    }
+
+    /// Writes the value through the proc mounted at `proc`, one already in
+    /// the container's namespaces.
+    pub fn apply(&self, plan: Plan, proc: &Path) -> Result<()> {
+        let path = self.path(proc);
+        if plan.skip(format!("write {} to {}", self.value, path.display())) {
+            return Ok(());
+        }
+        if !path.exists() {
+            return Err(CfsError::Usage(format!("Unknown sysctl {}", self.key)));
+        }
+        fs::write(&path, &self.value).context(format!("Failed to set sysctl {}", self))
+    }
+
+    fn path(&self, proc: &Path) -> PathBuf {
+        self.key
+            .split('.')
+            .fold(proc.join("sys"), |path, part| path.join(part))
+    }
+}
+
+impl std::fmt::Display for Sysctl {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}={}", self.key, self.value)
+    }
+}
@@ -0,0 +1,175 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use crate::error::{CfsError, Context, Result, check};
+
+/// A connected pair of unix sockets; the container's PID 1 hands the PTY
+/// master to the parent over it.
+pub fn socketpair() -> Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    check(
+        unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_STREAM | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        },
+        "socketpair failed",
+    )?;
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+/// Opens a PTY pair on the devpts mounted at `/dev/pts`, i.e. the
+/// container's own instance once the root has been switched.
+pub fn open_pty() -> Result<(OwnedFd, OwnedFd)> {
+    let master = unsafe {
+        libc::open(
+            c"/dev/ptmx".as_ptr(),
+            libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC,
+        )
+    };
+    check(master, "open of /dev/ptmx failed")?;
+    let master = unsafe { OwnedFd::from_raw_fd(master) };
+
+    let unlock: libc::c_int = 0;
+    check(
+        unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSPTLCK, &unlock) },
+        "unlocking the PTY failed",
+    )?;
+    let slave = unsafe {
+        libc::ioctl(
+            master.as_raw_fd(),
+            libc::TIOCGPTPEER,
+            libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC,
+        )
+    };
+    check(slave, "opening the PTY slave failed")?;
+    Ok((master, unsafe { OwnedFd::from_raw_fd(slave) }))
+}
+
+/// Passes `fd` to the other end of `socket` as SCM_RIGHTS.
+pub fn send_fd(socket: &OwnedFd, fd: &OwnedFd) -> Result<()> {
+    let mut byte = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: 1,
+    };
+    let space = unsafe { libc::CMSG_SPACE(size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<RawFd>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd.as_raw_fd());
+    }
+    check(
+        unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) },
+        "sending the PTY failed",
+    )
+}
+
+/// Receives a descriptor sent with [`send_fd`]. `None` means the other end
+/// went away without sending one.
+pub fn recv_fd(socket: &OwnedFd) -> Result<Option<OwnedFd>> {
+    let mut byte = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: byte.as_mut_ptr() as *mut libc::c_void,
+        iov_len: 1,
+    };
+    let space = unsafe { libc::CMSG_SPACE(size_of::<RawFd>() as u32) } as usize;
+    let mut control = vec![0u8; space];
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+    let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    check(n, "receiving the PTY failed")?;
+    if n == 0 {
+        return Ok(None);
+    }
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null() || (*cmsg).cmsg_type != libc::SCM_RIGHTS {
+            return Err(CfsError::Command(
+                "Expected a PTY from the container".into(),
+            ));
+        }
+        let fd = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd);
+        Ok(Some(OwnedFd::from_raw_fd(fd)))
+    }
+}
+
+/// Copies the window size of our terminal to the PTY.
+pub fn copy_window_size(master: RawFd) {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDIN_FILENO, libc::TIOCGWINSZ, &mut size) } == 0 {
+        unsafe { libc::ioctl(master, libc::TIOCSWINSZ, &size) };
+    }
+}
+
+/// Puts our terminal in raw mode while alive, so keystrokes, Ctrl-C
+/// included, reach the container's PTY as they are. Does nothing when stdin
+/// is no terminal.
+pub struct RawMode {
+    saved: Option<libc::termios>,
+}
+
+impl RawMode {
+    pub fn enable() -> RawMode {
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            return RawMode { saved: None };
+        }
+        let saved = termios;
+        unsafe {
+            libc::cfmakeraw(&mut termios);
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+        }
+        RawMode { saved: Some(saved) }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Some(saved) = &self.saved {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, saved) };
+        }
+    }
+}
+
+/// Copies the PTY master's output to our stdout in a background thread
+/// that ends when the container side of the PTY is closed, and with
+/// `input` our stdin to the master in another that just dies with the
+/// process.
+pub fn proxy(master: OwnedFd, input: bool) -> Result<std::thread::JoinHandle<()>> {
+    let mut output = File::from(master);
+    if input {
+        let mut master = output.try_clone().context("Failed to duplicate the PTY")?;
+        std::thread::spawn(move || {
+            let _ = io::copy(&mut io::stdin().lock(), &mut master);
+        });
+    }
+    Ok(std::thread::spawn(move || {
+        let mut stdout = io::stdout();
+        let mut buf = [0u8; 4096];
+        // EIO once the last slave descriptor has been closed
+        while let Ok(n) = output.read(&mut buf) {
+            if n == 0 || stdout.write_all(&buf[..n]).is_err() {
+                break;
+            }
+            let _ = stdout.flush();
+        }
+    }))
+}
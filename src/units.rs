@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use crate::error::{CfsError, Result};
+
+/// Parses a byte count with an optional binary `K`/`M`/`G` suffix, e.g.
+/// `256M` or `1g`. A trailing `B` (`256MB`) is accepted as well.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let invalid = || CfsError::Usage(format!("Invalid size {}, expected e.g. 512K, 256M, 1G", s));
+    let upper = s.trim().to_ascii_uppercase();
+    let digits = upper.strip_suffix('B').unwrap_or(&upper);
+    let (number, multiplier) = match digits.chars().last() {
+        Some('K') => (&digits[..digits.len() - 1], 1u64 << 10),
+        Some('M') => (&digits[..digits.len() - 1], 1u64 << 20),
+        Some('G') => (&digits[..digits.len() - 1], 1u64 << 30),
+        _ => (digits, 1),
+    };
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    number.checked_mul(multiplier).ok_or_else(invalid)
+}
+
+/// Renders a byte count with a binary suffix and a decimal, `512B`,
+/// `12.3M`, `1.0G`.
+pub fn format_size(bytes: u64) -> String {
+    let mut value = bytes as f64;
+    for suffix in ["B", "K", "M", "G"] {
+        if value < 1024.0 {
+            return match suffix {
+                "B" => format!("{}B", bytes),
+                _ => format!("{:.1}{}", value, suffix),
+            };
+        }
+        value /= 1024.0;
+    }
+    format!("{:.1}T", value)
+}
+
+/// Renders a number of seconds the way `ps` users expect, at most two units:
+/// `42s`, `5m12s`, `3h05m`, `2d04h`.
+pub fn format_duration(secs: u64) -> String {
+    let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    if days > 0 {
+        format!("{}d{:02}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h{:02}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m{:02}s", minutes, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// Parses a duration in whole seconds, minutes or hours, e.g. `30s`, `5m` or
+/// `1h`. A bare number counts seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let invalid = || CfsError::Usage(format!("Invalid duration {}, expected e.g. 30s, 5m, 1h", s));
+    let s = s.trim();
+    let (number, multiplier) = match s.chars().last() {
+        Some('s') => (&s[..s.len() - 1], 1),
+        Some('m') => (&s[..s.len() - 1], 60),
+        Some('h') => (&s[..s.len() - 1], 3600),
+        _ => (s, 1),
+    };
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    match number.checked_mul(multiplier) {
+        Some(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+        _ => Err(invalid()),
+    }
+}
@@ -0,0 +1,137 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{CfsError, Context, Result};
+use crate::mount;
+
+/// Who the command runs as inside the container, rather than root.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct User {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+impl User {
+    /// Resolves `--user`, `uid[:gid]` or `name[:group]`, against the
+    /// `/etc/passwd` and `/etc/group` of `root`. Without a group it is
+    /// the user's own, or 0 for a uid the rootfs doesn't list.
+    pub fn resolve(spec: &str, root: &Path) -> Result<User> {
+        let (user, group) = match spec.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (spec, None),
+        };
+        if user.is_empty() || group == Some("") {
+            return Err(CfsError::Usage(format!(
+                "Invalid --user {}, expected uid[:gid] or name[:group]",
+                spec
+            )));
+        }
+
+        let passwd = entries(root, "/etc/passwd")?;
+        let entry = passwd
+            .iter()
+            .find(|fields| fields[0] == user || fields[2] == user);
+        let uid = match (user.parse(), entry) {
+            (Ok(uid), _) => uid,
+            (Err(_), Some(fields)) => id(&fields[2], "/etc/passwd")?,
+            (Err(_), None) => {
+                return Err(CfsError::Usage(format!(
+                    "No user {} in the container's /etc/passwd",
+                    user
+                )));
+            }
+        };
+        let gid = match group {
+            Some(group) => match group.parse() {
+                Ok(gid) => gid,
+                Err(_) => {
+                    let groups = entries(root, "/etc/group")?;
+                    let fields =
+                        groups
+                            .iter()
+                            .find(|fields| fields[0] == group)
+                            .ok_or_else(|| {
+                                CfsError::Usage(format!(
+                                    "No group {} in the container's /etc/group",
+                                    group
+                                ))
+                            })?;
+                    id(&fields[2], "/etc/group")?
+                }
+            },
+            None => entry.map_or(Ok(0), |fields| id(&fields[3], "/etc/passwd"))?,
+        };
+        Ok(User { uid, gid })
+    }
+
+    /// Who the root of `pid`'s user namespace is on the host: root itself,
+    /// or for a rootless container the user who started it.
+    pub fn root_of(pid: libc::pid_t) -> Result<User> {
+        Ok(User {
+            uid: host_id(pid, "uid_map")?,
+            gid: host_id(pid, "gid_map")?,
+        })
+    }
+
+    /// Drops the supplementary groups and takes on the ids, gid first while
+    /// we may still change it. Only makes syscalls, so it can run between
+    /// fork and exec.
+    pub fn switch(&self) -> io::Result<()> {
+        // denied in a rootless container, where it could not change what
+        // we have anyway
+        if unsafe { libc::setgroups(0, std::ptr::null()) } != 0
+            && io::Error::last_os_error().raw_os_error() != Some(libc::EPERM)
+        {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { libc::setgid(self.gid) } != 0 || unsafe { libc::setuid(self.uid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// The colon separated lines of `file` in `root` with at least four fields,
+/// as passwd and group have. A rootfs without one simply lists nobody.
+fn entries(root: &Path, file: &str) -> Result<Vec<Vec<String>>> {
+    let path = mount::resolve_in_root(root, Path::new(file))?;
+    let contents = match fs::read_to_string(&path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        contents => contents.context(format!("Failed to read {}", path.display()))?,
+    };
+    Ok(contents
+        .lines()
+        .map(|line| line.split(':').map(String::from).collect::<Vec<_>>())
+        .filter(|fields| fields.len() >= 4)
+        .collect())
+}
+
+/// The host id that id 0 maps to, from `/proc/<pid>/<map>`, whose lines
+/// read `inside outside count`.
+fn host_id(pid: libc::pid_t, map: &str) -> Result<u32> {
+    let path = format!("/proc/{}/{}", pid, map);
+    let contents = fs::read_to_string(&path).context(format!("Failed to read {}", path))?;
+    contents
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<u32> = line
+                .split_whitespace()
+                .filter_map(|f| f.parse().ok())
+                .collect();
+            match fields[..] {
+                [0, outside, count] if count > 0 => Some(outside),
+                _ => None,
+            }
+        })
+        .next()
+        .ok_or_else(|| CfsError::Command(format!("Nothing maps to root in {}", path)))
+}
+
+fn id(field: &str, file: &str) -> Result<u32> {
+    field
+        .parse()
+        .map_err(|_| CfsError::Usage(format!("Invalid id {} in {}", field, file)))
+}
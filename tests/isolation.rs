@@ -0,0 +1,104 @@
+//! Runs real containers, which takes root and a rootfs with a shell:
+//!
+//!     CFS_TEST_ROOTFS=/path/to/rootfs cargo test -- --ignored
+
+#![cfg(target_os = "linux")]
+
+use std::sync::mpsc;
+use std::time::Duration;
+
+use cfs::Container;
+use cfs::mount::Volume;
+use cfs::net::NetMode;
+
+fn container() -> Container {
+    let rootfs = std::env::var("CFS_TEST_ROOTFS").expect("CFS_TEST_ROOTFS must name a rootfs");
+    Container::new().rootfs(rootfs).remove_on_exit(true)
+}
+
+fn sh(script: &str) -> [&str; 3] {
+    ["/bin/sh", "-c", script]
+}
+
+#[test]
+#[ignore = "needs root and CFS_TEST_ROOTFS"]
+fn sysv_ipc_is_private() {
+    // a segment on the host, which must not show inside
+    let id = unsafe { libc::shmget(libc::IPC_PRIVATE, 4096, libc::IPC_CREAT | 0o600) };
+    assert!(id >= 0, "shmget failed");
+    // just the header line
+    let status = container()
+        .command(sh("test $(wc -l < /proc/sysvipc/shm) -eq 1"))
+        .run();
+    unsafe { libc::shmctl(id, libc::IPC_RMID, std::ptr::null_mut()) };
+    assert!(status.unwrap().success());
+}
+
+#[test]
+#[ignore = "needs root and CFS_TEST_ROOTFS"]
+fn nested_volumes_in_the_overlay_come_off() {
+    let outer = std::env::temp_dir().join(format!("cfs-nested-{}", std::process::id()));
+    let inner = outer.join("inner");
+    std::fs::create_dir_all(&inner).unwrap();
+    // the inner volume first: /data/nested is only below /data once that
+    // is mounted, and has to come off before it all the same
+    let status = container()
+        .volume(Volume::parse(&format!("{}:/data/nested", inner.display())).unwrap())
+        .volume(Volume::parse(&format!("{}:/data", outer.display())).unwrap())
+        .command(sh("grep -q ' /data/nested ' /proc/self/mountinfo"))
+        .run();
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").unwrap();
+    std::fs::remove_dir_all(&outer).unwrap();
+    assert!(status.unwrap().success());
+    assert!(
+        !mountinfo.contains("/containers/"),
+        "an overlay mount was left behind"
+    );
+}
+
+#[test]
+#[ignore = "needs root and CFS_TEST_ROOTFS"]
+fn mounts_do_not_propagate_to_the_host() {
+    let target = "/cfs-propagation-test";
+    let (tid_sender, tid) = mpsc::channel();
+    let runner = std::thread::spawn(move || {
+        // the thread gets a mount namespace of its own with everything
+        // shared, as on systemd hosts, for a leak to have somewhere to go
+        unsafe {
+            assert_eq!(libc::unshare(libc::CLONE_NEWNS), 0, "unshare failed");
+            let flags = libc::MS_REC | libc::MS_SHARED;
+            let root = c"/".as_ptr();
+            let none = std::ptr::null();
+            let mounted = libc::mount(none, root, none, flags, std::ptr::null());
+            assert_eq!(mounted, 0, "mount failed");
+            tid_sender.send(libc::gettid()).unwrap();
+        }
+        container()
+            .volume(Volume::parse(&format!("/tmp:{}", target)).unwrap())
+            .command(["/bin/sleep", "1"])
+            .run()
+    });
+
+    let mountinfo = format!("/proc/{}/mountinfo", tid.recv().unwrap());
+    let mut leaked = false;
+    for _ in 0..8 {
+        std::thread::sleep(Duration::from_millis(100));
+        leaked |= std::fs::read_to_string(&mountinfo)
+            .unwrap()
+            .contains(target);
+    }
+    assert!(runner.join().unwrap().unwrap().success());
+    assert!(!leaked, "the volume showed up outside the container");
+}
+
+#[test]
+#[ignore = "needs root and CFS_TEST_ROOTFS"]
+fn loopback_is_up_without_a_network() {
+    let status = container()
+        .net(NetMode::None)
+        .command(sh(
+            "read flags < /sys/class/net/lo/flags; test $((flags & 1)) -eq 1",
+        ))
+        .run();
+    assert!(status.unwrap().success());
+}